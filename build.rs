@@ -8,7 +8,7 @@ fn main() {
     println!("cargo::rerun-if-changed=src");
 
     match std::env::var("CARGO_FEATURE_SSH") {
-        Err(e) if e == VarError::NotPresent => {
+        Err(VarError::NotPresent) => {
             return;
         }
         Err(e) => panic!("unknown error while checking ssh feature envar: {e}"),
@@ -34,7 +34,7 @@ fn main() {
     }
 
     for target in needed {
-        build_target(&target);
+        build_target(target);
         copy_bin_and_store_version(target);
     }
 }
@@ -117,8 +117,7 @@ fn build_target(target: &str) {
         s.spawn(|| {
             std::io::copy(&mut stderr, &mut std::io::stderr()).unwrap();
         });
-        let status = cargo.wait().unwrap();
-        status
+        cargo.wait().unwrap()
     });
 
     if !status.success() {
@@ -0,0 +1,321 @@
+//! Optional config file declaring datasets next to (or instead of) the
+//! `zcrab:policy` user property.
+//!
+//! Precedence: a dataset uses the entry whose `name` equals the dataset name,
+//! otherwise the first entry whose `name` pattern matches it. Settings in that
+//! entry override the zfs user properties of the dataset. Where both set a
+//! different value the config file wins and `status` reports the conflict.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use chrono::format::{Item, StrftimeItems};
+use color_eyre::eyre::{WrapErr, eyre};
+use color_eyre::{Result, Section};
+use byte_unit::Byte;
+use serde::{Deserialize, Deserializer};
+
+use crate::health::OnUnhealthyPool;
+use crate::hooks::Hooks;
+use crate::policy::RetentionPolicy;
+use crate::zfs;
+
+pub const DEFAULT_PATH: &str = concat!("/etc/", env!("CARGO_PKG_NAME"), ".toml");
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    #[serde(default, rename = "dataset")]
+    pub datasets: Vec<DataSetConfig>,
+    /// Where the daemon writes its prometheus metrics
+    pub metrics_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DataSetConfig {
+    /// Dataset name, a `*` matches any part of a single path component
+    pub name: String,
+    pub policy: Option<RetentionPolicy>,
+    /// strftime format for the part after the @ of new snapshots
+    pub naming: Option<String>,
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// Snapshot the dataset and all its descendants at once with
+    /// `zfs snapshot -r`
+    pub recursive: Option<bool>,
+    /// Datasets with the same group are snapshotted at once, this is
+    /// ignored for datasets in a recursive tree
+    pub group: Option<String>,
+    /// Postpone snapshots while less than this was written since the
+    /// latest snapshot, `1B` only skips datasets that did not change
+    #[serde(default, deserialize_with = "byte_size")]
+    pub min_written: Option<Byte>,
+    /// Destroy retained snapshots, least valuable first, while the pool
+    /// has less than this percentage free
+    pub min_pool_free_percent: Option<u8>,
+    /// Destroy retained snapshots, least valuable first, while the
+    /// snapshots of the dataset use more than this
+    #[serde(default, deserialize_with = "byte_size")]
+    pub max_snapshot_space: Option<Byte>,
+    /// What to do while the pool is degraded or resilvering, defaults to
+    /// skip-destroy
+    pub on_unhealthy_pool: Option<OnUnhealthyPool>,
+    /// Mark expired snapshots that are held or cloned with `zfs destroy -d`
+    /// so zfs destroys them once released, instead of skipping them
+    pub defer_destroy: Option<bool>,
+}
+
+/// Per dataset settings other than the policy
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub naming: Option<String>,
+    /// Commands from the config file only, see [`crate::hooks`]
+    pub hooks: Hooks,
+    /// Merged from the config file and the zfs properties
+    pub recursive: Option<bool>,
+    pub group: Option<String>,
+    /// Merged from the config file and the zfs properties
+    pub min_written: Option<Byte>,
+    pub min_pool_free_percent: Option<u8>,
+    pub max_snapshot_space: Option<Byte>,
+    pub on_unhealthy_pool: Option<OnUnhealthyPool>,
+    /// Merged from the config file and the zfs properties
+    pub defer_destroy: Option<bool>,
+}
+
+/// A setting that is different in the config file and the zfs properties
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Conflict {
+    pub setting: &'static str,
+    pub config: String,
+    pub property: String,
+}
+
+impl DataSetConfig {
+    pub fn settings(&self) -> Settings {
+        Settings {
+            naming: self.naming.clone(),
+            hooks: self.hooks.clone(),
+            recursive: self.recursive,
+            group: self.group.clone(),
+            min_written: self.min_written,
+            min_pool_free_percent: self.min_pool_free_percent,
+            max_snapshot_space: self.max_snapshot_space,
+            on_unhealthy_pool: self.on_unhealthy_pool,
+            defer_destroy: self.defer_destroy,
+        }
+    }
+}
+
+impl Config {
+    /// Without an explicit path a missing config file is not an error.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let config = match path {
+            Some(path) => std::fs::read_to_string(path)
+                .wrap_err("Could not read config file")
+                .with_note(|| format!("path: {}", path.display()))?,
+            None => match std::fs::read_to_string(DEFAULT_PATH) {
+                Ok(config) => config,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+                Err(e) => {
+                    return Err(e)
+                        .wrap_err("Could not read config file")
+                        .with_note(|| format!("path: {DEFAULT_PATH}"));
+                }
+            },
+        };
+
+        let path = path.unwrap_or(Path::new(DEFAULT_PATH));
+        Self::parse(&config).with_note(|| format!("path: {}", path.display()))
+    }
+
+    fn parse(config: &str) -> Result<Self> {
+        let config: Self = toml::from_str(config).wrap_err("Could not parse config file")?;
+        // the members of a group all get the name the leader's template renders
+        let mut group_naming = HashMap::new();
+        for dataset in &config.datasets {
+            if let (Some(group), Some(naming)) = (&dataset.group, &dataset.naming)
+                && let Some((other, other_naming)) =
+                    group_naming.insert(group, (&dataset.name, naming))
+                && other_naming != naming
+            {
+                return Err(eyre!("Group '{group}' has more than one naming template"))
+                    .with_note(|| format!("In entries for: {other} and {}", dataset.name))
+                    .suggestion("Set the same naming on every entry of the group");
+            }
+        }
+        for dataset in &config.datasets {
+            if let Some(naming) = &dataset.naming {
+                check_naming(naming).with_note(|| format!("In entry for: {}", dataset.name))?;
+            }
+            if dataset.min_pool_free_percent.is_some_and(|percent| percent > 100) {
+                return Err(eyre!("min-pool-free-percent is above 100"))
+                    .with_note(|| format!("In entry for: {}", dataset.name));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn entry_for(&self, dataset: &str) -> Option<&DataSetConfig> {
+        self.datasets
+            .iter()
+            .find(|entry| entry.name == dataset)
+            .or_else(|| {
+                self.datasets
+                    .iter()
+                    .find(|entry| pattern_matches(&entry.name, dataset))
+            })
+    }
+}
+
+/// Sizes are written as zfs does, `1M` means one MiB
+fn byte_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Byte>, D::Error> {
+    let size = String::deserialize(deserializer)?;
+    zfs::parse_used(&size)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Takes the config file value if set, noting a conflict if the property
+/// has a different value.
+pub fn pick<T: PartialEq + fmt::Display>(
+    setting: &'static str,
+    config: Option<T>,
+    property: Option<T>,
+    conflicts: &mut Vec<Conflict>,
+) -> Option<T> {
+    match (config, property) {
+        (Some(config), Some(property)) => {
+            if config != property {
+                conflicts.push(Conflict {
+                    setting,
+                    config: config.to_string(),
+                    property: property.to_string(),
+                });
+            }
+            Some(config)
+        }
+        (config, property) => config.or(property),
+    }
+}
+
+fn check_naming(naming: &str) -> Result<()> {
+    if StrftimeItems::new(naming).any(|item| item == Item::Error) {
+        return Err(eyre!("Invalid naming template: '{naming}'"))
+            .with_note(|| "The template uses strftime syntax, for example: %Y-%m-%d_%H:%M");
+    }
+    if naming.is_empty() || naming.contains(['@', '/', ' ']) {
+        return Err(eyre!("Invalid naming template: '{naming}'"))
+            .with_note(|| "Snapshot names can not be empty or contain '@', '/' or spaces");
+    }
+    if !StrftimeItems::new(naming).any(|item| matches!(item, Item::Numeric(..) | Item::Fixed(_)))
+    {
+        return Err(eyre!("Naming template without a time: '{naming}'"))
+            .with_note(|| "Every snapshot would get the same name, so only the first is made");
+    }
+    Ok(())
+}
+
+fn pattern_matches(pattern: &str, dataset: &str) -> bool {
+    let pattern = pattern.split('/').collect::<Vec<_>>();
+    let dataset = dataset.split('/').collect::<Vec<_>>();
+    pattern.len() == dataset.len()
+        && pattern
+            .iter()
+            .zip(dataset)
+            .all(|(pattern, part)| component_matches(pattern, part))
+}
+
+fn component_matches(pattern: &str, part: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == part,
+        Some((prefix, rest)) => {
+            let Some(part) = part.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=part.len())
+                .filter(|i| part.is_char_boundary(*i))
+                .any(|i| component_matches(rest, &part[i..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+[[dataset]]
+name = "tank/home/*"
+policy = "1h24:1d7"
+
+[[dataset]]
+name = "tank/home/david"
+policy = "15min8"
+naming = "%Y-%m-%d_%H:%M"
+
+min-written = "1M"
+
+[dataset.hooks]
+pre-snapshot = "psql -c CHECKPOINT"
+timeout = "30s"
+on-pre-snapshot-failure = "skip"
+"#;
+
+    #[test]
+    fn exact_name_beats_pattern() {
+        let config = Config::parse(CONFIG).unwrap();
+        let entry = config.entry_for("tank/home/david").unwrap();
+        assert_eq!(
+            entry.policy,
+            Some(RetentionPolicy::from_str("15min8").unwrap())
+        );
+        let entry = config.entry_for("tank/home/eve").unwrap();
+        assert_eq!(entry.name, "tank/home/*");
+        assert_eq!(entry.min_written, None);
+        let entry = config.entry_for("tank/home/david").unwrap();
+        assert_eq!(entry.min_written, Some(Byte::from_bytes(1024 * 1024)));
+    }
+
+    #[test]
+    fn hooks_table() {
+        let config = Config::parse(CONFIG).unwrap();
+        let hooks = &config.entry_for("tank/home/david").unwrap().hooks;
+        assert_eq!(hooks.pre_snapshot.as_deref(), Some("psql -c CHECKPOINT"));
+        assert_eq!(hooks.timeout, Some(std::time::Duration::from_secs(30).into()));
+        assert!(config.entry_for("tank/home/eve").unwrap().hooks.pre_snapshot.is_none());
+        assert!(Config::parse("[[dataset]]\nname = \"tank\"\nhooks = { timeout = \"soon\" }").is_err());
+    }
+
+    #[test]
+    fn one_naming_per_group() {
+        let entry = |name: &str, naming: &str| {
+            format!("[[dataset]]\nname = \"{name}\"\ngroup = \"db\"\nnaming = \"{naming}\"\n")
+        };
+        let same = entry("tank/db", "%Y-%m-%d_%H:%M") + &entry("tank/wal", "%Y-%m-%d_%H:%M");
+        assert!(Config::parse(&same).is_ok());
+        let different = entry("tank/db", "%Y-%m-%d_%H:%M") + &entry("tank/wal", "%Y%m%d%H%M");
+        assert!(Config::parse(&different).is_err());
+    }
+
+    #[test]
+    fn pattern_stays_in_component() {
+        assert!(pattern_matches("tank/*", "tank/home"));
+        assert!(pattern_matches("tank/h*e", "tank/home"));
+        assert!(!pattern_matches("tank/*", "tank/home/david"));
+        assert!(!pattern_matches("tank/*", "tank"));
+        assert!(!pattern_matches("tank/h*x", "tank/home"));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(Config::parse("[[dataset]]\nname = \"tank\"\npolicy = \"1x2\"").is_err());
+        assert!(Config::parse("[[dataset]]\nname = \"tank\"\nnaming = \"a@b\"").is_err());
+        assert!(Config::parse("[[dataset]]\nname = \"tank\"\nnaming = \"daily\"").is_err());
+        assert!(Config::parse("[[dataset]]\nname = \"tank\"\ntypo = 1").is_err());
+    }
+}
@@ -2,7 +2,7 @@ use std::fmt::Display;
 use color_eyre::Result;
 
 use crate::policy::RetentionPolicy;
-use crate::zfs;
+use crate::zfs::{self, ZfsBackend};
 
 pub mod interactive_cli;
 
@@ -18,7 +18,7 @@ impl Display for Configured {
 }
 
 impl Configured {
-    fn store_and_apply_retention_policy(&self) -> Result<()> {
-        zfs::set_policy(&self.name, &self.policy)
+    fn store_and_apply_retention_policy(&self, zfs: &dyn ZfsBackend) -> Result<()> {
+        zfs::set_policy(zfs, &self.name, &self.policy)
     }
 }
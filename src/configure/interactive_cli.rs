@@ -8,8 +8,9 @@ use inquire::validator::Validation;
 use inquire::{CustomType, Select, prompt_confirmation};
 use itertools::Itertools;
 
-use crate::policy::{RetentionPolicy, RetentionRule};
-use crate::zfs;
+use crate::lock::Locks;
+use crate::policy::{Period, RetentionPolicy, RetentionRule, Spacing};
+use crate::zfs::{self, ZfsBackend};
 
 use super::Configured;
 
@@ -27,9 +28,9 @@ impl Display for Wizard {
     }
 }
 
-pub fn start(sandbox: bool) -> Result<()> {
-    let mut unconfigured = zfs::iter_unconfigured_datasets()?.peekable();
-    let mut configured = zfs::iter_configured_datasets()?.peekable();
+pub fn start(zfs: &dyn ZfsBackend, locks: &Locks, sandbox: bool) -> Result<()> {
+    let mut unconfigured = zfs::iter_unconfigured_datasets(zfs)?.peekable();
+    let mut configured = zfs::iter_configured_datasets(zfs)?.peekable();
 
     let mut options = Vec::new();
     options.extend(unconfigured.peek().map(|_| Wizard::SetupDataset));
@@ -53,7 +54,8 @@ pub fn start(sandbox: bool) -> Result<()> {
     if let Some(changed) = changed
         && !sandbox
     {
-        changed.store_and_apply_retention_policy()?;
+        let _locked = locks.datasets([changed.name.as_str()])?;
+        changed.store_and_apply_retention_policy(zfs)?;
     }
 
     Ok(())
@@ -190,7 +192,8 @@ fn new_rule() -> Result<Option<RetentionRule>> {
     };
 
     Ok(Some(RetentionRule {
-        snapshot_period,
+        snapshot_period: Period::Fixed(snapshot_period),
         retained_copies,
+        spacing: Spacing::Rolling,
     }))
 }
@@ -0,0 +1,121 @@
+//! Why each snapshot is kept or removed. Lists every managed snapshot with
+//! the rules retaining it, and for expired ones the rule that let go of it
+//! last and which newer snapshot pushed it out.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use chrono::SecondsFormat;
+use color_eyre::eyre::eyre;
+use color_eyre::{Result, Section};
+use itertools::Itertools;
+
+use crate::config::Config;
+use crate::zfs::{self, ConfiguredDataSet, Protection, ZfsBackend, configured_datasets};
+
+pub fn print(zfs: &dyn ZfsBackend, config: &Config, only: &[String]) -> Result<()> {
+    let datasets = configured_datasets(zfs, config)?;
+    if let Some(unknown) = only
+        .iter()
+        .find(|name| !datasets.iter().any(|d| &d.path == *name))
+    {
+        return Err(eyre!("Not a managed dataset: {unknown}"))
+            .suggestion("Only datasets with a retention policy can be explained");
+    }
+    // of all datasets, another member of a group can keep a snapshot
+    let protected = zfs::protected(zfs, &datasets)?;
+    let mut stdout = io::stdout().lock();
+    for dataset in datasets
+        .iter()
+        .filter(|d| only.is_empty() || only.contains(&d.path))
+    {
+        write_explanation(&mut stdout, dataset, &protected);
+    }
+    Ok(())
+}
+
+fn write_explanation(
+    f: &mut impl Write,
+    dataset: &ConfiguredDataSet,
+    protected: &HashMap<String, Protection>,
+) {
+    let policy = &dataset.retention_policy;
+    let judgement = policy.judge(&dataset.sorted_snapshots);
+    let dropped = policy.dropped_out(&dataset.sorted_snapshots, &judgement.rejected);
+    let name_width = dataset
+        .sorted_snapshots
+        .iter()
+        .map(|snapshot| snapshot.short_name().chars().count())
+        .max()
+        .unwrap_or(0);
+
+    writeln!(f, "{} ({policy:?})", dataset.path).unwrap();
+    for snapshot in &dataset.sorted_snapshots {
+        let mut why = match (judgement.retained.get(snapshot), dropped.get(snapshot)) {
+            (Some(rules), _) => {
+                let rules = rules.iter().sorted().map(|rule| format!("{rule:?}")).join(", ");
+                format!("kept by {rules}")
+            }
+            (None, Some(Some(dropped))) => format!(
+                "expired, {:?} let go of it when the snapshot of {} was made",
+                dropped.rule,
+                dropped.by.created.to_rfc3339_opts(SecondsFormat::Secs, true)
+            ),
+            (None, _) => String::from("expired, no rule ever kept it"),
+        };
+        match protected.get(&snapshot.name) {
+            Some(protection @ Protection::Blocked(_)) => why = format!("{why}; {protection}"),
+            Some(protection) => why = format!("{why}; retained: {protection}"),
+            None => (),
+        }
+        writeln!(
+            f,
+            "  {:<name_width$}  {}  {why}",
+            snapshot.short_name(),
+            snapshot.created.to_rfc3339_opts(SecondsFormat::Secs, true),
+        )
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::config::Settings;
+    use crate::policy::RetentionPolicy;
+    use crate::policy::tests::aged;
+    use crate::zfs::SnapshotMetadata;
+
+    #[test]
+    fn says_what_pushed_snapshots_out() {
+        let snapshots = [aged!(50 m), aged!(100 m), aged!(130 m), aged!(2 d)];
+        let snapshots = snapshots.map(|snapshot| SnapshotMetadata {
+            name: format!("tank/home@{}", snapshot.name),
+            ..snapshot
+        });
+        let dataset = ConfiguredDataSet {
+            path: String::from("tank/home"),
+            retention_policy: RetentionPolicy::from_str("1h2:1d1").unwrap(),
+            sorted_snapshots: Box::new(snapshots),
+            settings: Settings::default(),
+            conflicts: Vec::new(),
+            group: None,
+            written: None,
+        };
+        let mut output = Vec::new();
+        write_explanation(&mut output, &dataset, &HashMap::new());
+        let output = String::from_utf8(output).unwrap();
+        let lines = output.lines().collect_vec();
+        assert_eq!(lines[0], "tank/home (1h2:1d1)");
+        assert!(lines[1].starts_with("  50m ") && lines[1].ends_with("kept by 1h2"));
+        assert!(lines[2].ends_with("expired, no rule ever kept it"));
+        assert!(lines[3].ends_with("kept by 1h2, 1d1"));
+        let made = dataset.sorted_snapshots[0]
+            .created
+            .to_rfc3339_opts(SecondsFormat::Secs, true);
+        let expected = format!("expired, 1h2 let go of it when the snapshot of {made} was made");
+        assert!(lines[4].ends_with(&expected));
+    }
+}
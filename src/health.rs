@@ -0,0 +1,247 @@
+//! Checks the pools before snapshots are made or destroyed. A degraded or
+//! resilvering pool is when history matters most, so by default nothing is
+//! destroyed on it. Pools that can not be written to are left alone
+//! entirely, on a suspended pool zfs commands would hang. So are pools whose
+//! status can not be read.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use color_eyre::eyre::eyre;
+use color_eyre::{Result, Section};
+use itertools::Itertools;
+use serde::Deserialize;
+
+use crate::zfs::{ConfiguredDataSet, ZfsBackend};
+
+pub const ON_UNHEALTHY_POOL_PROPERTY: &str = concat!(env!("CARGO_PKG_NAME"), ":on-unhealthy-pool");
+
+/// What `zpool` reports about a pool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolStatus {
+    /// ONLINE, DEGRADED, FAULTED, SUSPENDED, ...
+    pub health: String,
+    pub readonly: bool,
+    pub resilvering: bool,
+}
+
+impl PoolStatus {
+    #[cfg(test)]
+    pub fn online() -> Self {
+        PoolStatus {
+            health: String::from("ONLINE"),
+            readonly: false,
+            resilvering: false,
+        }
+    }
+
+    fn problem(&self) -> Option<Problem> {
+        match self.health.as_str() {
+            "ONLINE" if self.readonly => Some(Problem::ReadOnly),
+            "ONLINE" if self.resilvering => Some(Problem::Resilvering),
+            "ONLINE" => None,
+            "DEGRADED" => Some(Problem::Degraded),
+            health => Some(Problem::Unavailable(health.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    Degraded,
+    Resilvering,
+    ReadOnly,
+    /// FAULTED, SUSPENDED, UNAVAIL and the like
+    Unavailable(String),
+    /// `zpool` failed to report on the pool
+    Unknown(String),
+}
+
+impl Problem {
+    /// Whether the pool can be written to at all
+    fn writable(&self) -> bool {
+        matches!(self, Problem::Degraded | Problem::Resilvering)
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Degraded => f.write_str("is DEGRADED"),
+            Problem::Resilvering => f.write_str("is resilvering"),
+            Problem::ReadOnly => f.write_str("is imported read-only"),
+            Problem::Unavailable(health) => write!(f, "is {health}"),
+            Problem::Unknown(error) => write!(f, "could not be checked: {error}"),
+        }
+    }
+}
+
+/// What to do with the datasets of a degraded or resilvering pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnUnhealthyPool {
+    /// Only report the problem
+    Alert,
+    /// Keep making snapshots but destroy none
+    #[default]
+    SkipDestroy,
+    /// Neither make nor destroy snapshots
+    SkipAll,
+}
+
+impl fmt::Display for OnUnhealthyPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OnUnhealthyPool::Alert => "alert",
+            OnUnhealthyPool::SkipDestroy => "skip-destroy",
+            OnUnhealthyPool::SkipAll => "skip-all",
+        })
+    }
+}
+
+impl FromStr for OnUnhealthyPool {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "alert" => Ok(OnUnhealthyPool::Alert),
+            "skip-destroy" => Ok(OnUnhealthyPool::SkipDestroy),
+            "skip-all" => Ok(OnUnhealthyPool::SkipAll),
+            _ => Err(eyre!("Invalid unhealthy pool action: '{s}'"))
+                .with_note(|| "Valid actions are: alert|skip-destroy|skip-all"),
+        }
+    }
+}
+
+/// The problems of the pools holding the datasets, healthy pools are left
+/// out.
+#[derive(Debug, Default)]
+pub struct Health(HashMap<String, Problem>);
+
+impl Health {
+    pub fn check(zfs: &dyn ZfsBackend, datasets: &[ConfiguredDataSet]) -> Self {
+        let mut problems = HashMap::new();
+        for pool in datasets.iter().map(ConfiguredDataSet::pool).unique() {
+            let problem = match zfs.pool_status(pool) {
+                Ok(status) => status.problem(),
+                Err(e) => Some(Problem::Unknown(e.to_string())),
+            };
+            if let Some(problem) = problem {
+                problems.insert(pool.to_string(), problem);
+            }
+        }
+        Health(problems)
+    }
+
+    pub fn problem(&self, dataset: &ConfiguredDataSet) -> Option<&Problem> {
+        self.0.get(dataset.pool())
+    }
+
+    /// Whether the group of `dataset` may be snapshotted, all its members
+    /// are snapshotted with it.
+    pub fn may_snapshot(
+        &self,
+        datasets: &[ConfiguredDataSet],
+        dataset: &ConfiguredDataSet,
+    ) -> bool {
+        dataset.members(datasets).into_iter().all(|member| {
+            self.problem(member).is_none_or(|problem| {
+                problem.writable()
+                    && member.settings.on_unhealthy_pool.unwrap_or_default()
+                        != OnUnhealthyPool::SkipAll
+            })
+        })
+    }
+
+    pub fn may_destroy(&self, dataset: &ConfiguredDataSet) -> bool {
+        self.problem(dataset).is_none_or(|problem| {
+            problem.writable()
+                && dataset.settings.on_unhealthy_pool.unwrap_or_default() == OnUnhealthyPool::Alert
+        })
+    }
+
+    /// Prints every problem, the daemon does so on each pass
+    pub fn alert(&self) {
+        for (pool, problem) in self.iter() {
+            eprintln!("pool {pool} {problem}");
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Problem)> {
+        self.0
+            .iter()
+            .sorted_by_key(|(pool, _)| pool.as_str())
+            .map(|(pool, problem)| (pool.as_str(), problem))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::zfs::configured_datasets;
+    use crate::zfs::fake::FakeZfs;
+
+    fn degraded() -> PoolStatus {
+        PoolStatus {
+            health: String::from("DEGRADED"),
+            ..PoolStatus::online()
+        }
+    }
+
+    #[test]
+    fn problems() {
+        assert_eq!(PoolStatus::online().problem(), None);
+        assert_eq!(degraded().problem(), Some(Problem::Degraded));
+        let readonly = PoolStatus {
+            readonly: true,
+            ..PoolStatus::online()
+        };
+        assert_eq!(readonly.problem(), Some(Problem::ReadOnly));
+        let suspended = PoolStatus {
+            health: String::from("SUSPENDED"),
+            ..PoolStatus::online()
+        };
+        assert!(!suspended.problem().unwrap().writable());
+    }
+
+    #[test]
+    fn policy_per_dataset() {
+        let zfs = FakeZfs::new(Utc::now());
+        zfs.add_configured_dataset("tank/home", "1h3");
+        zfs.add_configured_dataset("tank/media", "1h3");
+        zfs.add_configured_dataset("tank/scratch", "1h3");
+        zfs.add_configured_dataset("backup/home", "1h3");
+        zfs.set_property("tank/media", ON_UNHEALTHY_POOL_PROPERTY, "skip-all")
+            .unwrap();
+        zfs.set_property("tank/scratch", ON_UNHEALTHY_POOL_PROPERTY, "alert")
+            .unwrap();
+        zfs.set_pool_status("tank", degraded());
+
+        let datasets = configured_datasets(&zfs, &Config::default()).unwrap();
+        let health = Health::check(&zfs, &datasets);
+        let allowed = datasets
+            .iter()
+            .map(|d| {
+                let snapshot = health.may_snapshot(&datasets, d);
+                (d.path.as_str(), snapshot, health.may_destroy(d))
+            })
+            .collect_vec();
+        assert_eq!(
+            allowed,
+            [
+                ("backup/home", true, true),
+                ("tank/home", true, false),
+                ("tank/media", false, false),
+                ("tank/scratch", true, true),
+            ]
+        );
+    }
+}
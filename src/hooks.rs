@@ -0,0 +1,313 @@
+//! Commands run before and after a snapshot is made and before one is
+//! destroyed, for example to flush and freeze a database. They are set per
+//! dataset in the config file and run as root through `sh -c` with the
+//! dataset and snapshot in the environment:
+//!
+//! - `ZCRAB_HOOK`: pre-snapshot, post-snapshot or pre-destroy
+//! - `ZCRAB_DATASET`: the dataset, for example `tank/db`
+//! - `ZCRAB_SNAPSHOT`: the full snapshot name, for example `tank/db@daily`
+//!
+//! The timeout and what to do when the pre-snapshot hook fails can also be
+//! set with zfs user properties, the commands can not. Anyone allowed to
+//! set user properties on a dataset, delegated with `zfs allow`, could
+//! otherwise run commands as root. Commands found in properties are
+//! ignored and reported as conflicts.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use color_eyre::eyre::{WrapErr, eyre};
+use color_eyre::{Result, Section};
+use serde::{Deserialize, Deserializer};
+
+use crate::DataSet;
+use crate::config::Conflict;
+use crate::zfs::ZfsBackend;
+
+const PRE_SNAPSHOT_PROPERTY: &str = concat!(env!("CARGO_PKG_NAME"), ":pre-snapshot");
+const POST_SNAPSHOT_PROPERTY: &str = concat!(env!("CARGO_PKG_NAME"), ":post-snapshot");
+const PRE_DESTROY_PROPERTY: &str = concat!(env!("CARGO_PKG_NAME"), ":pre-destroy");
+pub(crate) const TIMEOUT_PROPERTY: &str = concat!(env!("CARGO_PKG_NAME"), ":hook-timeout");
+pub(crate) const ON_FAILURE_PROPERTY: &str = concat!(env!("CARGO_PKG_NAME"), ":on-pre-snapshot-failure");
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What to do when the pre-snapshot hook fails or times out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    /// Make the snapshot anyway, it is crash consistent at least
+    #[default]
+    Continue,
+    Skip,
+}
+
+impl fmt::Display for OnFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OnFailure::Continue => "continue",
+            OnFailure::Skip => "skip",
+        })
+    }
+}
+
+impl FromStr for OnFailure {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "continue" => Ok(OnFailure::Continue),
+            "skip" => Ok(OnFailure::Skip),
+            _ => Err(eyre!("Invalid pre-snapshot failure action: '{s}'"))
+                .with_note(|| "Valid actions are: continue|skip"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Hooks {
+    pub pre_snapshot: Option<String>,
+    /// Also runs if the snapshot failed or was skipped, so whatever the
+    /// pre-snapshot hook froze is always thawed.
+    pub post_snapshot: Option<String>,
+    /// The snapshot is kept if this fails
+    pub pre_destroy: Option<String>,
+    /// How long each hook may run before it is killed, defaults to 60s
+    #[serde(default, deserialize_with = "timeout")]
+    pub timeout: Option<humantime::Duration>,
+    pub on_pre_snapshot_failure: Option<OnFailure>,
+}
+
+fn timeout<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<humantime::Duration>, D::Error> {
+    let timeout = String::deserialize(deserializer)?;
+    timeout
+        .parse()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+pub(crate) fn parse_timeout(value: &str) -> Result<humantime::Duration> {
+    value
+        .parse()
+        .wrap_err_with(|| format!("Invalid hook timeout: '{value}'"))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    PreSnapshot,
+    PostSnapshot,
+    PreDestroy,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::PreSnapshot => "pre-snapshot",
+            Kind::PostSnapshot => "post-snapshot",
+            Kind::PreDestroy => "pre-destroy",
+        })
+    }
+}
+
+impl Hooks {
+    /// The timeout and on-pre-snapshot-failure properties are picked like
+    /// the other settings, only the commands are merged here.
+    pub fn merge(config: Hooks, property: Hooks, conflicts: &mut Vec<Conflict>) -> Hooks {
+        Hooks {
+            pre_snapshot: config_only(
+                "pre-snapshot",
+                config.pre_snapshot,
+                property.pre_snapshot,
+                conflicts,
+            ),
+            post_snapshot: config_only(
+                "post-snapshot",
+                config.post_snapshot,
+                property.post_snapshot,
+                conflicts,
+            ),
+            pre_destroy: config_only(
+                "pre-destroy",
+                config.pre_destroy,
+                property.pre_destroy,
+                conflicts,
+            ),
+            ..config
+        }
+    }
+
+    fn run(&self, kind: Kind, command: Option<&str>, snapshot: &str) -> Result<()> {
+        let Some(command) = command else {
+            return Ok(());
+        };
+        let dataset = snapshot
+            .split_once('@')
+            .map_or(snapshot, |(dataset, _)| dataset);
+        let timeout = self.timeout.map_or(DEFAULT_TIMEOUT, Into::into);
+
+        let mut child = subprocess::Exec::shell(command)
+            .env("ZCRAB_HOOK", kind.to_string())
+            .env("ZCRAB_DATASET", dataset)
+            .env("ZCRAB_SNAPSHOT", snapshot)
+            .popen()
+            .wrap_err_with(|| format!("Could not start the {kind} hook"))
+            .with_note(|| format!("command: {command}"))?;
+        let status = child
+            .wait_timeout(timeout)
+            .wrap_err_with(|| format!("Could not wait for the {kind} hook"))?;
+
+        match status {
+            Some(status) if status.success() => Ok(()),
+            Some(status) => Err(eyre!("The {kind} hook failed, {status:?}"))
+                .with_note(|| format!("command: {command}")),
+            None => {
+                child.kill().wrap_err("Could not kill hook")?;
+                child.wait().wrap_err("Could not wait for hook")?;
+                Err(eyre!(
+                    "The {kind} hook timed out after {}",
+                    humantime::format_duration(timeout)
+                ))
+                .with_note(|| format!("command: {command}"))
+            }
+        }
+    }
+
+    /// Errors if the snapshot should not be made
+    pub fn before_snapshot(&self, snapshot: &str) -> Result<()> {
+        let res = self.run(Kind::PreSnapshot, self.pre_snapshot.as_deref(), snapshot);
+        match (res, self.on_pre_snapshot_failure.unwrap_or_default()) {
+            (Ok(()), _) => Ok(()),
+            (Err(e), OnFailure::Continue) => {
+                eprintln!("{e}, snapshotting {snapshot} anyway");
+                Ok(())
+            }
+            (Err(e), OnFailure::Skip) => Err(e.wrap_err(format!("Skipped snapshot {snapshot}"))),
+        }
+    }
+
+    pub fn after_snapshot(&self, snapshot: &str) -> Result<()> {
+        self.run(Kind::PostSnapshot, self.post_snapshot.as_deref(), snapshot)
+    }
+
+    pub fn before_destroy(&self, snapshot: &str) -> Result<()> {
+        self.run(Kind::PreDestroy, self.pre_destroy.as_deref(), snapshot)
+            .wrap_err_with(|| format!("Kept snapshot {snapshot}"))
+    }
+}
+
+/// A command from a property is never run, only reported
+fn config_only(
+    setting: &'static str,
+    config: Option<String>,
+    property: Option<String>,
+    conflicts: &mut Vec<Conflict>,
+) -> Option<String> {
+    if let Some(property) = property
+        && config.as_ref() != Some(&property)
+    {
+        conflicts.push(Conflict {
+            setting,
+            config: config.clone().unwrap_or_else(|| String::from("no hook")),
+            property,
+        });
+    }
+    config
+}
+
+fn set_on(zfs: &dyn ZfsBackend, property: &str) -> Result<Vec<(DataSet, String)>> {
+    Ok(zfs
+        .list_datasets(property)?
+        .into_iter()
+        .filter(|(_, value)| value != "-")
+        .collect())
+}
+
+/// The hook commands set with zfs user properties, they are inherited like
+/// any other property. `Hooks::merge` drops them.
+pub fn from_properties(zfs: &dyn ZfsBackend) -> Result<HashMap<DataSet, Hooks>> {
+    let mut hooks: HashMap<DataSet, Hooks> = HashMap::new();
+    for (dataset, command) in set_on(zfs, PRE_SNAPSHOT_PROPERTY)? {
+        hooks.entry(dataset).or_default().pre_snapshot = Some(command);
+    }
+    for (dataset, command) in set_on(zfs, POST_SNAPSHOT_PROPERTY)? {
+        hooks.entry(dataset).or_default().post_snapshot = Some(command);
+    }
+    for (dataset, command) in set_on(zfs, PRE_DESTROY_PROPERTY)? {
+        hooks.entry(dataset).or_default().pre_destroy = Some(command);
+    }
+    Ok(hooks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zfs::fake::FakeZfs;
+
+    fn hooks(pre: &str, on_failure: OnFailure) -> Hooks {
+        Hooks {
+            pre_snapshot: Some(pre.to_string()),
+            timeout: Some(Duration::from_millis(200).into()),
+            on_pre_snapshot_failure: Some(on_failure),
+            ..Hooks::default()
+        }
+    }
+
+    #[test]
+    fn failing_pre_snapshot_can_skip() {
+        assert!(hooks("true", OnFailure::Skip).before_snapshot("tank@a").is_ok());
+        assert!(hooks("false", OnFailure::Continue).before_snapshot("tank@a").is_ok());
+        assert!(hooks("false", OnFailure::Skip).before_snapshot("tank@a").is_err());
+    }
+
+    #[test]
+    fn slow_hooks_time_out() {
+        let err = hooks("sleep 5", OnFailure::Skip)
+            .run(Kind::PreSnapshot, Some("sleep 5"), "tank@a")
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+    }
+
+    #[test]
+    fn environment() {
+        let hooks = Hooks {
+            pre_destroy: Some(String::from(
+                r#"test "$ZCRAB_HOOK $ZCRAB_DATASET $ZCRAB_SNAPSHOT" = "pre-destroy tank/db tank/db@a""#,
+            )),
+            ..Hooks::default()
+        };
+        hooks.before_destroy("tank/db@a").unwrap();
+    }
+
+    #[test]
+    fn config_wins_over_properties() {
+        let zfs = FakeZfs::new(chrono::Utc::now());
+        zfs.add_dataset("tank");
+        zfs.add_dataset("tank/db");
+        zfs.set_property("tank", PRE_SNAPSHOT_PROPERTY, "sync").unwrap();
+        zfs.set_property("tank/db", POST_SNAPSHOT_PROPERTY, "thaw").unwrap();
+
+        let mut properties = from_properties(&zfs).unwrap();
+        let property = properties.remove("tank/db").unwrap();
+        assert_eq!(property.pre_snapshot.as_deref(), Some("sync"));
+        assert_eq!(property.post_snapshot.as_deref(), Some("thaw"));
+
+        let config = hooks("freeze", OnFailure::Skip);
+        let mut conflicts = Vec::new();
+        let merged = Hooks::merge(config, property.clone(), &mut conflicts);
+        assert_eq!(merged.pre_snapshot.as_deref(), Some("freeze"));
+        assert_eq!(merged.on_pre_snapshot_failure, Some(OnFailure::Skip));
+        assert_eq!(merged.post_snapshot, None);
+        assert_eq!(conflicts.len(), 2);
+
+        // commands only come from the config file
+        let mut conflicts = Vec::new();
+        let merged = Hooks::merge(Hooks::default(), property, &mut conflicts);
+        assert_eq!(merged.pre_snapshot, None);
+        assert_eq!(conflicts[0].property, "sync");
+    }
+}
@@ -0,0 +1,305 @@
+//! Keeps zcrab processes from acting on the same dataset at once. Only one
+//! daemon may run, it holds `daemon.lock` for as long as it lives. It also
+//! holds `daemon.status` and writes what it is doing into it for `status`,
+//! which never touches `daemon.lock` so it can not keep a daemon from
+//! starting. Every process that makes or destroys snapshots or changes
+//! properties locks the datasets involved first, so a manual `snap`, `gc`
+//! or `configure` waits for the daemon and the other way around. The wait
+//! is bounded, a dataset left locked by a stuck process fails rather than
+//! hangs whoever wants it next.
+
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{WrapErr, eyre};
+use color_eyre::{Result, Section};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::retry::Failure;
+
+pub const DEFAULT_DIR: &str = concat!("/run/", env!("CARGO_PKG_NAME"));
+
+const DAEMON_LOCK: &str = "daemon.lock";
+const DAEMON_STATUS: &str = "daemon.status";
+
+/// How long to wait for another process to unlock a dataset
+const DATASET_WAIT: Duration = Duration::from_secs(60);
+
+/// What `status` learns from a running daemon
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub doing: String,
+    /// Snapshots and destroys that failed and wait for a retry
+    pub failing: Vec<Failure>,
+}
+
+pub struct Locks {
+    /// None when nothing is written, as in sandbox mode
+    dir: Option<PathBuf>,
+    daemon: Option<Daemon>,
+    wait: Duration,
+}
+
+struct Daemon {
+    _lock: File,
+    status_file: File,
+    status: RefCell<DaemonStatus>,
+}
+
+/// Dataset locks, released when dropped
+#[must_use = "the datasets are unlocked when this is dropped"]
+pub struct Held {
+    _files: Vec<File>,
+}
+
+impl Locks {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: Some(dir.to_path_buf()),
+            daemon: None,
+            wait: DATASET_WAIT,
+        }
+    }
+
+    /// Locks nothing, for runs that change nothing
+    pub fn none() -> Self {
+        Self {
+            dir: None,
+            daemon: None,
+            wait: DATASET_WAIT,
+        }
+    }
+
+    fn open(dir: &Path, name: &str) -> Result<File> {
+        std::fs::create_dir_all(dir)
+            .wrap_err("Could not create the lock directory")
+            .with_note(|| format!("path: {}", dir.display()))?;
+        let path = dir.join(name);
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .wrap_err("Could not open lock file")
+            .with_note(|| format!("path: {}", path.display()))
+    }
+
+    /// Errors if another daemon is running
+    pub fn become_daemon(&mut self) -> Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let lock = Self::open(dir, DAEMON_LOCK)?;
+        match lock.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                let pid = self
+                    .daemon_status()?
+                    .map_or(String::from("unknown"), |status| status.pid.to_string());
+                return Err(eyre!("Another daemon is already running, pid: {pid}"))
+                    .suggestion("Stop it first, only one daemon may run at a time");
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).wrap_err("Could not lock the daemon lock file");
+            }
+        }
+        // only waits for a `status` that is reading the file right now
+        let status_file = Self::open(dir, DAEMON_STATUS)?;
+        status_file
+            .lock()
+            .wrap_err("Could not lock the daemon status file")?;
+        let status = DaemonStatus {
+            pid: std::process::id(),
+            doing: String::from("starting"),
+            failing: Vec::new(),
+        };
+        self.daemon = Some(Daemon {
+            _lock: lock,
+            status_file,
+            status: RefCell::new(status),
+        });
+        self.publish()
+    }
+
+    /// Overwrites the old status in place without emptying the file first,
+    /// `daemon_status` retries if it reads a mix of both.
+    fn publish(&self) -> Result<()> {
+        let Some(daemon) = &self.daemon else {
+            return Ok(());
+        };
+        let mut file = &daemon.status_file;
+        let status = serde_json::to_vec(&*daemon.status.borrow()).expect("status is serializable");
+        file.rewind()
+            .and_then(|()| file.write_all(&status))
+            .and_then(|()| file.set_len(status.len() as u64))
+            .wrap_err("Could not record what the daemon is doing")
+    }
+
+    /// Records what the daemon is doing for `status`
+    pub fn doing(&self, activity: &str) -> Result<()> {
+        if let Some(daemon) = &self.daemon {
+            daemon.status.borrow_mut().doing = activity.to_string();
+        }
+        self.publish()
+    }
+
+    /// Records the failures waiting for a retry for `status`
+    pub fn failing<'a>(&self, failures: impl IntoIterator<Item = &'a Failure>) -> Result<()> {
+        if let Some(daemon) = &self.daemon {
+            daemon.status.borrow_mut().failing = failures.into_iter().cloned().collect();
+        }
+        self.publish()
+    }
+
+    /// None if no daemon is running
+    pub fn daemon_status(&self) -> Result<Option<DaemonStatus>> {
+        let Some(dir) = &self.dir else {
+            return Ok(None);
+        };
+        let mut file = match File::open(dir.join(DAEMON_STATUS)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).wrap_err("Could not open the daemon status file"),
+        };
+        match file.try_lock_shared() {
+            Ok(()) => return Ok(None),
+            Err(TryLockError::WouldBlock) => (),
+            Err(TryLockError::Error(e)) => {
+                return Err(e).wrap_err("Could not check the daemon status file");
+            }
+        }
+
+        let mut attempts = 0;
+        loop {
+            let mut content = String::new();
+            file.rewind()
+                .and_then(|()| file.read_to_string(&mut content))
+                .wrap_err("Could not read the daemon status file")?;
+            match serde_json::from_str(&content) {
+                Ok(status) => return Ok(Some(status)),
+                // caught the daemon while it was writing
+                Err(_) if attempts < 3 => attempts += 1,
+                Err(e) => return Err(e).wrap_err("Could not parse the daemon status file"),
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Locks `datasets`, waiting a while for any other zcrab process acting
+    /// on one of them. They are always locked in the same order so two
+    /// processes can not wait on each other.
+    pub fn datasets<'a>(&self, datasets: impl IntoIterator<Item = &'a str>) -> Result<Held> {
+        let Some(dir) = &self.dir else {
+            return Ok(Held { _files: Vec::new() });
+        };
+        let dir = dir.join("datasets");
+        let mut held = Vec::new();
+        for dataset in datasets.into_iter().sorted().dedup() {
+            let file = Self::open(&dir, &format!("{}.lock", dataset.replace('/', "%")))?;
+            self.wait_for(&file, dataset)?;
+            held.push(file);
+        }
+        Ok(Held { _files: held })
+    }
+
+    fn wait_for(&self, file: &File, dataset: &str) -> Result<()> {
+        let deadline = Instant::now() + self.wait;
+        let mut told = false;
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(()),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    if !told {
+                        println!("waiting for another zcrab process to finish with {dataset}");
+                        told = true;
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(eyre!("Another zcrab process kept {dataset} locked"))
+                        .with_note(|| {
+                            format!("Waited for: {}", humantime::format_duration(self.wait))
+                        })
+                        .suggestion("Finish any restore or configure session of the dataset");
+                }
+                Err(TryLockError::Error(e)) => {
+                    return Err(e).wrap_err_with(|| format!("Could not lock dataset {dataset}"));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_daemon_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut first = Locks::new(dir.path());
+        assert_eq!(first.daemon_status().unwrap(), None);
+        first.become_daemon().unwrap();
+        first.doing("snapshotting tank/home").unwrap();
+        first.doing("idle").unwrap();
+
+        let mut second = Locks::new(dir.path());
+        assert!(second.become_daemon().is_err());
+        let status = second.daemon_status().unwrap().unwrap();
+        assert_eq!(status.pid, std::process::id());
+        assert_eq!(status.doing, "idle");
+        assert!(status.failing.is_empty());
+
+        drop(first);
+        assert_eq!(second.daemon_status().unwrap(), None);
+        second.become_daemon().unwrap();
+    }
+
+    #[test]
+    fn status_does_not_block_a_starting_daemon() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut daemon = Locks::new(dir.path());
+        daemon.become_daemon().unwrap();
+        drop(daemon);
+
+        // as if `status` was reading while the daemon starts
+        let reading = File::open(dir.path().join(DAEMON_STATUS)).unwrap();
+        reading.try_lock_shared().unwrap();
+        let mut daemon = Locks::new(dir.path());
+        let started = std::thread::spawn(move || daemon.become_daemon().map(|()| daemon));
+        std::thread::sleep(Duration::from_millis(50));
+        drop(reading);
+        let daemon = started.join().unwrap().unwrap();
+        assert!(daemon.daemon_status().unwrap().is_some());
+    }
+
+    #[test]
+    fn datasets_are_exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let locks = Locks::new(dir.path());
+        let held = locks.datasets(["tank/home", "tank/media"]).unwrap();
+
+        let path = dir.path().join("datasets").join("tank%home.lock");
+        let other = File::open(&path).unwrap();
+        assert!(matches!(other.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(held);
+        other.try_lock().unwrap();
+    }
+
+    #[test]
+    fn waiting_for_a_dataset_is_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut locks = Locks::new(dir.path());
+        locks.wait = Duration::from_millis(200);
+        let held = locks.datasets(["tank/home"]).unwrap();
+        assert!(locks.datasets(["tank/home"]).is_err());
+        drop(held);
+        assert!(locks.datasets(["tank/home"]).is_ok());
+    }
+}
@@ -1,17 +1,38 @@
+use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
 use color_eyre::eyre::eyre;
 use color_eyre::{Result, Section};
+use itertools::Itertools;
 use libproc::proc_pid;
 use service_install::install_system;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
-use std::thread;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+use config::Config;
+use health::Health;
+use lock::Locks;
+use retry::{Retries, Task};
 use policy::{RetentionPolicy, ZFS_PROPERTY};
-use zfs::{ConfiguredDataSet, SnapshotMetadata, configured_datasets};
+use zfs::{ConfiguredDataSet, Protection, SnapshotMetadata, ZfsBackend, configured_datasets};
 
+mod config;
 mod configure;
+mod explain;
+mod health;
+mod hooks;
+mod lock;
+mod metrics;
+mod migrate;
+mod oneshot;
 mod policy;
+#[cfg(feature = "ssh")]
+mod replicate;
+mod restore;
+mod retry;
+mod simulate;
+mod space;
 mod status;
 mod zfs;
 #[cfg(feature = "ssh")]
@@ -19,10 +40,11 @@ mod ssh;
 
 fn until_next_snapshot(
     datasets: &[ConfiguredDataSet],
+    now: DateTime<Utc>,
 ) -> impl Iterator<Item = (Duration, &ConfiguredDataSet)> {
     datasets
         .iter()
-        .filter_map(|dataset| dataset.until_next_snapshot().zip(Some(dataset)))
+        .filter_map(move |dataset| dataset.until_next_snapshot(now).zip(Some(dataset)))
 }
 
 type DataSet = String;
@@ -48,12 +70,15 @@ fn remove() -> Result<()> {
     version,
     about,
     long_about = "Usage
-    zfs-autosnap <status | snap | gc | help | version
+    zcrab <status | run | snap | gc | explain | simulate | restore | help | ...>
 Tips
-    use 'zfs set at.rollc.at:snapkeep=h:24,d:30,w:8,m:6,y1 some/dataset' to enable
-    use 'zfs set at.rollc.at:snapkeep=- some/dataset@some-snap' to retain
-    add 'zfs-autosnap snap' to cron.hourly
-    add 'zfs-autosnap gc'   to cron.daily
+    use 'zfs set zcrab:policy=1h24:1d30:1w8:1mo6:1y1 some/dataset' to enable,
+        keeping 24 hourly, 30 daily, 8 weekly, 6 monthly and 1 yearly snapshots
+    periods are given in s, min, h, d, w, mo or y
+    use 'zfs set zcrab:policy=- some/dataset@some-snap' to retain
+    use 'zcrab install' to run the daemon on boot, or instead
+    add 'zcrab snap' to cron.hourly
+    add 'zcrab gc'   to cron.daily
 "
 )]
 struct Args {
@@ -66,6 +91,10 @@ struct Args {
     /// Prints more information in Status
     #[arg(short, long)]
     verbose: bool,
+    /// Config file with datasets to manage, its settings take precedence
+    /// over those set as zfs properties. Defaults to /etc/zcrab.toml
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -77,9 +106,69 @@ enum Commands {
     /// Configure datasets for use
     Configure,
     /// Show Configuration, snapshots and schedule for next snapshot
-    Status,
+    Status {
+        #[arg(long, value_enum, default_value = "text")]
+        format: status::Format,
+    },
     /// Run the deamon in the foreground in the current terminal
-    Run,
+    Run {
+        /// Write prometheus metrics to this file after every pass, point
+        /// the node_exporter textfile collector at its directory
+        #[arg(long)]
+        metrics_file: Option<PathBuf>,
+    },
+    /// Make the snapshots that are due and exit, for use from cron or a timer
+    Snap {
+        /// Only consider this dataset, can be given more than once
+        #[arg(long)]
+        dataset: Vec<String>,
+        /// Snapshot even if no snapshot is due
+        #[arg(long)]
+        force: bool,
+    },
+    /// Remove expired snapshots and exit, for use from cron or a timer
+    Gc {
+        /// Only consider this dataset, can be given more than once
+        #[arg(long)]
+        dataset: Vec<String>,
+    },
+    /// Find, compare and get back snapshots
+    Restore {
+        #[command(subcommand)]
+        action: restore::Action,
+    },
+    /// List the versions of a file kept in snapshots
+    History {
+        path: PathBuf,
+        /// Copy the version in this snapshot next to the file, as
+        /// <name>@<snapshot>.<extension>
+        #[arg(long)]
+        restore: Option<String>,
+    },
+    /// List every snapshot with the rules keeping it, or why it expired
+    Explain {
+        /// Only explain these datasets, all managed ones if left out
+        dataset: Vec<String>,
+    },
+    /// Preview what a retention policy keeps over time
+    Simulate {
+        /// The policy to try, for example 1h24:1d7:1w4
+        policy: String,
+        /// Start with the snapshots of this dataset instead of none
+        #[arg(long)]
+        dataset: Option<String>,
+        /// How long to simulate, for example 6months
+        #[arg(long, default_value = "3months")]
+        span: humantime::Duration,
+        /// Time the daemon does not run as <after>+<length>, for example
+        /// 10d+3d, can be given more than once
+        #[arg(long)]
+        offline: Vec<simulate::Downtime>,
+    },
+    /// Convert the at.rollc.at:snapkeep properties of zfs-autosnap
+    Migrate,
+    /// Send new snapshots to the hosts set with the zcrab:replicate-to property
+    Replicate,
     /// Tests ssh
     Ssh,
 }
@@ -92,8 +181,18 @@ impl Display for Commands {
             Commands::Configure => {
                 concat!("configure datasets for use with ", env!("CARGO_PKG_NAME"))
             }
-            Commands::Status => "show configuration, snapshots and schedule for next snapshot",
-            Commands::Run => "run the deamon",
+            Commands::Status { .. } => {
+                "show configuration, snapshots and schedule for next snapshot"
+            }
+            Commands::Run { .. } => "run the deamon",
+            Commands::Snap { .. } => "make snapshots",
+            Commands::Gc { .. } => "remove expired snapshots",
+            Commands::Restore { .. } => "restore snapshots",
+            Commands::History { .. } => "list the versions of a file",
+            Commands::Explain { .. } => "explain why snapshots are kept",
+            Commands::Simulate { .. } => "simulate a retention policy",
+            Commands::Migrate => "migrate zfs-autosnap properties",
+            Commands::Replicate => "replicate snapshots",
             Commands::Ssh => "testing ssh",
         })
     }
@@ -102,13 +201,78 @@ impl Display for Commands {
 fn main() -> Result<()> {
     color_eyre::install().unwrap();
     let args = Args::parse();
+    let zfs = &zfs::Cli;
+    // only read by the commands that use it, a broken config file should
+    // not stand in the way of install or remove
+    let config = || Config::load(args.config.as_deref());
+    // sandbox runs change nothing so they need not wait for anyone, status
+    // only reads what the daemon is doing
+    let mut locks = if args.sandbox && !matches!(args.command, Commands::Status { .. }) {
+        Locks::none()
+    } else {
+        Locks::new(Path::new(lock::DEFAULT_DIR))
+    };
+    let locks = &mut locks;
 
     match (args.command, proc_pid::am_root() || args.sandbox) {
         (Commands::Install, true) => install(),
         (Commands::Remove, true) => remove(),
-        (Commands::Configure, true) => configure::interactive_cli::start(args.sandbox),
-        (Commands::Status, _) => status::print_status(args.verbose),
-        (Commands::Run, true) => daemon(args.sandbox),
+        (Commands::Configure, true) => {
+            configure::interactive_cli::start(zfs, locks, args.sandbox)
+        }
+        (Commands::Status { format }, _) => {
+            status::print_status(zfs, &config()?, locks, format, args.verbose)
+        }
+        (Commands::Run { metrics_file }, true) => {
+            let config = &config()?;
+            let metrics_file = metrics_file.or(config.metrics_file.clone());
+            daemon(zfs, config, locks, metrics_file.as_deref(), args.sandbox)
+        }
+        (Commands::Snap { dataset, force }, true) => {
+            oneshot::snap(zfs, &config()?, locks, &dataset, force, args.sandbox)
+        }
+        (Commands::Gc { dataset }, true) => {
+            oneshot::gc(zfs, &config()?, locks, &dataset, args.sandbox)
+        }
+        (Commands::Restore { action }, root) => match (action, root) {
+            (restore::Action::List { dataset }, _) => restore::list(zfs, &config()?, &dataset),
+            (restore::Action::Diff { snapshot, to }, true) => {
+                restore::diff(zfs, &config()?, &snapshot, to.as_deref())
+            }
+            (restore::Action::Clone { snapshot, to }, true) => {
+                restore::clone(zfs, &config()?, &snapshot, to, args.sandbox)
+            }
+            (restore::Action::Browse, true) => {
+                restore::tui::run(zfs, &config()?, locks, args.sandbox)
+            }
+            (restore::Action::Rollback { snapshot, yes }, true) => {
+                restore::rollback(zfs, &config()?, locks, &snapshot, yes, args.sandbox)
+            }
+            (action, false) => {
+                Err(eyre!("Need root to {action:?}").suggestion("Try running with sudo"))
+            }
+        },
+        (Commands::History { path, restore }, _) => {
+            restore::history(zfs, &config()?, &path, restore.as_deref(), args.sandbox)
+        }
+        (Commands::Explain { dataset }, _) => explain::print(zfs, &config()?, &dataset),
+        (
+            Commands::Simulate {
+                policy,
+                dataset,
+                span,
+                offline,
+            },
+            _,
+        ) => {
+            let config = &config()?;
+            simulate::run(zfs, config, &policy, dataset.as_deref(), span.into(), &offline)
+        }
+        (Commands::Migrate, true) => migrate::run(zfs, locks, args.sandbox),
+        #[cfg(feature = "ssh")]
+        (Commands::Replicate, true) => replicate::run(zfs, &config()?, locks, args.sandbox),
+        #[cfg(not(feature = "ssh"))]
+        (Commands::Replicate, true) => panic!("not compiled with ssh support"),
         #[cfg(feature = "ssh")]
         (Commands::Ssh, _) => ssh::test(),
         #[cfg(not(feature = "ssh"))]
@@ -119,44 +283,776 @@ fn main() -> Result<()> {
     }
 }
 
-fn daemon(sandbox: bool) -> Result<()> {
+fn daemon(
+    zfs: &dyn ZfsBackend,
+    config: &Config,
+    locks: &mut Locks,
+    metrics_file: Option<&Path>,
+    sandbox: bool,
+) -> Result<()> {
+    locks.become_daemon()?;
+    let mut counters = metrics::Counters::default();
+    let mut retries = Retries::default();
+    let mut failed_passes = 0;
     loop {
-        let datasets = configured_datasets()?;
-        let until_next_check = until_next_snapshot(&datasets)
-            .map(|(dur, _)| dur)
-            .min()
-            .unwrap_or(Duration::from_secs(60 * 10));
-        thread::sleep(until_next_check);
-        for dataset in need_snapshot(&datasets) {
-            if sandbox {
-                println!("would snapshot dataset: {dataset}");
-            } else {
-                let s = zfs::snapshot(dataset)?;
-                println!("made snapshot: {}", s.name);
+        let res = daemon_pass(zfs, config, locks, &mut counters, &mut retries, sandbox)
+            .and_then(|()| locks.failing(retries.failures()));
+        if res.is_err() {
+            counters.pass_failed();
+        }
+        // after a failed pass too, that is when they are needed
+        if let Some(path) = metrics_file
+            && let Err(e) = write_metrics(zfs, config, path, &counters, &retries)
+        {
+            eprintln!("{e:?}");
+        }
+
+        // whatever failed might be fixed by the next pass, listing datasets
+        // can fail while a pool is imported for example
+        if let Err(e) = res {
+            failed_passes += 1;
+            let wait = retry::backoff(failed_passes);
+            eprintln!(
+                "pass failed, retrying in {}: {e:?}",
+                humantime::format_duration(wait)
+            );
+            locks.doing(&format!("waiting to retry after: {e}"))?;
+            zfs.sleep(wait);
+        } else {
+            failed_passes = 0;
+        }
+    }
+}
+
+fn write_metrics(
+    zfs: &dyn ZfsBackend,
+    config: &Config,
+    path: &Path,
+    counters: &metrics::Counters,
+    retries: &Retries,
+) -> Result<()> {
+    let datasets = configured_datasets(zfs, config)?;
+    let health = Health::check(zfs, &datasets);
+    let metrics = metrics::render(&datasets, counters, retries, &health, zfs.now());
+    metrics::write_textfile(path, &metrics)
+}
+
+/// Makes and removes the snapshots that are due. Whatever fails for one
+/// dataset is retried in a later pass, the other datasets carry on.
+fn daemon_pass(
+    zfs: &dyn ZfsBackend,
+    config: &Config,
+    locks: &Locks,
+    counters: &mut metrics::Counters,
+    retries: &mut Retries,
+    sandbox: bool,
+) -> Result<()> {
+    let datasets = configured_datasets(zfs, config)?;
+    // a snapshot held back by an unhealthy pool stays due, sleep as if it
+    // was not so the pool is not polled in a tight loop. Likewise wait out
+    // the backoff of a failed snapshot.
+    let health = Health::check(zfs, &datasets);
+    let now = zfs.now();
+    let until_next_check = until_next_snapshot(&datasets, now)
+        .filter(|(_, dataset)| health.may_snapshot(&datasets, dataset))
+        .map(|(dur, dataset)| {
+            let task = Task::Snapshot(dataset.leader().to_string());
+            dur.max(retries.until_retry(&task, now))
+        })
+        .min()
+        .unwrap_or(Duration::from_secs(60 * 10));
+    let wake = now + until_next_check;
+    locks.doing(&format!("sleeping until {}", wake.format("%Y-%m-%d %H:%M:%S")))?;
+    zfs.sleep(until_next_check);
+    // pick up what was written while sleeping
+    let datasets = configured_datasets(zfs, config)?;
+    retries.prune(&datasets);
+    let health = Health::check(zfs, &datasets);
+    health.alert();
+    for dataset in need_snapshot(&datasets, zfs.now()) {
+        let task = Task::Snapshot(dataset.path.clone());
+        if !health.may_snapshot(&datasets, dataset) {
+            println!("skipped snapshot of {}: pool is unhealthy", dataset.path);
+        } else if retries.waiting(&task, zfs.now()) {
+            continue;
+        } else if sandbox {
+            println!("would snapshot dataset: {}", dataset.path);
+        } else {
+            let members = dataset.members(&datasets);
+            let _locked = match locks.datasets(members.iter().map(|member| member.path.as_str())) {
+                Ok(locked) => locked,
+                Err(e) => {
+                    counters.failed(&dataset.path);
+                    eprintln!("could not snapshot {}: {e:?}", dataset.path);
+                    continue;
+                }
+            };
+            locks.doing(&format!("snapshotting {}", dataset.path))?;
+            match zfs::snapshot(zfs, &datasets, dataset) {
+                Ok(made) => {
+                    retries.succeeded(&task);
+                    for s in made {
+                        counters.created(s.dataset());
+                        println!("made snapshot: {}", s.name);
+                    }
+                }
+                Err(e) => {
+                    counters.failed(&dataset.path);
+                    eprintln!("could not snapshot {}: {e:?}", dataset.path);
+                    retries.failed(task, &e, zfs.now());
+                }
             }
         }
-        for snapshot in need_removal(&datasets) {
-            if sandbox {
-                println!("would remove expired snapshot: {}", snapshot.name);
-            } else {
-                zfs::destroy_snapshot(snapshot)?;
-                println!("removed expired snapshot: {}", snapshot.name);
+    }
+    let protected = match zfs::protected(zfs, &datasets) {
+        Ok(protected) => protected,
+        Err(e) => {
+            // without knowing what is protected nothing may be destroyed
+            for (dataset, _) in removal_batches(&datasets, &HashMap::new()) {
+                counters.failed(&dataset.path);
+            }
+            eprintln!("could not check which snapshots are protected: {e:?}");
+            return Ok(());
+        }
+    };
+    for (dataset, expired) in removal_batches(&datasets, &protected) {
+        if !health.may_destroy(dataset) {
+            for snapshot in expired {
+                println!("kept expired snapshot {}: pool is unhealthy", snapshot.name);
+            }
+            continue;
+        }
+        let now = zfs.now();
+        let expired = expired
+            .into_iter()
+            .filter(|snapshot| !retries.waiting(&Task::Destroy(snapshot.name.clone()), now))
+            .collect_vec();
+        if expired.is_empty() {
+            continue;
+        }
+        let locked = (!sandbox)
+            .then(|| locks.datasets([dataset.path.as_str()]))
+            .transpose();
+        let _locked = match locked {
+            Ok(locked) => locked,
+            Err(e) => {
+                counters.failed(&dataset.path);
+                eprintln!("could not remove expired snapshots of {}: {e:?}", dataset.path);
+                continue;
+            }
+        };
+        locks.doing(&format!("removing expired snapshots of {}", dataset.path))?;
+        for (snapshot, result) in remove_expired(zfs, &datasets, dataset, &expired, sandbox) {
+            let task = Task::Destroy(snapshot.name.clone());
+            match result {
+                Ok(()) => {
+                    retries.succeeded(&task);
+                    counters.destroyed(snapshot.dataset());
+                    println!("removed expired snapshot: {}", snapshot.name);
+                }
+                Err(e) => {
+                    counters.failed(snapshot.dataset());
+                    eprintln!("could not remove {}: {e:?}", snapshot.name);
+                    retries.failed(task, &e, zfs.now());
+                }
+            }
+        }
+    }
+
+    if space::limited(&datasets) {
+        let datasets = configured_datasets(zfs, config)?;
+        let protected = match zfs::protected(zfs, &datasets) {
+            Ok(protected) => protected,
+            Err(e) => {
+                for dataset in datasets.iter().filter(|d| space::limited([*d])) {
+                    counters.failed(&dataset.path);
+                }
+                eprintln!("could not check which snapshots are protected: {e:?}");
+                return Ok(());
             }
+        };
+        let datasets = healthy(datasets, &health);
+        locks.doing("freeing space")?;
+        let freed = space::free_space(zfs, &datasets, &protected, locks, sandbox);
+        for snapshot in freed.destroyed {
+            counters.destroyed(snapshot.dataset());
+        }
+        for (name, e) in freed.failed {
+            counters.failed(&name);
+            eprintln!("could not free space on {name}: {e:?}");
         }
     }
+    Ok(())
+}
+
+/// The datasets whose snapshots may be destroyed
+fn healthy(datasets: Vec<ConfiguredDataSet>, health: &Health) -> Vec<ConfiguredDataSet> {
+    datasets
+        .into_iter()
+        .filter(|dataset| health.may_destroy(dataset))
+        .collect()
 }
 
-fn need_snapshot(datasets: &[ConfiguredDataSet]) -> impl Iterator<Item = &DataSet> {
-    until_next_snapshot(datasets)
+/// The datasets to snapshot, for a group only its leader is returned. A
+/// group is due if any of its members is.
+fn need_snapshot(
+    datasets: &[ConfiguredDataSet],
+    now: DateTime<Utc>,
+) -> impl Iterator<Item = &ConfiguredDataSet> {
+    let due: HashSet<&str> = until_next_snapshot(datasets, now)
         .filter(|(until, _)| until.is_zero())
-        .map(|(_, dataset)| &dataset.path)
+        .map(|(_, dataset)| dataset.leader())
+        .collect();
+    datasets
+        .iter()
+        .filter(move |dataset| dataset.leader() == dataset.path && due.contains(dataset.leader()))
+}
+
+fn need_removal<'a>(
+    datasets: &'a [ConfiguredDataSet],
+    protected: &'a HashMap<String, Protection>,
+) -> impl Iterator<Item = (&'a ConfiguredDataSet, &'a SnapshotMetadata)> {
+    datasets
+        .iter()
+        .flat_map(|dataset| {
+            dataset
+                .retention_policy
+                .judge(&dataset.sorted_snapshots)
+                .rejected
+                .into_iter()
+                .map(move |snapshot| (dataset, snapshot))
+        })
+        .filter(|(_, snapshot)| !protected.contains_key(&snapshot.name))
+        .filter(|(dataset, snapshot)| !removed_with_leader(datasets, dataset, snapshot))
+}
+
+/// `need_removal` with the snapshots of each dataset together, so they can
+/// be destroyed in one zfs call
+fn removal_batches<'a>(
+    datasets: &'a [ConfiguredDataSet],
+    protected: &'a HashMap<String, Protection>,
+) -> Vec<(&'a ConfiguredDataSet, Vec<&'a SnapshotMetadata>)> {
+    need_removal(datasets, protected)
+        .chunk_by(|(dataset, _)| dataset.path.as_str())
+        .into_iter()
+        .map(|(_, batch)| {
+            let (datasets, snapshots): (Vec<_>, Vec<_>) = batch.unzip();
+            (datasets[0], snapshots)
+        })
+        .collect()
 }
 
-fn need_removal(datasets: &[ConfiguredDataSet]) -> impl Iterator<Item = &SnapshotMetadata> {
-    datasets.iter().flat_map(|dataset| {
-        dataset
-            .retention_policy
-            .judge(&dataset.sorted_snapshots)
-            .rejected
-    })
+/// Destroys the expired snapshots of `dataset` the pre-destroy hook lets go
+/// in a single zfs call, after a dry run reporting the space that frees.
+/// If that fails they are destroyed one by one so a single busy snapshot
+/// does not keep the others. Returns how it went for each snapshot, in
+/// sandbox mode only the dry run is made and nothing is returned.
+fn remove_expired<'a>(
+    zfs: &dyn ZfsBackend,
+    datasets: &[ConfiguredDataSet],
+    dataset: &ConfiguredDataSet,
+    expired: &[&'a SnapshotMetadata],
+    sandbox: bool,
+) -> Vec<(&'a SnapshotMetadata, Result<()>)> {
+    if sandbox {
+        for snapshot in expired {
+            println!("would remove expired snapshot: {}", snapshot.name);
+        }
+        match zfs::reclaimable(zfs, dataset, expired) {
+            Ok(space) => println!("which would free {}", space.get_appropriate_unit(true)),
+            Err(e) => eprintln!("could not tell the space that would free: {e:?}"),
+        }
+        return Vec::new();
+    }
+
+    let mut results = Vec::new();
+    let mut batch = Vec::new();
+    for snapshot in expired {
+        match zfs::before_destroy(datasets, dataset, snapshot) {
+            Ok(()) => batch.push(*snapshot),
+            Err(e) => results.push((*snapshot, Err(e))),
+        }
+    }
+    if batch.is_empty() {
+        return results;
+    }
+    let together = zfs::reclaimable(zfs, dataset, &batch).and_then(|space| {
+        println!(
+            "removing {} expired snapshots of {}, freeing {}",
+            batch.len(),
+            dataset.path,
+            space.get_appropriate_unit(true)
+        );
+        zfs::destroy_snapshots(zfs, dataset, &batch)
+    });
+    match together {
+        Ok(()) => results.extend(batch.into_iter().map(|snapshot| (snapshot, Ok(())))),
+        Err(e) if batch.len() == 1 => results.push((batch[0], Err(e))),
+        Err(_) => results.extend(
+            batch
+                .into_iter()
+                .map(|snapshot| (snapshot, zfs::destroy_snapshots(zfs, dataset, &[snapshot]))),
+        ),
+    }
+    results
+}
+
+/// Destroying a snapshot of the leader of a recursive group also destroys
+/// the copies in the rest of the group.
+fn removed_with_leader(
+    datasets: &[ConfiguredDataSet],
+    dataset: &ConfiguredDataSet,
+    snapshot: &SnapshotMetadata,
+) -> bool {
+    let Some(group) = &dataset.group else {
+        return false;
+    };
+    group.recursive
+        && group.leader != dataset.path
+        && datasets
+            .iter()
+            .filter(|other| other.path == group.leader)
+            .flat_map(|leader| leader.sorted_snapshots.iter())
+            .any(|copy| copy.short_name() == snapshot.short_name())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use metrics::Counters;
+    use zfs::fake::FakeZfs;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn pass(zfs: &FakeZfs, config: &Config, counters: &mut Counters) -> Result<()> {
+        daemon_pass(zfs, config, &Locks::none(), counters, &mut Retries::default(), false)
+    }
+
+    fn start() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z")
+            .unwrap()
+            .to_utc()
+    }
+
+    #[test]
+    fn snapshots_new_dataset_right_away() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h3");
+
+        pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        assert_eq!(zfs.now(), start());
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 1);
+    }
+
+    #[test]
+    fn follows_policy() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h3");
+        zfs.add_dataset("tank/scratch");
+
+        for _ in 0..10 {
+            pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        }
+        assert_eq!(zfs.now(), start() + HOUR * 9);
+        assert!(zfs.snapshots_of("tank/scratch").is_empty());
+
+        let datasets = configured_datasets(&zfs, &Config::default()).unwrap();
+        assert_eq!(datasets[0].sorted_snapshots.len(), 4);
+        let protected = zfs::protected(&zfs, &datasets).unwrap();
+        assert_eq!(need_removal(&datasets, &protected).count(), 1);
+        assert_eq!(need_snapshot(&datasets, zfs.now()).count(), 0);
+    }
+
+    #[test]
+    fn counts_created_and_destroyed() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h3");
+
+        let mut counters = Counters::default();
+        for _ in 0..10 {
+            pass(&zfs, &Config::default(), &mut counters).unwrap();
+        }
+        let counts = counters.get("tank/home");
+        assert_eq!(counts.created, 10);
+        assert_eq!(counts.destroyed, 6);
+        assert_eq!(counts.failed, 0);
+    }
+
+    #[test]
+    fn sandbox_changes_nothing() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h3");
+
+        let mut counters = Counters::default();
+        let mut retries = Retries::default();
+        daemon_pass(&zfs, &Config::default(), &Locks::none(), &mut counters, &mut retries, true)
+            .unwrap();
+        assert!(zfs.snapshots_of("tank/home").is_empty());
+    }
+
+    #[test]
+    fn opted_out_snapshots_are_kept() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h1");
+        pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        let first = zfs.snapshots_of("tank/home").remove(0);
+        zfs.set_property(&first, ZFS_PROPERTY, "-").unwrap();
+        zfs.advance(Duration::from_secs(60));
+
+        for _ in 0..5 {
+            pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        }
+        assert!(zfs.snapshots_of("tank/home").contains(&first));
+    }
+
+    #[test]
+    fn config_file_manages_matching_datasets() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_dataset("tank/home/alice");
+        zfs.add_dataset("tank/home/bob");
+        zfs.add_configured_dataset("tank/home/carol", "1d2");
+        zfs.add_dataset("tank/scratch");
+        let config: Config = toml::from_str(
+            r#"
+            [[dataset]]
+            name = "tank/home/*"
+            policy = "1h3"
+            naming = "hourly-%H"
+            "#,
+        )
+        .unwrap();
+
+        pass(&zfs, &config, &mut Counters::default()).unwrap();
+        assert_eq!(
+            zfs.snapshots_of("tank/home/alice"),
+            ["tank/home/alice@hourly-00"]
+        );
+        assert_eq!(
+            zfs.snapshots_of("tank/home/bob"),
+            ["tank/home/bob@hourly-00"]
+        );
+        assert!(zfs.snapshots_of("tank/scratch").is_empty());
+
+        let datasets = configured_datasets(&zfs, &config).unwrap();
+        let carol = datasets
+            .iter()
+            .find(|d| d.path == "tank/home/carol")
+            .unwrap();
+        assert_eq!(
+            carol.retention_policy,
+            RetentionPolicy::from_str("1h3").unwrap()
+        );
+        assert_eq!(carol.conflicts.len(), 1);
+    }
+
+    #[test]
+    fn hooks_can_veto() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_dataset("tank/db");
+        let config: Config = toml::from_str(
+            r#"
+            [[dataset]]
+            name = "tank/db"
+            policy = "1h1"
+            hooks = { pre-snapshot = "false", on-pre-snapshot-failure = "skip" }
+            "#,
+        )
+        .unwrap();
+        let mut counters = Counters::default();
+        pass(&zfs, &config, &mut counters).unwrap();
+        assert!(zfs.snapshots_of("tank/db").is_empty());
+        assert_eq!(counters.get("tank/db").failed, 1);
+
+        let config: Config = toml::from_str(
+            r#"
+            [[dataset]]
+            name = "tank/db"
+            policy = "1h1"
+            hooks = { pre-destroy = "false" }
+            "#,
+        )
+        .unwrap();
+        for _ in 0..2 {
+            pass(&zfs, &config, &mut Counters::default()).unwrap();
+        }
+        let mut counters = Counters::default();
+        pass(&zfs, &config, &mut counters).unwrap();
+        assert_eq!(zfs.snapshots_of("tank/db").len(), 3);
+        assert_eq!(counters.get("tank/db").failed, 1);
+    }
+
+    #[test]
+    fn failures_back_off_while_others_carry_on() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_dataset("tank/db");
+        zfs.add_configured_dataset("tank/home", "1h3");
+        let config: Config = toml::from_str(
+            r#"
+            [[dataset]]
+            name = "tank/db"
+            policy = "1h3"
+            hooks = { pre-snapshot = "false", on-pre-snapshot-failure = "skip" }
+            "#,
+        )
+        .unwrap();
+        let locks = Locks::none();
+        let mut counters = Counters::default();
+        let mut retries = Retries::default();
+
+        for _ in 0..3 {
+            daemon_pass(&zfs, &config, &locks, &mut counters, &mut retries, false).unwrap();
+        }
+        // retried after one and then two more minutes
+        assert_eq!(zfs.now(), start() + Duration::from_secs(3 * 60));
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 1);
+        assert_eq!(counters.get("tank/db").failed, 3);
+        let failure = retries.of("tank/db").next().unwrap();
+        assert_eq!(failure.attempts, 3);
+        assert!(failure.error.contains("Skipped snapshot tank/db@"));
+    }
+
+    #[test]
+    fn vetoed_destroy_does_not_stop_freeing_space() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_dataset("tank/db");
+        zfs.add_dataset("tank/home");
+        let config: Config = toml::from_str(
+            r#"
+            [[dataset]]
+            name = "tank/db"
+            policy = "1h3"
+            max-snapshot-space = "0"
+            hooks = { pre-destroy = "false" }
+
+            [[dataset]]
+            name = "tank/home"
+            policy = "1h3"
+            max-snapshot-space = "0"
+            "#,
+        )
+        .unwrap();
+        for _ in 0..3 {
+            pass(&zfs, &config, &mut Counters::default()).unwrap();
+        }
+        for snapshot in zfs.snapshots_of("tank/db").iter().chain(&zfs.snapshots_of("tank/home")) {
+            zfs.set_used(snapshot, 10);
+        }
+
+        let mut counters = Counters::default();
+        pass(&zfs, &config, &mut counters).unwrap();
+        // the oldest has not expired yet, it is no candidate
+        assert_eq!(zfs.snapshots_of("tank/db").len(), 4);
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 2);
+        assert!(counters.get("tank/db").failed > 0);
+    }
+
+    fn short_names(zfs: &FakeZfs, dataset: &str) -> Vec<String> {
+        zfs.snapshots_of(dataset)
+            .into_iter()
+            .map(|name| name.split_once('@').unwrap().1.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn recursive_tree_is_snapshotted_and_pruned_together() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_dataset("tank");
+        zfs.add_dataset("tank/home");
+        zfs.add_configured_dataset("tank/home/alice", "1h3");
+        zfs.add_dataset("tank/scratch");
+        let config: Config = toml::from_str(
+            r#"
+            [[dataset]]
+            name = "tank"
+            policy = "1h1"
+            recursive = true
+            "#,
+        )
+        .unwrap();
+
+        for _ in 0..6 {
+            pass(&zfs, &config, &mut Counters::default()).unwrap();
+        }
+        // alice retains more, so the whole tree keeps those snapshots
+        let alice = short_names(&zfs, "tank/home/alice");
+        assert_eq!(alice.len(), 4);
+        for dataset in ["tank", "tank/home", "tank/scratch"] {
+            assert_eq!(short_names(&zfs, dataset), alice);
+        }
+    }
+
+    #[test]
+    fn members_of_a_recursive_tree_can_veto() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_dataset("tank");
+        zfs.add_dataset("tank/db");
+        let config: Config = toml::from_str(
+            r#"
+            [[dataset]]
+            name = "tank"
+            policy = "1h1"
+            recursive = true
+
+            [[dataset]]
+            name = "tank/db"
+            policy = "1h1"
+            hooks = { pre-destroy = "false" }
+            "#,
+        )
+        .unwrap();
+
+        let mut counters = Counters::default();
+        for _ in 0..3 {
+            pass(&zfs, &config, &mut counters).unwrap();
+        }
+        assert_eq!(zfs.snapshots_of("tank/db").len(), 3);
+        assert_eq!(zfs.snapshots_of("tank").len(), 3);
+        assert!(counters.get("tank").failed > 0);
+    }
+
+    #[test]
+    fn config_group_shares_snapshots() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_dataset("tank/db");
+        zfs.add_dataset("fast/app");
+        let config: Config = toml::from_str(
+            r#"
+            [[dataset]]
+            name = "tank/db"
+            policy = "1h1"
+            group = "app"
+
+            [[dataset]]
+            name = "fast/app"
+            policy = "2h1"
+            group = "app"
+            "#,
+        )
+        .unwrap();
+
+        for _ in 0..6 {
+            pass(&zfs, &config, &mut Counters::default()).unwrap();
+        }
+        assert_eq!(short_names(&zfs, "tank/db"), short_names(&zfs, "fast/app"));
+        assert_eq!(zfs.now(), start() + HOUR * 5);
+    }
+
+    #[test]
+    fn idle_datasets_are_postponed() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h3");
+        zfs.set_property("tank/home", zfs::MIN_WRITTEN_PROPERTY, "1K")
+            .unwrap();
+
+        for _ in 0..4 {
+            pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        }
+        assert_eq!(zfs.now(), start() + HOUR * 3);
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 1);
+
+        zfs.write("tank/home", 512);
+        pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 1);
+
+        zfs.advance(HOUR / 2);
+        zfs.write("tank/home", 512);
+        pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        assert_eq!(zfs.now(), start() + HOUR * 4 + HOUR / 2);
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 2);
+    }
+
+    #[test]
+    fn unhealthy_pool_keeps_history() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h1");
+        let degraded = health::PoolStatus {
+            health: String::from("DEGRADED"),
+            ..health::PoolStatus::online()
+        };
+        zfs.set_pool_status("tank", degraded);
+
+        for _ in 0..4 {
+            pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        }
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 4);
+
+        let suspended = health::PoolStatus {
+            health: String::from("SUSPENDED"),
+            ..health::PoolStatus::online()
+        };
+        zfs.set_pool_status("tank", suspended);
+        let before = zfs.now();
+        pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 4);
+        assert_eq!(zfs.now(), before + HOUR / 3);
+
+        zfs.set_pool_status("tank", health::PoolStatus::online());
+        pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "ssh")]
+    fn replication_anchor_is_kept() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h1");
+        pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        let anchor = zfs.snapshots_of("tank/home").remove(0);
+        zfs.hold(zfs::REPLICATION_ANCHOR, &anchor).unwrap();
+
+        for _ in 0..5 {
+            pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        }
+        let snapshots = zfs.snapshots_of("tank/home");
+        assert!(snapshots.contains(&anchor));
+        assert_eq!(snapshots.len(), 3);
+    }
+
+    #[test]
+    fn expired_go_in_one_call() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h6");
+        for _ in 0..6 {
+            pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        }
+        assert_eq!(zfs.destroy_calls(), 0);
+
+        zfs.set_property("tank/home", ZFS_PROPERTY, "1h1").unwrap();
+        let mut counters = Counters::default();
+        pass(&zfs, &Config::default(), &mut counters).unwrap();
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 2);
+        assert_eq!(counters.get("tank/home").destroyed, 5);
+        assert_eq!(zfs.destroy_calls(), 1);
+    }
+
+    #[test]
+    fn clones_block_destroy() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h1");
+        pass(&zfs, &Config::default(), &mut Counters::default()).unwrap();
+        let cloned = zfs.snapshots_of("tank/home").remove(0);
+        zfs.clone_snapshot(&cloned, "tank/restore", &[]).unwrap();
+
+        let mut counters = Counters::default();
+        for _ in 0..3 {
+            pass(&zfs, &Config::default(), &mut counters).unwrap();
+        }
+        assert_eq!(counters.get("tank/home").failed, 0);
+        assert!(zfs.snapshots_of("tank/home").contains(&cloned));
+        let protection = |zfs: &FakeZfs| {
+            let datasets = configured_datasets(zfs, &Config::default()).unwrap();
+            zfs::protected(zfs, &datasets).unwrap()[&cloned].to_string()
+        };
+        assert_eq!(protection(&zfs), "would expire but blocked by clone tank/restore");
+
+        zfs.set_property("tank/home", zfs::DEFER_DESTROY_PROPERTY, "on")
+            .unwrap();
+        pass(&zfs, &Config::default(), &mut counters).unwrap();
+        assert_eq!(counters.get("tank/home").failed, 0);
+        assert!(zfs.blockers().unwrap()[&cloned].deferred);
+        assert_eq!(protection(&zfs), "destroy deferred, blocked by clone tank/restore");
+    }
 }
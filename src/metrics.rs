@@ -0,0 +1,264 @@
+//! Prometheus metrics in the text exposition format, written to a file for
+//! the node_exporter textfile collector.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::WrapErr;
+use color_eyre::{Result, Section};
+
+use crate::DataSet;
+use crate::health::Health;
+use crate::retry::Retries;
+use crate::zfs::ConfiguredDataSet;
+
+const PREFIX: &str = env!("CARGO_PKG_NAME");
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    pub created: u64,
+    pub destroyed: u64,
+    pub failed: u64,
+}
+
+/// Counters kept by the daemon for as long as it runs
+#[derive(Debug, Default)]
+pub struct Counters {
+    datasets: BTreeMap<DataSet, Counts>,
+    failed_passes: u64,
+}
+
+impl Counters {
+    pub fn get(&self, dataset: &str) -> Counts {
+        self.datasets.get(dataset).copied().unwrap_or_default()
+    }
+
+    fn entry(&mut self, dataset: &str) -> &mut Counts {
+        self.datasets.entry(dataset.to_string()).or_default()
+    }
+
+    pub fn failed_passes(&self) -> u64 {
+        self.failed_passes
+    }
+
+    pub fn pass_failed(&mut self) {
+        self.failed_passes += 1;
+    }
+
+    pub fn created(&mut self, dataset: &str) {
+        self.entry(dataset).created += 1;
+    }
+
+    pub fn destroyed(&mut self, dataset: &str) {
+        self.entry(dataset).destroyed += 1;
+    }
+
+    pub fn failed(&mut self, dataset: &str) {
+        self.entry(dataset).failed += 1;
+    }
+}
+
+struct Metric<'a> {
+    name: &'a str,
+    kind: &'a str,
+    help: &'a str,
+}
+
+impl Metric<'_> {
+    fn header(&self, out: &mut String) {
+        let Metric { name, kind, help } = self;
+        writeln!(out, "# HELP {PREFIX}_{name} {help}").unwrap();
+        writeln!(out, "# TYPE {PREFIX}_{name} {kind}").unwrap();
+    }
+
+    /// A metric about the daemon as a whole, without labels
+    fn write_single(&self, out: &mut String, value: u128) {
+        self.header(out);
+        writeln!(out, "{PREFIX}_{} {value}", self.name).unwrap();
+    }
+
+    fn write<'d>(&self, out: &mut String, samples: impl Iterator<Item = (&'d str, u128)>) {
+        self.header(out);
+        for (dataset, value) in samples {
+            let dataset = escape(dataset);
+            writeln!(out, "{PREFIX}_{}{{dataset=\"{dataset}\"}} {value}", self.name).unwrap();
+        }
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+pub fn render(
+    datasets: &[ConfiguredDataSet],
+    counters: &Counters,
+    retries: &Retries,
+    health: &Health,
+    now: DateTime<Utc>,
+) -> String {
+    let mut out = String::new();
+
+    Metric {
+        name: "snapshots",
+        kind: "gauge",
+        help: "Number of snapshots of the dataset",
+    }
+    .write(
+        &mut out,
+        datasets
+            .iter()
+            .map(|d| (d.path.as_str(), d.sorted_snapshots.len() as u128)),
+    );
+    Metric {
+        name: "newest_snapshot_age_seconds",
+        kind: "gauge",
+        help: "Time since the newest snapshot of the dataset was made",
+    }
+    .write(
+        &mut out,
+        datasets.iter().filter_map(|d| {
+            let newest = d.sorted_snapshots.first()?;
+            let age = now.signed_duration_since(newest.created).num_seconds();
+            Some((d.path.as_str(), age.max(0) as u128))
+        }),
+    );
+    Metric {
+        name: "snapshots_used_bytes",
+        kind: "gauge",
+        help: "Space used by the snapshots of the dataset",
+    }
+    .write(
+        &mut out,
+        datasets.iter().map(|d| {
+            let used = d.sorted_snapshots.iter().map(|s| s.used.get_bytes()).sum();
+            (d.path.as_str(), used)
+        }),
+    );
+    Metric {
+        name: "next_snapshot_seconds",
+        kind: "gauge",
+        help: "Time until the next snapshot of the dataset is due",
+    }
+    .write(
+        &mut out,
+        datasets.iter().filter_map(|d| {
+            let until = d.until_next_snapshot(now)?;
+            Some((d.path.as_str(), u128::from(until.as_secs())))
+        }),
+    );
+    Metric {
+        name: "pool_healthy",
+        kind: "gauge",
+        help: "Whether the pool of the dataset is online, not resilvering and writable",
+    }
+    .write(
+        &mut out,
+        datasets
+            .iter()
+            .map(|d| (d.path.as_str(), u128::from(health.problem(d).is_none()))),
+    );
+    Metric {
+        name: "failing_tasks",
+        kind: "gauge",
+        help: "Snapshots and destroys of the dataset that failed and wait for a retry",
+    }
+    .write(
+        &mut out,
+        datasets
+            .iter()
+            .map(|d| (d.path.as_str(), retries.of(&d.path).count() as u128)),
+    );
+
+    let counter = |f: fn(Counts) -> u64| {
+        datasets
+            .iter()
+            .map(move |d| (d.path.as_str(), u128::from(f(counters.get(&d.path)))))
+    };
+    Metric {
+        name: "snapshots_created_total",
+        kind: "counter",
+        help: "Snapshots made since the daemon started",
+    }
+    .write(&mut out, counter(|c| c.created));
+    Metric {
+        name: "snapshots_destroyed_total",
+        kind: "counter",
+        help: "Snapshots destroyed since the daemon started",
+    }
+    .write(&mut out, counter(|c| c.destroyed));
+    Metric {
+        name: "snapshot_failures_total",
+        kind: "counter",
+        help: "Failed attempts to make or destroy a snapshot since the daemon started",
+    }
+    .write(&mut out, counter(|c| c.failed));
+    Metric {
+        name: "failed_passes_total",
+        kind: "counter",
+        help: "Daemon passes that failed and were retried after a backoff",
+    }
+    .write_single(&mut out, u128::from(counters.failed_passes()));
+
+    out
+}
+
+/// Replaces the file in one go so the collector never reads half of it.
+pub fn write_textfile(path: &Path, metrics: &str) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, metrics)
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .wrap_err("Could not write metrics file")
+        .with_note(|| format!("path: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::config::Settings;
+    use crate::policy::RetentionPolicy;
+    use crate::policy::tests::aged;
+
+    #[test]
+    fn exposition_format() {
+        let datasets = [ConfiguredDataSet {
+            path: String::from("tank/\"odd\""),
+            retention_policy: RetentionPolicy::from_str("1h3").unwrap(),
+            sorted_snapshots: Box::new([aged!(10 m), aged!(70 m)]),
+            settings: Settings::default(),
+            conflicts: Vec::new(),
+            group: None,
+            written: None,
+        }];
+        let mut counters = Counters::default();
+        counters.created(&datasets[0].path);
+        counters.failed(&datasets[0].path);
+        counters.pass_failed();
+
+        let metrics = render(
+            &datasets,
+            &counters,
+            &Retries::default(),
+            &Health::default(),
+            Utc::now(),
+        );
+        let label = r#"{dataset="tank/\"odd\""}"#;
+        assert!(metrics.contains("# TYPE zcrab_snapshots gauge\n"));
+        assert!(metrics.contains(&format!("zcrab_snapshots{label} 2\n")));
+        assert!(metrics.contains(&format!("zcrab_newest_snapshot_age_seconds{label} 600\n")));
+        assert!(metrics.contains(&format!("zcrab_snapshots_created_total{label} 1\n")));
+        assert!(metrics.contains(&format!("zcrab_snapshots_destroyed_total{label} 0\n")));
+        assert!(metrics.contains(&format!("zcrab_snapshot_failures_total{label} 1\n")));
+        assert!(metrics.contains(&format!("zcrab_pool_healthy{label} 1\n")));
+        assert!(metrics.contains(&format!("zcrab_failing_tasks{label} 0\n")));
+        assert!(metrics.contains("zcrab_failed_passes_total 1\n"));
+    }
+}
@@ -0,0 +1,199 @@
+//! Converts the `at.rollc.at:snapkeep` properties of zfs-autosnap, which
+//! zcrab was forked from, into zcrab properties.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+
+use color_eyre::eyre::{WrapErr, eyre};
+use color_eyre::{Result, Section};
+use inquire::prompt_confirmation;
+
+use crate::lock::Locks;
+use crate::policy::{RetentionPolicy, ZFS_PROPERTY};
+use crate::zfs::ZfsBackend;
+
+pub const LEGACY_PROPERTY: &str = "at.rollc.at:snapkeep";
+
+/// zfs-autosnap keeps the newest snapshot of each calendar hour, day, week
+/// (starting on Monday), month and year.
+const LEGACY_UNITS: [(char, &str); 5] = [
+    ('h', "1h"),
+    ('d', "1d"),
+    ('w', "1w"),
+    ('m', "1mo"),
+    ('y', "1y"),
+];
+
+pub(crate) fn translate(legacy: &str) -> Result<RetentionPolicy> {
+    // both h24d30w8m6y1 and h:24,d:30,w:8 are in use
+    let compact: String = legacy
+        .chars()
+        .filter(|c| !matches!(c, ':' | ',' | ' '))
+        .collect();
+
+    let mut rules = Vec::new();
+    let mut rest = compact.as_str();
+    while let Some(unit) = rest.chars().next() {
+        let Some((_, period)) = LEGACY_UNITS.iter().find(|(u, _)| *u == unit) else {
+            return Err(eyre!("Unknown unit '{unit}' in legacy policy: '{legacy}'"));
+        };
+        rest = &rest[unit.len_utf8()..];
+        let (count, after) = rest.split_at(
+            rest.find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len()),
+        );
+        if count.is_empty() {
+            return Err(eyre!(
+                "Missing count after '{unit}' in legacy policy: '{legacy}'"
+            ));
+        }
+        rules.push(format!("{period}{count}@newest"));
+        rest = after;
+    }
+
+    RetentionPolicy::from_str(&rules.join(":")).with_note(|| format!("Legacy policy: {legacy}"))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Change {
+    /// Dataset or snapshot
+    name: String,
+    legacy: String,
+    /// `ZFS_PROPERTY` if it is set locally
+    current: Option<String>,
+    new: String,
+}
+
+fn changes(zfs: &dyn ZfsBackend) -> Result<Vec<Change>> {
+    let current: HashMap<_, _> = zfs.list_local(ZFS_PROPERTY)?.into_iter().collect();
+
+    let mut changes = Vec::new();
+    for (name, legacy) in zfs.list_local(LEGACY_PROPERTY)? {
+        // a '-' opts a snapshot out in both tools
+        let new = if legacy == "-" {
+            legacy.clone()
+        } else {
+            let policy = translate(&legacy).with_note(|| format!("Set on: {name}"))?;
+            format!("{policy:?}")
+        };
+
+        let current = current.get(&name).cloned();
+        if current.as_ref() == Some(&new) {
+            continue;
+        }
+        changes.push(Change {
+            name,
+            legacy,
+            current,
+            new,
+        });
+    }
+    Ok(changes)
+}
+
+fn write_diff(f: &mut impl Write, changes: &[Change]) {
+    for change in changes {
+        writeln!(f, "{}", change.name).unwrap();
+        writeln!(f, "    {LEGACY_PROPERTY}={}", change.legacy).unwrap();
+        if let Some(current) = &change.current {
+            writeln!(f, "  - {ZFS_PROPERTY}={current}").unwrap();
+        }
+        writeln!(f, "  + {ZFS_PROPERTY}={}", change.new).unwrap();
+    }
+}
+
+fn apply(zfs: &dyn ZfsBackend, locks: &Locks, changes: &[Change]) -> Result<()> {
+    for change in changes {
+        // for a snapshot the dataset it is of
+        let dataset = change
+            .name
+            .split_once('@')
+            .map_or(change.name.as_str(), |(dataset, _)| dataset);
+        let _locked = locks.datasets([dataset])?;
+        zfs.set_property(&change.name, ZFS_PROPERTY, &change.new)
+            .wrap_err("Could not set the new property")
+            .with_note(|| format!("On: {}", change.name))?;
+    }
+    Ok(())
+}
+
+pub fn run(zfs: &dyn ZfsBackend, locks: &Locks, sandbox: bool) -> Result<()> {
+    let changes = changes(zfs)?;
+    if changes.is_empty() {
+        println!("Nothing to migrate, no {LEGACY_PROPERTY} properties need converting");
+        return Ok(());
+    }
+
+    write_diff(&mut std::io::stdout(), &changes);
+    if sandbox || !prompt_confirmation("Apply these changes? (y/n)")? {
+        return Ok(());
+    }
+
+    apply(zfs, locks, &changes)?;
+    println!(
+        "Migrated {} datasets and snapshots. The {LEGACY_PROPERTY} properties \
+        are left in place but no longer read.",
+        changes.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zfs::fake::FakeZfs;
+
+    #[test]
+    fn translates_both_styles() {
+        let expected = "1h24@newest:1d30@newest:1w8@newest:1mo6@newest:1y1@newest";
+        for legacy in ["h24d30w8m6y1", "h:24,d:30,w:8,m:6,y1"] {
+            assert_eq!(format!("{:?}", translate(legacy).unwrap()), expected);
+        }
+        assert!(translate("x24").is_err());
+        assert!(translate("h").is_err());
+        assert!(translate("").is_err());
+    }
+
+    #[test]
+    fn migrates_policies_and_opt_outs() {
+        let zfs = FakeZfs::new(chrono::Utc::now());
+        zfs.add_dataset("tank");
+        zfs.add_dataset("tank/home");
+        zfs.add_dataset("tank/done");
+        zfs.set_property("tank", LEGACY_PROPERTY, "d7").unwrap();
+        zfs.set_property("tank/done", LEGACY_PROPERTY, "h1")
+            .unwrap();
+        zfs.set_property("tank/done", ZFS_PROPERTY, "1h1@newest")
+            .unwrap();
+        zfs.snapshot("tank/home@keep").unwrap();
+        zfs.set_property("tank/home@keep", LEGACY_PROPERTY, "-")
+            .unwrap();
+
+        let changes = changes(&zfs).unwrap();
+        assert_eq!(
+            changes,
+            [
+                Change {
+                    name: String::from("tank"),
+                    legacy: String::from("d7"),
+                    current: None,
+                    new: String::from("1d7@newest"),
+                },
+                Change {
+                    name: String::from("tank/home@keep"),
+                    legacy: String::from("-"),
+                    current: None,
+                    new: String::from("-"),
+                },
+            ]
+        );
+
+        apply(&zfs, &Locks::none(), &changes).unwrap();
+        assert_eq!(
+            zfs.get_property("tank/home", ZFS_PROPERTY).unwrap(),
+            "1d7@newest"
+        );
+        assert!(super::changes(&zfs).unwrap().is_empty());
+    }
+}
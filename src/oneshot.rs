@@ -0,0 +1,269 @@
+//! Single passes of the daemon, for running zcrab from cron or a systemd
+//! timer instead of as a long running service. Both exit with an error if
+//! any dataset failed, after trying all of them.
+
+use std::collections::HashSet;
+
+use color_eyre::eyre::eyre;
+use color_eyre::{Result, Section};
+
+use crate::config::Config;
+use crate::health::Health;
+use crate::lock::Locks;
+use crate::space;
+use crate::zfs::{self, ConfiguredDataSet, ZfsBackend, configured_datasets};
+use crate::{healthy, need_snapshot, removal_batches, remove_expired};
+
+/// Errors if a name in `only` is not a managed dataset
+fn check_selection(datasets: &[ConfiguredDataSet], only: &[String]) -> Result<()> {
+    if let Some(unknown) = only
+        .iter()
+        .find(|name| !datasets.iter().any(|d| &d.path == *name))
+    {
+        return Err(eyre!("Not a managed dataset: {unknown}"))
+            .suggestion("Only datasets with a retention policy can be selected");
+    }
+    Ok(())
+}
+
+/// The managed datasets named in `only`, all of them if it is empty
+fn selected(datasets: Vec<ConfiguredDataSet>, only: &[String]) -> Result<Vec<ConfiguredDataSet>> {
+    check_selection(&datasets, only)?;
+    Ok(datasets
+        .into_iter()
+        .filter(|d| only.is_empty() || only.contains(&d.path))
+        .collect())
+}
+
+pub(crate) fn report(failed: Vec<String>, action: &str) -> Result<()> {
+    if failed.is_empty() {
+        return Ok(());
+    }
+    Err(eyre!("Could not {action} {} datasets", failed.len()))
+        .with_note(|| format!("Failed datasets: {}", failed.join(", ")))
+}
+
+/// Snapshots the datasets that are due, or all selected with `force`.
+pub fn snap(
+    zfs: &dyn ZfsBackend,
+    config: &Config,
+    locks: &Locks,
+    only: &[String],
+    force: bool,
+    sandbox: bool,
+) -> Result<()> {
+    let datasets = configured_datasets(zfs, config)?;
+    check_selection(&datasets, only)?;
+    // a group is snapshotted as a whole by its leader, selecting any member
+    // selects the group
+    let leaders: HashSet<&str> = datasets
+        .iter()
+        .filter(|d| only.is_empty() || only.contains(&d.path))
+        .map(ConfiguredDataSet::leader)
+        .collect();
+    let due: Vec<_> = if force {
+        datasets
+            .iter()
+            .filter(|d| d.leader() == d.path)
+            .collect()
+    } else {
+        need_snapshot(&datasets, zfs.now()).collect()
+    };
+    let due = due
+        .into_iter()
+        .filter(|d| leaders.contains(d.path.as_str()))
+        .collect::<Vec<_>>();
+
+    let health = Health::check(zfs, &datasets);
+    health.alert();
+
+    let mut failed = Vec::new();
+    for dataset in due {
+        if !health.may_snapshot(&datasets, dataset) {
+            println!("skipped snapshot of {}: pool is unhealthy", dataset.path);
+            continue;
+        }
+        if sandbox {
+            println!("would snapshot dataset: {}", dataset.path);
+            continue;
+        }
+        let members = dataset.members(&datasets);
+        let _locked = locks.datasets(members.iter().map(|member| member.path.as_str()))?;
+        match zfs::snapshot(zfs, &datasets, dataset) {
+            Ok(made) => {
+                for snapshot in made {
+                    println!("made snapshot: {}", snapshot.name);
+                }
+            }
+            Err(e) => {
+                eprintln!("could not snapshot {}: {e}", dataset.path);
+                failed.push(dataset.path.clone());
+            }
+        }
+    }
+    report(failed, "snapshot")
+}
+
+/// Removes the snapshots rejected by the retention policies.
+pub fn gc(
+    zfs: &dyn ZfsBackend,
+    config: &Config,
+    locks: &Locks,
+    only: &[String],
+    sandbox: bool,
+) -> Result<()> {
+    let datasets = selected(configured_datasets(zfs, config)?, only)?;
+    let protected = zfs::protected(zfs, &datasets)?;
+    let health = Health::check(zfs, &datasets);
+    health.alert();
+
+    let mut failed: Vec<String> = Vec::new();
+    for (dataset, expired) in removal_batches(&datasets, &protected) {
+        if !health.may_destroy(dataset) {
+            for snapshot in expired {
+                println!("kept expired snapshot {}: pool is unhealthy", snapshot.name);
+            }
+            continue;
+        }
+        let _locked = (!sandbox)
+            .then(|| locks.datasets([dataset.path.as_str()]))
+            .transpose()?;
+        for (snapshot, result) in remove_expired(zfs, &datasets, dataset, &expired, sandbox) {
+            match result {
+                Ok(()) => println!("removed expired snapshot: {}", snapshot.name),
+                Err(e) => {
+                    eprintln!("could not remove {}: {e}", snapshot.name);
+                    if !failed.iter().any(|d| d == snapshot.dataset()) {
+                        failed.push(snapshot.dataset().to_string());
+                    }
+                }
+            }
+        }
+    }
+    if failed.is_empty() && space::limited(&datasets) {
+        let datasets = selected(configured_datasets(zfs, config)?, only)?;
+        let protected = zfs::protected(zfs, &datasets)?;
+        let datasets = healthy(datasets, &health);
+        for (name, e) in space::free_space(zfs, &datasets, &protected, locks, sandbox).failed {
+            eprintln!("could not free space on {name}: {e}");
+            if !failed.contains(&name) {
+                failed.push(name);
+            }
+        }
+    }
+    report(failed, "clean up")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{DateTime, Utc};
+
+    use super::*;
+    use crate::zfs::fake::FakeZfs;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn start() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z")
+            .unwrap()
+            .to_utc()
+    }
+
+    #[test]
+    fn snaps_only_when_due_unless_forced() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h3");
+
+        snap(&zfs, &Config::default(), &Locks::none(), &[], false, false).unwrap();
+        zfs.advance(Duration::from_secs(60));
+        snap(&zfs, &Config::default(), &Locks::none(), &[], false, false).unwrap();
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 1);
+
+        snap(&zfs, &Config::default(), &Locks::none(), &[], true, false).unwrap();
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 2);
+    }
+
+    #[test]
+    fn forced_snap_takes_each_group_once() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_dataset("tank/db");
+        zfs.add_dataset("fast/app");
+        zfs.add_configured_dataset("tank/home", "1h3");
+        let config: Config = toml::from_str(
+            r#"
+            [[dataset]]
+            name = "tank/db"
+            policy = "1h3"
+            group = "app"
+
+            [[dataset]]
+            name = "fast/app"
+            policy = "1h3"
+            group = "app"
+            "#,
+        )
+        .unwrap();
+
+        snap(&zfs, &config, &Locks::none(), &[], true, false).unwrap();
+        for only in ["tank/db", "fast/app"] {
+            zfs.advance(Duration::from_secs(60));
+            let only = [String::from(only)];
+            snap(&zfs, &config, &Locks::none(), &only, true, false).unwrap();
+        }
+        assert_eq!(zfs.snapshots_of("tank/db").len(), 3);
+        assert_eq!(zfs.snapshots_of("fast/app").len(), 3);
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 1);
+    }
+
+    #[test]
+    fn dataset_filter() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h3");
+        zfs.add_configured_dataset("tank/media", "1h3");
+        zfs.add_dataset("tank/scratch");
+
+        let only = [String::from("tank/media")];
+        snap(&zfs, &Config::default(), &Locks::none(), &only, false, false).unwrap();
+        assert!(zfs.snapshots_of("tank/home").is_empty());
+        assert_eq!(zfs.snapshots_of("tank/media").len(), 1);
+
+        let only = [String::from("tank/scratch")];
+        assert!(snap(&zfs, &Config::default(), &Locks::none(), &only, false, false).is_err());
+    }
+
+    #[test]
+    fn gc_removes_expired() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h1");
+        for _ in 0..3 {
+            snap(&zfs, &Config::default(), &Locks::none(), &[], false, false).unwrap();
+            zfs.advance(HOUR);
+        }
+
+        gc(&zfs, &Config::default(), &Locks::none(), &[], true).unwrap();
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 3);
+        gc(&zfs, &Config::default(), &Locks::none(), &[], false).unwrap();
+        assert_eq!(zfs.snapshots_of("tank/home").len(), 1);
+    }
+
+    #[test]
+    fn failures_give_an_error() {
+        let zfs = FakeZfs::new(start());
+        let config: Config = toml::from_str(
+            r#"
+            [[dataset]]
+            name = "tank/home"
+            policy = "1h3"
+            naming = "fixed"
+            "#,
+        )
+        .unwrap();
+        zfs.add_dataset("tank/home");
+
+        snap(&zfs, &config, &Locks::none(), &[], false, false).unwrap();
+        let err = snap(&zfs, &config, &Locks::none(), &[], true, false).unwrap_err();
+        assert_eq!(err.to_string(), "Could not snapshot 1 datasets");
+    }
+}
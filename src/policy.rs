@@ -1,4 +1,4 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use color_eyre::eyre::{Context, eyre};
 use color_eyre::{Result, Section};
 use core::fmt;
@@ -9,6 +9,8 @@ use std::time::Duration;
 
 use crate::zfs::SnapshotMetadata;
 
+mod calendar;
+
 // User property names must contain a colon (":") character to distinguish them from native
 // properties.  They may contain lowercase letters, numbers, and the following punctuation
 // characters: colon (":"), dash ("-"), period ("."), and underscore ("_").  The expected
@@ -17,15 +19,19 @@ use crate::zfs::SnapshotMetadata;
 // at most 256 characters, and cannot begin with a dash ("-").
 
 pub const ZFS_PROPERTY: &str = concat!(env!("CARGO_PKG_NAME"), ":policy");
+// Policy for the replicated copies on the target, set on the source dataset
+#[cfg(feature = "ssh")]
+pub const TARGET_ZFS_PROPERTY: &str = concat!(env!("CARGO_PKG_NAME"), ":target-policy");
 
 // Describes the number of snapshots to keep for each period.
-#[derive(PartialEq, Eq, Hash, Clone)]
+#[derive(PartialEq, Eq, Hash, Clone, serde::Deserialize)]
+#[serde(try_from = "String")]
 pub struct RetentionPolicy(pub Vec<RetentionRule>);
 
 impl fmt::Debug for RetentionPolicy {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for rule in self.0.iter().take(self.0.len().saturating_sub(1)) {
-            f.write_fmt(format_args!("{rule:?} "))?;
+            f.write_fmt(format_args!("{rule:?}:"))?;
         }
         if let Some(last) = self.0.last() {
             f.write_fmt(format_args!("{last:?}"))?;
@@ -37,56 +43,165 @@ impl fmt::Debug for RetentionPolicy {
 
 #[derive(PartialEq, Eq, Hash, Clone)]
 pub struct RetentionRule {
-    pub snapshot_period: Duration,
+    pub snapshot_period: Period,
     pub retained_copies: usize,
+    pub spacing: Spacing,
+}
+
+/// Time between snapshots, months and years follow the calendar
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Period {
+    Fixed(Duration),
+    Months(u32),
+}
+
+/// How a rule picks the snapshots it retains
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Spacing {
+    /// At least `snapshot_period` apart counting from the oldest snapshot
+    Rolling,
+    /// One per `snapshot_period` long bucket aligned to the wall clock (top
+    /// of the hour, local midnight, Monday, January 1st).
+    Calendar(Keep),
+}
+
+/// Which snapshot of a calendar bucket is retained
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Keep {
+    Oldest,
+    Newest,
 }
 
-const VALID_SYNTAX: [(char, u64); 6] = [
-    ('s', 1),
-    ('m', 60),
-    ('h', 60 * 60),
-    ('d', 60 * 60 * 24),
-    ('w', 60 * 60 * 24 * 7),
-    ('y', 60 * 60 * 24 * 365),
+impl Spacing {
+    const SYNTAX: [(&str, Spacing); 2] = [
+        ("@oldest", Spacing::Calendar(Keep::Oldest)),
+        ("@newest", Spacing::Calendar(Keep::Newest)),
+    ];
+
+    fn suffix(&self) -> &'static str {
+        Self::SYNTAX
+            .iter()
+            .find(|(_, spacing)| spacing == self)
+            .map_or("", |(suffix, _)| suffix)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Unit {
+    Seconds(u64),
+    Months(u32),
+}
+
+// (token, name, length)
+const VALID_SYNTAX: [(&str, &str, Unit); 7] = [
+    ("s", "seconds", Unit::Seconds(1)),
+    ("min", "minutes", Unit::Seconds(60)),
+    ("h", "hours", Unit::Seconds(60 * 60)),
+    ("d", "days", Unit::Seconds(60 * 60 * 24)),
+    ("w", "weeks", Unit::Seconds(60 * 60 * 24 * 7)),
+    ("mo", "months", Unit::Months(1)),
+    ("y", "years", Unit::Months(12)),
 ];
 
-impl fmt::Debug for RetentionRule {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let period = self.snapshot_period.as_secs();
-        for (unit, duration) in VALID_SYNTAX.iter().rev() {
-            if period % duration == 0 && period / duration > 0 {
-                let amount = period / duration;
-                return f.write_fmt(format_args!("{amount}{unit}:{}", self.retained_copies));
+impl Period {
+    /// Average length of a month in the gregorian calendar
+    const MONTH: Duration = Duration::from_secs(2_629_746);
+
+    fn new(amount: u32, unit: Unit) -> Self {
+        match unit {
+            Unit::Seconds(secs) => Period::Fixed(Duration::from_secs(secs * u64::from(amount))),
+            Unit::Months(months) => Period::Months(months.saturating_mul(amount)),
+        }
+    }
+
+    /// The moment one period after `time`, adding months keeps the day of
+    /// the month where possible (Jan 31 + 1mo is Feb 28 or 29).
+    pub fn after(self, time: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Period::Fixed(duration) => time + duration,
+            Period::Months(months) => time
+                .checked_add_months(chrono::Months::new(months))
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        }
+    }
+
+    /// Exact for fixed periods, months count as an average month
+    pub fn approximate(self) -> Duration {
+        match self {
+            Period::Fixed(duration) => duration,
+            Period::Months(months) => Self::MONTH * months,
+        }
+    }
+
+    fn is_zero(self) -> bool {
+        match self {
+            Period::Fixed(duration) => duration.is_zero(),
+            Period::Months(months) => months == 0,
+        }
+    }
+
+    /// Amount together with the token and name of the largest unit that
+    /// fits a whole number of times.
+    fn in_largest_unit(self) -> (u64, &'static str, &'static str) {
+        for (token, name, unit) in VALID_SYNTAX.iter().rev() {
+            match (self, unit) {
+                (Period::Fixed(duration), Unit::Seconds(secs))
+                    if duration.as_secs().is_multiple_of(*secs) && duration.as_secs() > 0 =>
+                {
+                    return (duration.as_secs() / secs, token, name);
+                }
+                (Period::Months(months), Unit::Months(per))
+                    if months.is_multiple_of(*per) && months > 0 =>
+                {
+                    return (u64::from(months / per), token, name);
+                }
+                _ => (),
             }
         }
+        (0, "s", "seconds")
+    }
+}
 
-        f.write_str("RetentionRule should be formattable")
+impl Ord for Period {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.approximate().cmp(&other.approximate())
+    }
+}
+
+impl PartialOrd for Period {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Debug for RetentionRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (amount, unit, _) = self.snapshot_period.in_largest_unit();
+        f.write_fmt(format_args!(
+            "{amount}{unit}{}{}",
+            self.retained_copies,
+            self.spacing.suffix()
+        ))
     }
 }
 
 impl fmt::Display for RetentionRule {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        const VALID_SYNTAX: [(&str, u64); 6] = [
-            ("seconds", 1),
-            ("minutes", 60),
-            ("hours", 60 * 60),
-            ("days", 60 * 60 * 24),
-            ("weeks", 60 * 60 * 24 * 7),
-            ("years", 60 * 60 * 24 * 365),
-        ];
-
-        let period = self.snapshot_period.as_secs();
-        for (unit, duration) in VALID_SYNTAX.iter().rev() {
-            if period % duration == 0 && period / duration > 0 {
-                let amount = period / duration;
-                return f.write_fmt(format_args!(
-                    "maintain last {amount} snapshots spaced out by {} {unit}",
-                    self.retained_copies
-                ));
+        let (amount, _, unit) = self.snapshot_period.in_largest_unit();
+        f.write_fmt(format_args!(
+            "maintain last {} snapshots spaced out by {amount} {unit}",
+            self.retained_copies
+        ))?;
+        match self.spacing {
+            Spacing::Rolling => Ok(()),
+            Spacing::Calendar(Keep::Oldest) => {
+                f.write_str(" aligned to the calendar, keeping the oldest")
+            }
+            Spacing::Calendar(Keep::Newest) => {
+                f.write_str(" aligned to the calendar, keeping the newest")
             }
         }
-
-        f.write_str("RetentionRule should be formattable")
     }
 }
 
@@ -106,31 +221,55 @@ impl FromStr for RetentionRule {
     type Err = color_eyre::Report;
 
     fn from_str(rule: &str) -> Result<Self> {
-        for (unit, unit_duration) in VALID_SYNTAX {
-            if let Some((unit_amount, retained_copies)) = rule.split_once(unit) {
-                let unit_amount = unit_amount
-                    .parse::<usize>()
-                    .wrap_err("Could not parse duration between snapshots")
-                    .with_note(|| format!("Rule input: {rule}"))?;
-                let retained_copies = retained_copies
-                    .parse::<usize>()
-                    .wrap_err("Could not parse number of copies to keep")
-                    .with_note(|| format!("Rule input: {rule}"))?;
-                return Ok(Self {
-                    snapshot_period: Duration::from_secs(unit_duration).mul_f64(unit_amount as f64),
-                    retained_copies,
-                });
-            }
-        }
-
-        Err(
-            eyre!("No valid time unit found in rule: '{rule}'").with_note(|| {
+        let (spacing, without_suffix) = Spacing::SYNTAX
+            .iter()
+            .find_map(|(suffix, spacing)| Some((*spacing, rule.strip_suffix(suffix)?)))
+            .unwrap_or((Spacing::Rolling, rule));
+
+        // <amount><unit><retained copies>
+        let (amount, rest) = without_suffix.split_at(
+            without_suffix
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(without_suffix.len()),
+        );
+        let (token, retained_copies) = rest.split_at(
+            rest.find(|c: char| c.is_ascii_digit())
+                .unwrap_or(rest.len()),
+        );
+
+        let Some((_, _, unit)) = VALID_SYNTAX.iter().find(|(t, _, _)| *t == token) else {
+            let err = if token == "m" {
+                eyre!("Ambiguous time unit 'm' in rule: '{rule}'")
+                    .with_note(|| "Use 'min' for minutes or 'mo' for months")
+            } else {
+                eyre!("No valid time unit found in rule: '{rule}'")
+            };
+            return Err(err).with_note(|| {
                 format!(
-                    "Valid patterns are: {}",
-                    VALID_SYNTAX.iter().map(|(pat, _)| pat).join("|")
+                    "Valid patterns are: {}, optionally followed by {}",
+                    VALID_SYNTAX.iter().map(|(token, _, _)| token).join("|"),
+                    Spacing::SYNTAX.iter().map(|(pat, _)| pat).join("|")
                 )
-            }),
-        )
+            });
+        };
+
+        let amount = amount
+            .parse::<u32>()
+            .wrap_err("Could not parse duration between snapshots")
+            .with_note(|| format!("Rule input: {rule}"))?;
+        let retained_copies = retained_copies
+            .parse::<usize>()
+            .wrap_err("Could not parse number of copies to keep")
+            .with_note(|| format!("Rule input: {rule}"))?;
+        let snapshot_period = Period::new(amount, *unit);
+        if snapshot_period.is_zero() && spacing != Spacing::Rolling {
+            return Err(eyre!("Calendar aligned rules need a period: '{rule}'"));
+        }
+        Ok(Self {
+            snapshot_period,
+            retained_copies,
+            spacing,
+        })
     }
 }
 
@@ -138,6 +277,19 @@ impl FromStr for RetentionPolicy {
     type Err = color_eyre::Report;
 
     fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return Err(eyre!("'{s}' is a zfs-autosnap style policy"))
+                .with_note(|| {
+                    "Rules are now written as <period><unit><copies> and separated \
+                    by ':', where 'min' means minutes and 'mo' months"
+                })
+                .suggestion(concat!(
+                    "Run '",
+                    env!("CARGO_PKG_NAME"),
+                    " migrate' to convert at.rollc.at:snapkeep properties"
+                ));
+        }
+
         let mut rules: Vec<_> = s
             .split(':')
             .map(RetentionRule::from_str)
@@ -155,27 +307,68 @@ impl FromStr for RetentionPolicy {
     }
 }
 
+impl TryFrom<String> for RetentionPolicy {
+    type Error = color_eyre::Report;
+
+    fn try_from(s: String) -> Result<Self> {
+        Self::from_str(&s)
+    }
+}
+
 impl RetentionRule {
-    pub fn next_snapshot_in(&self, snapshots: &[SnapshotMetadata]) -> Option<Duration> {
+    pub fn next_snapshot_in(
+        &self,
+        snapshots: &[SnapshotMetadata],
+        now: DateTime<Utc>,
+    ) -> Option<Duration> {
+        if let Spacing::Calendar(_) = self.spacing {
+            return Some(self.next_calendar_snapshot_in(snapshots, now));
+        }
+
         let mut snapshots_oldest_first = snapshots.iter().collect_vec();
         snapshots_oldest_first.sort();
 
-        let now = Utc::now();
-        not_too_old(&snapshots_oldest_first, self)
-            .last()
-            .map(|snapshot| snapshot.created + self.snapshot_period)
-            .map(|next_at| {
-                next_at
-                    .signed_duration_since(now)
-                    .to_std()
-                    .unwrap_or(Duration::ZERO)
-            })
+        // without any snapshot the first one is due right away
+        let Some(newest) = not_too_old(&snapshots_oldest_first, self).last() else {
+            return Some(Duration::ZERO);
+        };
+        let next_at = self.snapshot_period.after(newest.created);
+        Some(
+            next_at
+                .signed_duration_since(now)
+                .to_std()
+                .unwrap_or(Duration::ZERO),
+        )
+    }
+
+    /// Due right away unless the current bucket already has a snapshot
+    fn next_calendar_snapshot_in(
+        &self,
+        snapshots: &[SnapshotMetadata],
+        now: DateTime<Utc>,
+    ) -> Duration {
+        let current = calendar::bucket(self.snapshot_period, now);
+        if snapshots
+            .iter()
+            .all(|s| calendar::bucket(self.snapshot_period, s.created) < current)
+        {
+            return Duration::ZERO;
+        }
+
+        calendar::bucket_start(self.snapshot_period, current + 1)
+            .signed_duration_since(now)
+            .to_std()
+            .unwrap_or(Duration::ZERO)
     }
 
     pub(crate) fn rejects<'a>(
         &self,
         snapshots_oldest_first: &[&'a SnapshotMetadata],
     ) -> HashSet<&'a SnapshotMetadata> {
+        if let Spacing::Calendar(keep) = self.spacing {
+            return self.calendar_rejects(snapshots_oldest_first, keep);
+        }
+
         let mut to_remove: HashSet<_> = snapshots_oldest_first.iter().copied().collect();
 
         let not_too_old = not_too_old(snapshots_oldest_first, self);
@@ -183,13 +376,41 @@ impl RetentionRule {
         let mut retain_next_newer_than = chrono::DateTime::from_timestamp_millis(0).unwrap();
         for snapshot in not_too_old {
             if snapshot.created >= retain_next_newer_than {
-                retain_next_newer_than = snapshot.created + self.snapshot_period;
+                retain_next_newer_than = self.snapshot_period.after(snapshot.created);
                 to_remove.remove(snapshot);
             }
         }
 
         to_remove
     }
+
+    /// Keeps one snapshot from each of the newest `retained_copies` buckets
+    /// that have any snapshots.
+    fn calendar_rejects<'a>(
+        &self,
+        snapshots_oldest_first: &[&'a SnapshotMetadata],
+        keep: Keep,
+    ) -> HashSet<&'a SnapshotMetadata> {
+        let mut to_remove: HashSet<_> = snapshots_oldest_first.iter().copied().collect();
+
+        let mut buckets: BTreeMap<i64, Vec<&SnapshotMetadata>> = BTreeMap::new();
+        for snapshot in snapshots_oldest_first {
+            let bucket = calendar::bucket(self.snapshot_period, snapshot.created);
+            buckets.entry(bucket).or_default().push(snapshot);
+        }
+
+        for in_bucket in buckets.values().rev().take(self.retained_copies) {
+            let kept = match keep {
+                Keep::Oldest => in_bucket.first(),
+                Keep::Newest => in_bucket.last(),
+            };
+            if let Some(kept) = kept {
+                to_remove.remove(kept);
+            }
+        }
+
+        to_remove
+    }
 }
 
 type Retainers<'rules> = HashSet<&'rules RetentionRule>;
@@ -211,14 +432,23 @@ impl fmt::Debug for Judgement<'_, '_> {
     }
 }
 
-impl RetentionPolicy {
-    pub fn next_snapshot_in(&self, snapshots: &[SnapshotMetadata]) -> Option<Duration> {
-        let mut snapshots_oldest_first = snapshots.to_vec();
-        snapshots_oldest_first.sort();
+/// The last rule to retain a rejected snapshot and the snapshot whose
+/// arrival made it let go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DroppedOut<'snapshots, 'rules> {
+    pub rule: &'rules RetentionRule,
+    pub by: &'snapshots SnapshotMetadata,
+}
 
+impl RetentionPolicy {
+    pub fn next_snapshot_in(
+        &self,
+        snapshots: &[SnapshotMetadata],
+        now: DateTime<Utc>,
+    ) -> Option<Duration> {
         self.0
             .iter()
-            .filter_map(|rule| rule.next_snapshot_in(snapshots))
+            .filter_map(|rule| rule.next_snapshot_in(snapshots, now))
             .min()
     }
 
@@ -261,6 +491,53 @@ impl RetentionPolicy {
 
         res
     }
+
+    /// Why each of the `rejected` snapshots is no longer retained, None for
+    /// those no rule ever retained. Found by judging the snapshots as they
+    /// were before each one was made, newest first, until the last rule to
+    /// retain each rejected snapshot is known. Snapshots destroyed since are
+    /// not taken into account.
+    pub fn dropped_out<'snapshots, 'rules>(
+        &'rules self,
+        snapshots_newest_first: &'snapshots [SnapshotMetadata],
+        rejected: &HashSet<&'snapshots SnapshotMetadata>,
+    ) -> HashMap<&'snapshots SnapshotMetadata, Option<DroppedOut<'snapshots, 'rules>>> {
+        let mut snapshots_oldest_first = snapshots_newest_first.iter().collect_vec();
+        snapshots_oldest_first.sort();
+        let mut dropped: HashMap<_, Option<DroppedOut>> =
+            rejected.iter().map(|snapshot| (*snapshot, None)).collect();
+        // (position, snapshot) oldest first, each leaves once it is resolved
+        let mut unresolved = snapshots_oldest_first
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, snapshot)| rejected.contains(snapshot))
+            .collect_vec();
+
+        // every rule rejects them all once every snapshot is made, the first
+        // rule found retaining one walking back let go of it last
+        for made in (1..snapshots_oldest_first.len()).rev() {
+            let Some((oldest, _)) = unresolved.first() else {
+                break;
+            };
+            if made <= *oldest {
+                break;
+            }
+            let made_so_far = &snapshots_oldest_first[..made];
+            let by = snapshots_oldest_first[made];
+            for rule in self.0.iter().rev() {
+                let rejects = rule.rejects(made_so_far);
+                unresolved.retain(|(position, snapshot)| {
+                    if *position >= made || rejects.contains(snapshot) {
+                        return true;
+                    }
+                    dropped.insert(snapshot, Some(DroppedOut { rule, by }));
+                    false
+                });
+            }
+        }
+        dropped
+    }
 }
 
 fn not_too_old<'a>(
@@ -276,7 +553,7 @@ fn not_too_old<'a>(
         let mut n_considerd = 0;
         for snapshot in snapshots_oldest_first.iter().skip(n) {
             if snapshot.created >= keep_next_newer_than {
-                keep_next_newer_than = snapshot.created + rule.snapshot_period;
+                keep_next_newer_than = rule.snapshot_period.after(snapshot.created);
                 n_considerd += 1;
             }
         }
@@ -334,24 +611,174 @@ pub(crate) mod tests {
     }
     pub(crate) use aged;
 
+    #[test]
+    fn debug_format_parses_back() {
+        let policy = RetentionPolicy::from_str("1d30:15min8:1w4").unwrap();
+        assert_eq!(format!("{policy:?}"), "15min8:1d30:1w4");
+        assert_eq!(
+            RetentionPolicy::from_str(&format!("{policy:?}")).unwrap(),
+            policy
+        );
+    }
+
+    mod grammar {
+        use super::*;
+
+        #[test]
+        fn months_and_years_follow_the_calendar() {
+            let policy = RetentionPolicy::from_str("1mo6:1y2:30min4").unwrap();
+            assert_eq!(format!("{policy:?}"), "30min4:1mo6:1y2");
+            assert_eq!(policy.0[1].snapshot_period, Period::Months(1));
+            assert_eq!(policy.0[2].snapshot_period, Period::Months(12));
+            assert_eq!(
+                format!("{:?}", RetentionRule::from_str("24mo1").unwrap()),
+                "2y1"
+            );
+
+            let jan_31 = DateTime::parse_from_rfc3339("2024-01-31T12:00:00Z")
+                .unwrap()
+                .to_utc();
+            let feb_29 = DateTime::parse_from_rfc3339("2024-02-29T12:00:00Z")
+                .unwrap()
+                .to_utc();
+            assert_eq!(Period::Months(1).after(jan_31), feb_29);
+            let next_feb_28 = DateTime::parse_from_rfc3339("2025-02-28T12:00:00Z")
+                .unwrap()
+                .to_utc();
+            assert_eq!(Period::Months(12).after(feb_29), next_feb_28);
+        }
+
+        #[test]
+        fn rejects_ambiguous_input() {
+            let err = RetentionRule::from_str("5m10").unwrap_err();
+            assert!(err.to_string().contains("Ambiguous time unit"));
+            let err = RetentionPolicy::from_str("h24d30w8m6y1").unwrap_err();
+            assert!(err.to_string().contains("zfs-autosnap"));
+            assert!(RetentionRule::from_str("1x2").is_err());
+        }
+    }
+
     mod snapshot_creation {
         use super::*;
 
         #[test]
         fn optimal_interval() {
-            let policy = RetentionPolicy::from_str("10m2").unwrap();
+            let policy = RetentionPolicy::from_str("10min2").unwrap();
             let snapshots = [aged!(5 m), aged!(15 m)];
-            let next_in = policy.next_snapshot_in(&snapshots).unwrap();
+            let next_in = policy.next_snapshot_in(&snapshots, Utc::now()).unwrap();
             assert_eq!(next_in.as_secs_f32().round() as usize, 60 * 5);
         }
+
+        #[test]
+        fn first_snapshot_is_due_immediately() {
+            let policy = RetentionPolicy::from_str("10min2").unwrap();
+            let next_in = policy.next_snapshot_in(&[], Utc::now()).unwrap();
+            assert_eq!(next_in, Duration::ZERO);
+        }
+    }
+
+    mod calendar_aligned {
+        use chrono::{Local, NaiveDate, TimeZone};
+
+        use super::*;
+
+        fn at(day: u32, hour: u32, minute: u32) -> SnapshotMetadata {
+            let local = NaiveDate::from_ymd_opt(2025, 3, day)
+                .unwrap()
+                .and_hms_opt(hour, minute, 0)
+                .unwrap();
+            SnapshotMetadata {
+                name: format!("{day}T{hour}:{minute}"),
+                created: Local.from_local_datetime(&local).unwrap().to_utc(),
+                used: Byte::from_bytes(0),
+            }
+        }
+
+        fn names(rejected: HashSet<&SnapshotMetadata>) -> BTreeSet<&str> {
+            rejected.iter().map(|s| s.name.as_str()).collect()
+        }
+
+        #[test]
+        fn parse_and_format() {
+            let policy = RetentionPolicy::from_str("1h24@newest:1d7@oldest:1w4").unwrap();
+            assert_eq!(format!("{policy:?}"), "1h24@newest:1d7@oldest:1w4");
+            assert_eq!(policy.0[0].spacing, Spacing::Calendar(Keep::Newest));
+            assert!(RetentionRule::from_str("0d7@oldest").is_err());
+            assert!(RetentionRule::from_str("1d7@first").is_err());
+        }
+
+        #[test]
+        fn keeps_one_per_local_day() {
+            let snapshots = [
+                at(12, 23, 30),
+                at(12, 13, 0),
+                at(12, 0, 10),
+                at(11, 23, 59),
+                at(11, 0, 0),
+                at(10, 12, 0),
+            ];
+            let oldest = RetentionPolicy::from_str("1d2@oldest").unwrap();
+            assert_eq!(
+                names(oldest.judge(&snapshots).rejected),
+                BTreeSet::from(["12T13:0", "12T23:30", "11T23:59", "10T12:0"])
+            );
+            let newest = RetentionPolicy::from_str("1d2@newest").unwrap();
+            assert_eq!(
+                names(newest.judge(&snapshots).rejected),
+                BTreeSet::from(["12T13:0", "12T0:10", "11T0:0", "10T12:0"])
+            );
+        }
+
+        #[test]
+        fn next_snapshot_on_the_hour() {
+            let policy = RetentionPolicy::from_str("1h24@oldest").unwrap();
+            let now = at(12, 10, 20).created;
+            let next_in = policy.next_snapshot_in(&[at(12, 10, 0)], now).unwrap();
+            assert_eq!(next_in, Duration::from_secs(40 * 60));
+            let next_in = policy.next_snapshot_in(&[at(12, 9, 59)], now).unwrap();
+            assert_eq!(next_in, Duration::ZERO);
+        }
+
+        #[test]
+        fn weeks_start_on_monday() {
+            // the 10th of March 2025 is a monday
+            let policy = RetentionPolicy::from_str("1w1@oldest").unwrap();
+            let snapshots = [at(10, 0, 0), at(9, 23, 0)];
+            let now = at(12, 10, 0).created;
+            let next_in = policy.next_snapshot_in(&snapshots, now).unwrap();
+            assert_eq!(now + next_in, at(17, 0, 0).created);
+            assert_eq!(
+                names(policy.judge(&snapshots).rejected),
+                BTreeSet::from(["9T23:0"])
+            );
+        }
     }
 
     mod snapshot_removal {
         use super::*;
 
+        #[test]
+        fn dropped_out_when_newer_arrived() {
+            let policy = RetentionPolicy::from_str("1h2").unwrap();
+            let snapshots = [aged!(50 m), aged!(100 m), aged!(130 m), aged!(200 m)];
+            let judgement = policy.judge(&snapshots);
+            let dropped = policy.dropped_out(&snapshots, &judgement.rejected);
+            let dropped = dropped
+                .iter()
+                .map(|(snapshot, dropped)| {
+                    let by = dropped.map(|d| (format!("{:?}", d.rule), d.by.name.as_str()));
+                    (snapshot.name.as_str(), by)
+                })
+                .collect::<BTreeMap<_, _>>();
+            assert_eq!(
+                dropped,
+                BTreeMap::from([("100m", None), ("200m", Some((String::from("1h2"), "50m")))])
+            );
+        }
+
         #[test]
         fn kept_util_amount_times_period() {
-            let policy = RetentionPolicy::from_str("10m2").unwrap();
+            let policy = RetentionPolicy::from_str("10min2").unwrap();
             let snapshots = [aged!(8 m), aged!(19 m), aged!(30 m)];
             let rejected = policy.judge(&snapshots).rejected;
             let rejected = rejected.iter().map(|s| (*s).clone()).collect::<Vec<_>>();
@@ -375,7 +802,7 @@ pub(crate) mod tests {
 
         #[test]
         fn retained_short_do_not_count_to_retained_long() {
-            let policy = RetentionPolicy::from_str("50s2:10m2").unwrap();
+            let policy = RetentionPolicy::from_str("50s2:10min2").unwrap();
             let snapshots = [
                 aged!(38 s),
                 aged!(79 s),
@@ -0,0 +1,63 @@
+//! Buckets aligned to wall clock boundaries in local time. Periods that
+//! divide a day start at midnight, days at midnight, weeks on Monday and
+//! months on the first of the month, years start in January.
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+use super::Period;
+
+/// Monday the 5th of January 1970 at midnight
+fn origin() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(1970, 1, 5)
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .expect("is a valid date")
+}
+
+fn period_secs(period: std::time::Duration) -> i64 {
+    i64::try_from(period.as_secs())
+        .expect("periods are far shorter then i64::MAX seconds")
+        .max(1)
+}
+
+/// Index of the bucket `time` falls in, later buckets have higher indices
+pub(super) fn bucket(period: Period, time: DateTime<Utc>) -> i64 {
+    let local = time.with_timezone(&Local).naive_local();
+    match period {
+        Period::Fixed(duration) => (local - origin())
+            .num_seconds()
+            .div_euclid(period_secs(duration)),
+        Period::Months(months) => {
+            let month = i64::from(local.year()) * 12 + i64::from(local.month0());
+            month.div_euclid(i64::from(months.max(1)))
+        }
+    }
+}
+
+pub(super) fn bucket_start(period: Period, bucket: i64) -> DateTime<Utc> {
+    let start = match period {
+        Period::Fixed(duration) => {
+            origin() + chrono::Duration::seconds(bucket * period_secs(duration))
+        }
+        Period::Months(months) => {
+            let month = bucket * i64::from(months.max(1));
+            i32::try_from(month.div_euclid(12))
+                .ok()
+                .and_then(|year| NaiveDate::from_ymd_opt(year, month.rem_euclid(12) as u32 + 1, 1))
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .expect("bucket is near the current date")
+        }
+    };
+
+    // When the clock jumps forward some local times do not exist, the
+    // bucket then starts at the first time that does.
+    Local
+        .from_local_datetime(&start)
+        .earliest()
+        .or_else(|| {
+            Local
+                .from_local_datetime(&(start + chrono::Duration::hours(1)))
+                .earliest()
+        })
+        .expect("clock jumps are shorter then an hour")
+        .to_utc()
+}
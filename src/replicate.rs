@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use color_eyre::eyre::{WrapErr, eyre};
+use color_eyre::{Result, Section};
+use itertools::Itertools;
+
+use crate::DataSet;
+use crate::config::Config;
+use crate::lock::Locks;
+use crate::oneshot::report;
+use crate::policy::{RetentionPolicy, TARGET_ZFS_PROPERTY};
+use crate::ssh::Connection;
+use crate::zfs::{
+    ConfiguredDataSet, REPLICATION_ANCHOR, SnapshotMetadata, ZfsBackend, configured_datasets,
+};
+
+/// Set on a dataset to replicate it, the value has the form `host:dataset`
+/// where host is anything ssh accepts.
+pub const TARGET_PROPERTY: &str = concat!(env!("CARGO_PKG_NAME"), ":replicate-to");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Target {
+    pub(crate) host: String,
+    pub(crate) dataset: String,
+    /// From `TARGET_ZFS_PROPERTY`, without one nothing is pruned on the target
+    pub(crate) retention_policy: Option<RetentionPolicy>,
+}
+
+impl FromStr for Target {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some((host, dataset)) if !host.is_empty() && !dataset.is_empty() => Ok(Self {
+                host: host.to_string(),
+                dataset: dataset.to_string(),
+                retention_policy: None,
+            }),
+            _ => Err(eyre!("Invalid replication target: '{s}'"))
+                .with_note(|| "The target should look like: host:pool/dataset"),
+        }
+    }
+}
+
+/// What needs to be sent to bring the target up to date
+#[derive(Debug, PartialEq, Eq)]
+enum Plan<'a> {
+    UpToDate,
+    Full {
+        snapshot: &'a SnapshotMetadata,
+    },
+    Incremental {
+        base: &'a SnapshotMetadata,
+        snapshot: &'a SnapshotMetadata,
+    },
+}
+
+/// Returns None if there is nothing to send, `remote` is None if the target
+/// dataset does not exist yet.
+fn plan<'a>(
+    local_newest_first: &'a [SnapshotMetadata],
+    remote: Option<&[SnapshotMetadata]>,
+) -> Option<Plan<'a>> {
+    let newest = local_newest_first.first()?;
+    let remote = remote.unwrap_or_default();
+    let newest_common = local_newest_first.iter().find(|snapshot| {
+        remote
+            .iter()
+            .any(|r| r.short_name() == snapshot.short_name())
+    });
+
+    Some(match newest_common {
+        Some(common) if common == newest => Plan::UpToDate,
+        Some(base) => Plan::Incremental {
+            base,
+            snapshot: newest,
+        },
+        None => Plan::Full { snapshot: newest },
+    })
+}
+
+/// The targets set locally on datasets, an inherited target would have the
+/// descendants all replicate into the same dataset. A target that does not
+/// parse only fails its own dataset.
+fn targets(zfs: &dyn ZfsBackend) -> Result<HashMap<DataSet, Result<Target>>> {
+    let mut policies: HashMap<_, _> = zfs
+        .list_local(TARGET_ZFS_PROPERTY)?
+        .into_iter()
+        .filter(|(name, policy)| !name.contains('@') && policy != "-")
+        .collect();
+
+    Ok(zfs
+        .list_local(TARGET_PROPERTY)?
+        .into_iter()
+        .filter(|(name, target)| !name.contains('@') && target != "-")
+        .map(|(dataset, target)| {
+            let policy = policies.remove(&dataset);
+            let target = Target::from_str(&target).and_then(|mut target| {
+                target.retention_policy = policy
+                    .map(|policy| RetentionPolicy::from_str(&policy))
+                    .transpose()
+                    .wrap_err("Could not parse the policy for the replication target")?;
+                Ok(target)
+            });
+            let target = target.with_note(|| format!("Set on dataset: {dataset}"));
+            (dataset, target)
+        })
+        .collect())
+}
+
+pub(crate) fn run(
+    zfs: &dyn ZfsBackend,
+    config: &Config,
+    locks: &Locks,
+    sandbox: bool,
+) -> Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_io()
+        .build()
+        .expect("should always be able to start a tokio runtime");
+
+    rt.block_on(async { replicate_all(zfs, config, locks, sandbox).await })
+}
+
+async fn replicate_all(
+    zfs: &dyn ZfsBackend,
+    config: &Config,
+    locks: &Locks,
+    sandbox: bool,
+) -> Result<()> {
+    let datasets = configured_datasets(zfs, config)?;
+    let mut targets = targets(zfs)?;
+    let mut connections: HashMap<String, Connection> = HashMap::new();
+
+    let mut failed = Vec::new();
+    for dataset in &datasets {
+        let target = match targets.remove(&dataset.path) {
+            None => continue,
+            Some(Ok(target)) => target,
+            Some(Err(e)) => {
+                eprintln!("{e:?}");
+                failed.push(dataset.path.clone());
+                continue;
+            }
+        };
+        let replicated = async {
+            if !connections.contains_key(&target.host) {
+                let connection = Connection::new(&target.host).await?;
+                connections.insert(target.host.clone(), connection);
+            }
+            let remote = &connections[&target.host];
+            replicate(zfs, remote, locks, dataset, &target, sandbox).await
+        };
+        if let Err(e) = replicated
+            .await
+            .wrap_err_with(|| format!("Could not replicate dataset: {}", dataset.path))
+            .with_note(|| format!("Target: {}:{}", target.host, target.dataset))
+        {
+            eprintln!("{e:?}");
+            failed.push(dataset.path.clone());
+        }
+    }
+
+    report(failed, "replicate")
+}
+
+async fn replicate(
+    zfs: &dyn ZfsBackend,
+    remote: &Connection,
+    locks: &Locks,
+    dataset: &ConfiguredDataSet,
+    target: &Target,
+    sandbox: bool,
+) -> Result<()> {
+    let remote_snapshots = remote.snapshots(&target.dataset).await?;
+    let Some(plan) = plan(&dataset.sorted_snapshots, remote_snapshots.as_deref()) else {
+        return Ok(());
+    };
+
+    let (base, snapshot) = match plan {
+        Plan::UpToDate if sandbox => return prune(remote, target, sandbox).await,
+        Plan::UpToDate => {
+            let newest = &dataset.sorted_snapshots[0];
+            let _locked = locks.datasets([dataset.path.as_str()])?;
+            move_anchor(zfs, newest)?;
+            return prune(remote, target, sandbox).await;
+        }
+        Plan::Full { snapshot } => (None, snapshot),
+        Plan::Incremental { base, snapshot } => (Some(base), snapshot),
+    };
+
+    let destination = format!("{}:{}", target.host, target.dataset);
+    if sandbox {
+        match base {
+            Some(base) => println!(
+                "would send {} incremental from {} to {destination}",
+                snapshot.name,
+                base.short_name()
+            ),
+            None => println!("would send {} in full to {destination}", snapshot.name),
+        }
+        return prune(remote, target, sandbox).await;
+    }
+
+    let stream = zfs.send(base.map(|b| b.name.as_str()), &snapshot.name)?;
+    let received = remote
+        .receive(&target.dataset, base.is_some(), stream)
+        .await;
+    if base.is_none() && remote_snapshots.is_some() {
+        received.with_note(|| {
+            "There is no snapshot in common with the target, a full send \
+                needs the target dataset to not exist yet"
+        })?;
+    } else {
+        received?;
+    }
+    println!("replicated {} to {destination}", snapshot.name);
+    let locked = locks.datasets([dataset.path.as_str()])?;
+    move_anchor(zfs, snapshot)?;
+    drop(locked);
+    prune(remote, target, sandbox).await
+}
+
+/// Protects the newest snapshot in common with the target from being pruned
+/// locally and releases all earlier anchors of the dataset, also those left
+/// behind by an interrupted run.
+fn move_anchor(zfs: &dyn ZfsBackend, anchor: &SnapshotMetadata) -> Result<()> {
+    let (dataset, _) = anchor
+        .name
+        .split_once('@')
+        .expect("snapshot names always contain an @");
+    let held = zfs
+        .blockers()?
+        .into_iter()
+        .filter(|(_, blockers)| blockers.holds.iter().any(|tag| tag == REPLICATION_ANCHOR))
+        .map(|(name, _)| name)
+        .filter(|name| name.split_once('@').is_some_and(|(d, _)| d == dataset))
+        .collect_vec();
+
+    if !held.contains(&anchor.name) {
+        zfs.hold(REPLICATION_ANCHOR, &anchor.name)
+            .wrap_err("Could not protect the new replication anchor")?;
+    }
+    for previous in held.iter().filter(|name| **name != anchor.name) {
+        zfs.release(REPLICATION_ANCHOR, previous)
+            .wrap_err("Could not release a previous replication anchor")
+            .with_note(|| format!("Snapshot: {previous}"))?;
+    }
+    Ok(())
+}
+
+/// Applies the target retention policy to the snapshots on the target. The
+/// newest snapshot is always kept as it is the base for the next incremental
+/// send.
+async fn prune(remote: &Connection, target: &Target, sandbox: bool) -> Result<()> {
+    let Some(policy) = &target.retention_policy else {
+        return Ok(());
+    };
+    let Some(snapshots) = remote.snapshots(&target.dataset).await? else {
+        return Ok(());
+    };
+
+    for snapshot in expired_on_target(policy, &snapshots) {
+        if sandbox {
+            println!(
+                "would remove expired snapshot: {}:{}",
+                target.host, snapshot.name
+            );
+        } else {
+            remote.destroy_snapshot(snapshot).await?;
+            println!(
+                "removed expired snapshot: {}:{}",
+                target.host, snapshot.name
+            );
+        }
+    }
+    Ok(())
+}
+
+fn expired_on_target<'a>(
+    policy: &RetentionPolicy,
+    snapshots_newest_first: &'a [SnapshotMetadata],
+) -> Vec<&'a SnapshotMetadata> {
+    let newest = snapshots_newest_first.first();
+    let mut expired: Vec<_> = policy
+        .judge(snapshots_newest_first)
+        .rejected
+        .into_iter()
+        .filter(|snapshot| Some(*snapshot) != newest)
+        .collect();
+    expired.sort();
+    expired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::tests::aged;
+
+    fn on(dataset: &str, mut snapshots: Vec<SnapshotMetadata>) -> Vec<SnapshotMetadata> {
+        for snapshot in &mut snapshots {
+            snapshot.name = format!("{dataset}@{}", snapshot.name);
+        }
+        snapshots
+    }
+
+    fn local() -> Vec<SnapshotMetadata> {
+        on("tank/home", vec![aged!(1 h), aged!(2 h), aged!(3 h)])
+    }
+
+    #[test]
+    fn parse_target() {
+        let target = Target::from_str("backup.lan:pool/backups/home").unwrap();
+        assert_eq!(target.host, "backup.lan");
+        assert_eq!(target.dataset, "pool/backups/home");
+        assert!(Target::from_str("pool/backups/home").is_err());
+        assert!(Target::from_str("backup.lan:").is_err());
+    }
+
+    #[test]
+    fn targets_are_not_inherited() {
+        use crate::zfs::fake::FakeZfs;
+
+        let zfs = FakeZfs::new(chrono::Utc::now());
+        for dataset in ["tank/home", "tank/home/alice", "tank/bad"] {
+            zfs.add_dataset(dataset);
+        }
+        zfs.set_property("tank/home", TARGET_PROPERTY, "backup.lan:pool/home")
+            .unwrap();
+        zfs.set_property("tank/home", TARGET_ZFS_PROPERTY, "1d30")
+            .unwrap();
+        zfs.set_property("tank/bad", TARGET_PROPERTY, "pool/bad")
+            .unwrap();
+
+        let targets = targets(&zfs).unwrap();
+        assert_eq!(targets.len(), 2);
+        let home = targets["tank/home"].as_ref().unwrap();
+        assert_eq!(home.dataset, "pool/home");
+        assert!(home.retention_policy.is_some());
+        assert!(targets["tank/bad"].is_err());
+    }
+
+    #[test]
+    fn nothing_to_send() {
+        assert_eq!(plan(&[], None), None);
+    }
+
+    #[test]
+    fn full_send_without_target() {
+        let local = local();
+        assert_eq!(
+            plan(&local, None),
+            Some(Plan::Full {
+                snapshot: &local[0]
+            })
+        );
+    }
+
+    #[test]
+    fn incremental_from_newest_common() {
+        let local = local();
+        let remote = on("backup/home", vec![aged!(2 h), aged!(3 h)]);
+        assert_eq!(
+            plan(&local, Some(&remote)),
+            Some(Plan::Incremental {
+                base: &local[1],
+                snapshot: &local[0]
+            })
+        );
+    }
+
+    #[test]
+    fn up_to_date() {
+        let local = local();
+        let remote = on("backup/home", vec![aged!(1 h), aged!(3 h)]);
+        assert_eq!(plan(&local, Some(&remote)), Some(Plan::UpToDate));
+    }
+
+    fn anchors(zfs: &dyn ZfsBackend) -> Vec<String> {
+        zfs.blockers()
+            .unwrap()
+            .into_iter()
+            .filter(|(_, blockers)| blockers.holds == [REPLICATION_ANCHOR])
+            .map(|(name, _)| name)
+            .sorted()
+            .collect()
+    }
+
+    #[test]
+    fn anchor_moves_to_newest_replicated() {
+        use crate::zfs::fake::FakeZfs;
+
+        let zfs = FakeZfs::new(chrono::Utc::now());
+        zfs.add_configured_dataset("tank/home", "1h2");
+        let datasets = configured_datasets(&zfs, &Config::default()).unwrap();
+        let old = crate::zfs::snapshot(&zfs, &datasets, &datasets[0])
+            .unwrap()
+            .remove(0);
+        zfs.advance(std::time::Duration::from_secs(60 * 60));
+        let new = crate::zfs::snapshot(&zfs, &datasets, &datasets[0])
+            .unwrap()
+            .remove(0);
+
+        move_anchor(&zfs, &old).unwrap();
+        move_anchor(&zfs, &new).unwrap();
+        assert_eq!(anchors(&zfs), [new.name]);
+    }
+
+    #[test]
+    fn up_to_date_runs_release_earlier_anchors() {
+        use crate::zfs::fake::FakeZfs;
+
+        let zfs = FakeZfs::new(chrono::Utc::now());
+        zfs.add_configured_dataset("tank/home", "1h2");
+        zfs.add_configured_dataset("tank/other", "1h2");
+        let datasets = configured_datasets(&zfs, &Config::default()).unwrap();
+        let snap = |dataset: usize| {
+            zfs.advance(std::time::Duration::from_secs(60 * 60));
+            crate::zfs::snapshot(&zfs, &datasets, &datasets[dataset])
+                .unwrap()
+                .remove(0)
+        };
+        let (old, other, new) = (snap(0), snap(1), snap(0));
+        zfs.hold(REPLICATION_ANCHOR, &old.name).unwrap();
+        zfs.hold(REPLICATION_ANCHOR, &other.name).unwrap();
+
+        // the newest got to the target some other way, every run finds it
+        // up to date
+        move_anchor(&zfs, &new).unwrap();
+        move_anchor(&zfs, &new).unwrap();
+        assert_eq!(anchors(&zfs), [new.name, other.name]);
+    }
+
+    #[test]
+    fn target_policy_prunes() {
+        let policy = RetentionPolicy::from_str("1d2").unwrap();
+        let remote = on("backup/home", vec![aged!(20 h), aged!(2 d), aged!(4 d)]);
+        let expired = expired_on_target(&policy, &remote);
+        assert_eq!(expired, vec![&remote[2]]);
+    }
+
+    #[test]
+    fn newest_on_target_is_never_pruned() {
+        let policy = RetentionPolicy::from_str("1d1").unwrap();
+        let remote = on("backup/home", vec![aged!(1 h), aged!(3 d)]);
+        let expired = expired_on_target(&policy, &remote);
+        assert!(!expired.contains(&&remote[0]));
+    }
+}
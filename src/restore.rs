@@ -0,0 +1,781 @@
+//! Getting data back out of snapshots: listing the snapshots of a dataset
+//! with the rules keeping them, comparing them, cloning one to copy files
+//! out of, finding the versions of a single file and rolling a dataset
+//! back. All of it is also offered by a full-screen browser, see [`tui`].
+//!
+//! A `zfs rollback` can not keep anything newer than the snapshot, not even
+//! a safety snapshot taken just before. So a rollback swaps datasets
+//! instead, like boot environment tools do: the snapshot is cloned, the clone
+//! takes the name of the dataset and is promoted so it owns the snapshots up
+//! to the one rolled back to. The dataset itself is kept under a new name
+//! with the newer snapshots and a safety snapshot of the state it was in,
+//! opted out of management until destroyed by hand. Swapping back undoes
+//! the rollback.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt, fchown};
+use std::path::{self, Path, PathBuf};
+use std::time::SystemTime;
+
+use byte_unit::Byte;
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::Subcommand;
+use color_eyre::eyre::{WrapErr, eyre};
+use color_eyre::{Result, Section};
+use itertools::Itertools;
+
+use crate::ZFS_PROPERTY;
+use crate::config::Config;
+use crate::lock::Locks;
+use crate::policy::Judgement;
+use crate::zfs::{
+    self, ConfiguredDataSet, Protection, SnapshotMetadata, ZfsBackend, configured_datasets,
+};
+
+pub mod tui;
+
+#[derive(Subcommand, Debug)]
+pub enum Action {
+    /// List the snapshots of a dataset with the rules keeping each
+    List { dataset: String },
+    /// Show the files changed since a snapshot
+    Diff {
+        snapshot: String,
+        /// A later snapshot of the same dataset, the live dataset if left out
+        to: Option<String>,
+    },
+    /// Clone a snapshot to a new dataset to copy files out of
+    Clone {
+        snapshot: String,
+        /// Name of the clone, defaults to <dataset>-restore-<snapshot>
+        #[arg(long)]
+        to: Option<String>,
+    },
+    /// Browse the datasets and their snapshots full-screen, pin, destroy,
+    /// clone, compare and roll back from there
+    Browse,
+    /// Return a dataset to the state of a snapshot, keeping the current
+    /// state and the newer snapshots in a dataset next to it
+    Rollback {
+        snapshot: String,
+        /// Roll back, without this what would be set aside is only shown
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+/// A change reported by `zfs diff`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub file_type: FileType,
+    /// Absolute, below the mountpoint of the dataset
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+    /// Renamed to the given path
+    Renamed(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    /// Devices, pipes, sockets and the like
+    Other,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = self.path.display();
+        match &self.kind {
+            ChangeKind::Added => write!(f, "+ {path}"),
+            ChangeKind::Removed => write!(f, "- {path}"),
+            ChangeKind::Modified => write!(f, "M {path}"),
+            ChangeKind::Renamed(to) => write!(f, "R {path} -> {}", to.display()),
+        }
+    }
+}
+
+/// Parses the output of `zfs diff -H -F`
+pub fn parse_diff(rows: Vec<Vec<String>>) -> Result<Vec<Change>> {
+    let mut changes = Vec::with_capacity(rows.len());
+    for row in rows {
+        let kind = match row.as_slice() {
+            [kind, _, _] if kind == "+" => ChangeKind::Added,
+            [kind, _, _] if kind == "-" => ChangeKind::Removed,
+            [kind, _, _] if kind == "M" => ChangeKind::Modified,
+            [kind, _, _, to] if kind == "R" => ChangeKind::Renamed(unescape(to)),
+            _ => {
+                return Err(eyre!("zfs diff parse error"))
+                    .with_note(|| format!("line: {}", row.join("\t")));
+            }
+        };
+        let file_type = match row[1].as_str() {
+            "F" => FileType::File,
+            "/" => FileType::Directory,
+            "@" => FileType::Symlink,
+            _ => FileType::Other,
+        };
+        changes.push(Change {
+            kind,
+            file_type,
+            path: unescape(&row[2]),
+        });
+    }
+    Ok(changes)
+}
+
+/// zfs diff writes spaces, backslashes and unprintable bytes as a backslash
+/// followed by four octal digits
+fn unescape(path: &str) -> PathBuf {
+    let bytes = path.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let digits = &bytes[i + 1..bytes.len().min(i + 5)];
+        if bytes[i] == b'\\'
+            && digits.len() == 4
+            && digits.iter().all(|digit| (b'0'..=b'7').contains(digit))
+            && let Ok(byte) = u8::try_from(
+                digits
+                    .iter()
+                    .fold(0u32, |value, digit| value * 8 + u32::from(digit - b'0')),
+            )
+        {
+            unescaped.push(byte);
+            i += 5;
+        } else {
+            unescaped.push(bytes[i]);
+            i += 1;
+        }
+    }
+    PathBuf::from(OsString::from_vec(unescaped))
+}
+
+fn managed<'a>(datasets: &'a [ConfiguredDataSet], name: &str) -> Result<&'a ConfiguredDataSet> {
+    datasets
+        .iter()
+        .find(|dataset| dataset.path == name)
+        .ok_or_else(|| eyre!("Not a managed dataset: {name}"))
+        .suggestion("Only datasets with a retention policy can be restored")
+}
+
+/// The managed dataset `snapshot` belongs to, errors if the snapshot does
+/// not exist
+fn find<'a>(
+    zfs: &dyn ZfsBackend,
+    datasets: &'a [ConfiguredDataSet],
+    snapshot: &str,
+) -> Result<&'a ConfiguredDataSet> {
+    let (dataset, _) = snapshot
+        .split_once('@')
+        .ok_or_else(|| eyre!("Not a snapshot name: {snapshot}"))
+        .suggestion("Snapshot names have the form dataset@name")?;
+    let dataset = managed(datasets, dataset)?;
+    if !zfs.list_snapshots()?.iter().any(|s| s.name == snapshot) {
+        return Err(eyre!("No such snapshot: {snapshot}")).suggestion(format!(
+            "List the snapshots with: {} restore list {}",
+            env!("CARGO_PKG_NAME"),
+            dataset.path
+        ));
+    }
+    Ok(dataset)
+}
+
+/// Every snapshot of `dataset` newest first, those opted out of management
+/// included
+fn all_snapshots(zfs: &dyn ZfsBackend, dataset: &str) -> Result<Vec<SnapshotMetadata>> {
+    let mut snapshots = zfs
+        .list_snapshots()?
+        .into_iter()
+        .filter(|snapshot| snapshot.dataset() == dataset)
+        .collect_vec();
+    snapshots.sort_by(|a, b| b.cmp(a));
+    Ok(snapshots)
+}
+
+pub fn list(zfs: &dyn ZfsBackend, config: &Config, dataset: &str) -> Result<()> {
+    let datasets = configured_datasets(zfs, config)?;
+    let dataset = managed(&datasets, dataset)?;
+    let protected = zfs::protected(zfs, &datasets)?;
+    let snapshots = all_snapshots(zfs, &dataset.path)?;
+    write_list(&mut io::stdout().lock(), dataset, &snapshots, &protected);
+    Ok(())
+}
+
+fn write_list(
+    f: &mut impl Write,
+    dataset: &ConfiguredDataSet,
+    snapshots: &[SnapshotMetadata],
+    protected: &HashMap<String, Protection>,
+) {
+    let judgement = dataset.retention_policy.judge(&dataset.sorted_snapshots);
+    let name_width = snapshots
+        .iter()
+        .map(|snapshot| snapshot.short_name().chars().count())
+        .max()
+        .unwrap_or(0);
+
+    writeln!(f, "{}", dataset.path).unwrap();
+    for snapshot in snapshots {
+        writeln!(
+            f,
+            "  {:<name_width$}  {}  {:>10}  {}",
+            snapshot.short_name(),
+            snapshot.created.to_rfc3339_opts(SecondsFormat::Secs, true),
+            snapshot.used.get_appropriate_unit(true).to_string(),
+            reason(&judgement, protected, snapshot),
+        )
+        .unwrap();
+    }
+}
+
+/// Why `snapshot` is kept, or that it is not
+fn reason(
+    judgement: &Judgement<'_, '_>,
+    protected: &HashMap<String, Protection>,
+    snapshot: &SnapshotMetadata,
+) -> String {
+    if let Some(rules) = judgement.retained.get(snapshot) {
+        let rules = rules.iter().sorted().map(|rule| format!("{rule:?}")).join(", ");
+        format!("kept by {rules}")
+    } else if let Some(protection @ Protection::Blocked(_)) = protected.get(&snapshot.name) {
+        protection.to_string()
+    } else if let Some(protection) = protected.get(&snapshot.name) {
+        format!("retained: {protection}")
+    } else if judgement.rejected.contains(snapshot) {
+        String::from("expired")
+    } else {
+        String::from("opted out, kept until destroyed by hand")
+    }
+}
+
+pub fn diff(zfs: &dyn ZfsBackend, config: &Config, snapshot: &str, to: Option<&str>) -> Result<()> {
+    let datasets = configured_datasets(zfs, config)?;
+    let dataset = find(zfs, &datasets, snapshot)?;
+    for change in zfs.diff(snapshot, to.unwrap_or(&dataset.path))? {
+        println!("{change}");
+    }
+    Ok(())
+}
+
+pub fn clone(
+    zfs: &dyn ZfsBackend,
+    config: &Config,
+    snapshot: &str,
+    to: Option<String>,
+    sandbox: bool,
+) -> Result<()> {
+    let datasets = configured_datasets(zfs, config)?;
+    find(zfs, &datasets, snapshot)?;
+    let clone = to.unwrap_or_else(|| clone_name(snapshot));
+    if sandbox {
+        println!("would clone {snapshot} to {clone}");
+        return Ok(());
+    }
+
+    let mountpoint = clone_to(zfs, snapshot, &clone)?;
+    println!("cloned {snapshot} to {clone}, mounted at {mountpoint}");
+    println!("{snapshot} can not be destroyed until the clone is, run: zfs destroy {clone}");
+    Ok(())
+}
+
+/// tank/home@daily is cloned to tank/home-restore-daily unless told
+/// otherwise
+fn clone_name(snapshot: &str) -> String {
+    let (dataset, short_name) = snapshot.split_once('@').expect("a snapshot name");
+    format!("{dataset}-restore-{short_name}")
+}
+
+/// Returns where the clone is mounted
+fn clone_to(zfs: &dyn ZfsBackend, snapshot: &str, clone: &str) -> Result<String> {
+    // opted out, a policy inherited from a parent would have the clone
+    // snapshotted
+    zfs.clone_snapshot(snapshot, clone, &[(ZFS_PROPERTY, "-")])?;
+    zfs.get_property(clone, "mountpoint")
+}
+
+/// A version of a file, told apart from the others by its inode, size and
+/// modification time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Version {
+    inode: u64,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl Version {
+    fn of(metadata: &Metadata) -> Result<Self> {
+        Ok(Version {
+            inode: metadata.ino(),
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+}
+
+/// The managed dataset `path` is on, its mountpoint and the path below it.
+/// The path need not exist anymore.
+fn locate<'a>(
+    zfs: &dyn ZfsBackend,
+    datasets: &'a [ConfiguredDataSet],
+    path: &Path,
+) -> Result<(&'a ConfiguredDataSet, PathBuf, PathBuf)> {
+    let absolute = path::absolute(path)?;
+    let (Some(parent), Some(name)) = (absolute.parent(), absolute.file_name()) else {
+        return Err(eyre!("Not a file: {}", path.display()));
+    };
+    // only the directory, the file may have been deleted
+    let absolute = fs::canonicalize(parent)
+        .wrap_err("Could not find the directory of the file")
+        .with_note(|| format!("path: {}", parent.display()))?
+        .join(name);
+
+    let mut found = None;
+    for dataset in datasets {
+        let mountpoint = PathBuf::from(zfs.get_property(&dataset.path, "mountpoint")?);
+        let longer = found
+            .as_ref()
+            .is_none_or(|(_, longest): &(_, PathBuf)| mountpoint.starts_with(longest));
+        if mountpoint.is_absolute() && absolute.starts_with(&mountpoint) && longer {
+            found = Some((dataset, mountpoint));
+        }
+    }
+    let (dataset, mountpoint) = found
+        .ok_or_else(|| eyre!("Not on a managed dataset: {}", absolute.display()))
+        .suggestion("Only datasets with a retention policy have snapshots to look in")?;
+    let relative = absolute
+        .strip_prefix(&mountpoint)
+        .expect("checked above")
+        .to_path_buf();
+    Ok((dataset, mountpoint, relative))
+}
+
+/// The distinct versions of the file at `relative` below `mountpoint`
+/// oldest first, each with the snapshots holding it
+fn versions<'a>(
+    mountpoint: &Path,
+    relative: &Path,
+    snapshots: &'a [SnapshotMetadata],
+) -> Result<Vec<(Version, Vec<&'a SnapshotMetadata>)>> {
+    let mut versions: Vec<(Version, Vec<_>)> = Vec::new();
+    for snapshot in snapshots.iter().rev() {
+        let path = snapshot_dir(mountpoint, snapshot.short_name()).join(relative);
+        let metadata = match fs::symlink_metadata(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            metadata => metadata
+                .wrap_err("Could not read from the snapshot")
+                .with_note(|| format!("path: {}", path.display()))?,
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        let version = Version::of(&metadata)?;
+        match versions.iter_mut().find(|(known, _)| *known == version) {
+            Some((_, holding)) => holding.push(snapshot),
+            None => versions.push((version, vec![snapshot])),
+        }
+    }
+    Ok(versions)
+}
+
+/// Lists the versions of the file at `path` kept in snapshots, or copies
+/// the one in snapshot `restore` next to it
+pub fn history(
+    zfs: &dyn ZfsBackend,
+    config: &Config,
+    path: &Path,
+    restore: Option<&str>,
+    sandbox: bool,
+) -> Result<()> {
+    let datasets = configured_datasets(zfs, config)?;
+    let (dataset, mountpoint, relative) = locate(zfs, &datasets, path)?;
+    let snapshots = all_snapshots(zfs, &dataset.path)?;
+    let live = mountpoint.join(&relative);
+
+    let Some(restore) = restore else {
+        let current = match fs::symlink_metadata(&live) {
+            Ok(metadata) if metadata.is_file() => Some(Version::of(&metadata)?),
+            _ => None,
+        };
+        let versions = versions(&mountpoint, &relative, &snapshots)?;
+        write_history(&mut io::stdout().lock(), &live, &versions, current);
+        return Ok(());
+    };
+
+    let restore = restore
+        .strip_prefix(&format!("{}@", dataset.path))
+        .unwrap_or(restore);
+    let snapshot = snapshots
+        .iter()
+        .find(|snapshot| snapshot.short_name() == restore)
+        .ok_or_else(|| eyre!("No such snapshot of {}: {restore}", dataset.path))
+        .suggestion(format!(
+            "List the versions with: {} history {}",
+            env!("CARGO_PKG_NAME"),
+            live.display()
+        ))?;
+    let from = snapshot_dir(&mountpoint, restore).join(&relative);
+    let metadata = fs::symlink_metadata(&from)
+        .ok()
+        .filter(Metadata::is_file)
+        .ok_or_else(|| eyre!("{} holds no version of {}", snapshot.name, live.display()))?;
+    let to = restored_name(&live, restore);
+    if fs::symlink_metadata(&to).is_ok() {
+        return Err(eyre!("{} already exists", to.display()))
+            .suggestion("Move it out of the way first");
+    }
+    if sandbox {
+        println!("would restore {} from {} to {}", live.display(), snapshot.name, to.display());
+        return Ok(());
+    }
+    copy_out(&from, &to, &metadata)
+        .wrap_err_with(|| format!("Could not restore {}", to.display()))?;
+    println!("restored {} from {} to {}", live.display(), snapshot.name, to.display());
+    Ok(())
+}
+
+/// Copies a version of a file out of a snapshot to `to`, which must not exist
+fn copy_out(from: &Path, to: &Path, metadata: &Metadata) -> Result<()> {
+    // create_new does not follow a symlink put in the way meanwhile
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(to)?;
+    io::copy(&mut File::open(from)?, &mut file)?;
+    file.set_modified(metadata.modified()?)?;
+    fchown(&file, Some(metadata.uid()), Some(metadata.gid()))?;
+    // after the owner, changing it clears the setuid bits
+    file.set_permissions(fs::Permissions::from_mode(metadata.mode()))?;
+    Ok(())
+}
+
+fn write_history(
+    f: &mut impl Write,
+    path: &Path,
+    versions: &[(Version, Vec<&SnapshotMetadata>)],
+    current: Option<Version>,
+) {
+    if versions.is_empty() {
+        writeln!(f, "no snapshot holds {}", path.display()).unwrap();
+        return;
+    }
+    let name_width = versions
+        .iter()
+        .map(|(_, holding)| holding[0].short_name().chars().count())
+        .max()
+        .unwrap_or(0);
+
+    writeln!(f, "{}", path.display()).unwrap();
+    for (version, holding) in versions.iter().rev() {
+        let first = holding[0];
+        let modified = DateTime::<Utc>::from(version.modified);
+        let current = if current == Some(*version) { ", current" } else { "" };
+        writeln!(
+            f,
+            "  {:<name_width$}  {}  {:>10}  modified {}, in {} snapshot{}{current}",
+            first.short_name(),
+            first.created.to_rfc3339_opts(SecondsFormat::Secs, true),
+            Byte::from_bytes(u128::from(version.size))
+                .get_appropriate_unit(true)
+                .to_string(),
+            modified.to_rfc3339_opts(SecondsFormat::Secs, true),
+            holding.len(),
+            if holding.len() == 1 { "" } else { "s" },
+        )
+        .unwrap();
+    }
+    writeln!(
+        f,
+        "restore one next to the original with: {} history {} --restore <snapshot>",
+        env!("CARGO_PKG_NAME"),
+        path.display()
+    )
+    .unwrap();
+}
+
+/// report.odt from snapshot `daily` is restored to report@daily.odt
+fn restored_name(path: &Path, short_name: &str) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push("@");
+    name.push(short_name);
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+fn snapshot_dir(mountpoint: &Path, short_name: &str) -> PathBuf {
+    mountpoint.join(".zfs").join("snapshot").join(short_name)
+}
+
+pub fn rollback(
+    zfs: &dyn ZfsBackend,
+    config: &Config,
+    locks: &Locks,
+    snapshot: &str,
+    yes: bool,
+    sandbox: bool,
+) -> Result<()> {
+    let datasets = configured_datasets(zfs, config)?;
+    let dataset = find(zfs, &datasets, snapshot)?;
+    let changes = zfs.diff(snapshot, &dataset.path)?;
+    let newer = newer(zfs, snapshot)?;
+    if changes.is_empty() && newer.is_empty() {
+        println!("{} did not change since {snapshot}", dataset.path);
+        return Ok(());
+    }
+    if !yes || sandbox {
+        println!("rolling back {} to {snapshot} sets aside:", dataset.path);
+        for change in &changes {
+            println!("  {change}");
+        }
+        if !newer.is_empty() {
+            println!("and the newer snapshots:");
+            for name in &newer {
+                println!("  {name}");
+            }
+        }
+        println!("both are kept in {}-before-rollback-<time>", dataset.path);
+        if !sandbox {
+            println!("run again with --yes to roll back");
+        }
+        return Ok(());
+    }
+
+    let safety = roll_back(zfs, locks, &dataset.path, snapshot)?;
+    println!("rolled {} back to {snapshot}", dataset.path);
+    println!("the state before is kept in the safety snapshot {safety}");
+    Ok(())
+}
+
+/// Full names of the snapshots newer than `snapshot` of its dataset, newest
+/// first
+fn newer(zfs: &dyn ZfsBackend, snapshot: &str) -> Result<Vec<String>> {
+    let (dataset, _) = snapshot.split_once('@').expect("a snapshot name");
+    Ok(all_snapshots(zfs, dataset)?
+        .into_iter()
+        .map(|s| s.name)
+        .take_while(|name| name != snapshot)
+        .collect())
+}
+
+const KEPT_NAMING: &str = "before-rollback-%Y-%m-%dT%H:%M:%SZ";
+
+/// Properties fixed when a dataset is created, a clone gets them from its
+/// origin
+const CREATION_PROPERTIES: [&str; 9] = [
+    "encryption",
+    "keyformat",
+    "keylocation",
+    "pbkdf2iters",
+    "casesensitivity",
+    "normalization",
+    "utf8only",
+    "volsize",
+    "volblocksize",
+];
+
+/// Swaps `dataset` for a promoted clone of `snapshot`, see the module
+/// documentation. Returns the safety snapshot of the state it was in.
+fn roll_back(
+    zfs: &dyn ZfsBackend,
+    locks: &Locks,
+    dataset: &str,
+    snapshot: &str,
+) -> Result<String> {
+    let now = zfs.now();
+    let kept = format!("{dataset}-{}", now.format(KEPT_NAMING));
+    let _locked = locks.datasets([dataset, kept.as_str()])?;
+    let refuse = |why: String| {
+        Err(eyre!("Can not roll back {dataset} and keep its current state"))
+            .with_note(|| why)
+            .suggestion("Clone the snapshot to copy files out of it instead")
+    };
+    if !dataset.contains('/') {
+        return refuse(String::from("the root dataset of a pool can not be renamed"));
+    }
+    let below = format!("{dataset}/");
+    if let Some((child, _)) = zfs
+        .list_datasets(ZFS_PROPERTY)?
+        .into_iter()
+        .find(|(name, _)| name.starts_with(&below))
+    {
+        return refuse(format!("its descendants such as {child} would move along"));
+    }
+    let properties = zfs.local_properties(dataset)?;
+    if properties.iter().any(|(property, _)| property == "mountpoint") {
+        return refuse(String::from(
+            "it has its own mountpoint, which the kept dataset would stay mounted on",
+        ));
+    }
+
+    let safety = format!("{dataset}@{}", now.format(KEPT_NAMING));
+    zfs.snapshot(&safety)
+        .wrap_err("Could not take a safety snapshot")?;
+    zfs.set_property(&safety, ZFS_PROPERTY, "-")?;
+
+    let restored = format!("{dataset}-rollback-{}", now.format("%Y-%m-%dT%H:%M:%SZ"));
+    let settable = properties
+        .iter()
+        .filter(|(property, _)| !CREATION_PROPERTIES.contains(&property.as_str()))
+        .map(|(property, value)| (property.as_str(), value.as_str()))
+        .collect_vec();
+    zfs.clone_snapshot(snapshot, &restored, &settable)
+        .wrap_err_with(|| format!("Could not clone {snapshot}"))?;
+    let unchanged = || format!("{dataset} is unchanged, remove the clone: zfs destroy {restored}");
+    zfs.rename(dataset, &kept)
+        .wrap_err_with(|| format!("Could not rename {dataset} out of the way"))
+        .with_note(unchanged)?;
+    if let Err(e) = zfs.rename(&restored, dataset) {
+        let back = zfs.rename(&kept, dataset);
+        return Err(e)
+            .wrap_err_with(|| format!("Could not rename the clone to {dataset}"))
+            .with_note(|| match back {
+                Ok(()) => unchanged(),
+                Err(_) => format!("{dataset} is now named {kept}, rename it back by hand"),
+            });
+    }
+    zfs.promote(dataset)
+        .wrap_err_with(|| format!("Could not promote the rolled back {dataset}"))
+        .suggestion(format!("Run: zfs promote {dataset}"))?;
+
+    // left alone until destroyed by hand, the clone has the settings now
+    let own = concat!(env!("CARGO_PKG_NAME"), ":");
+    for (property, _) in properties.iter().filter(|(property, _)| property.starts_with(own)) {
+        zfs.inherit_property(&kept, property)?;
+    }
+    zfs.set_property(&kept, ZFS_PROPERTY, "-")?;
+    Ok(format!("{kept}@{}", now.format(KEPT_NAMING)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::zfs::fake::FakeZfs;
+
+    fn start() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z")
+            .unwrap()
+            .to_utc()
+    }
+
+    fn row(columns: &[&str]) -> Vec<String> {
+        columns.iter().map(|column| column.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_diff() {
+        let changes = parse_diff(vec![
+            row(&["M", "/", "/tank/home"]),
+            row(&["+", "F", "/tank/home/my\\0040report.odt"]),
+            row(&["R", "F", "/tank/home/a", "/tank/home/b"]),
+        ])
+        .unwrap();
+        assert_eq!(changes[0].file_type, FileType::Directory);
+        assert_eq!(changes[1].path, Path::new("/tank/home/my report.odt"));
+        assert_eq!(changes[1].kind, ChangeKind::Added);
+        assert_eq!(changes[2].to_string(), "R /tank/home/a -> /tank/home/b");
+
+        assert!(parse_diff(vec![row(&["?", "F", "/tank/home/a"])]).is_err());
+    }
+
+    #[test]
+    fn rollback_keeps_the_state_it_sets_aside() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h3");
+        zfs.snapshot("tank/home@a").unwrap();
+        zfs.advance(Duration::from_secs(60));
+        zfs.snapshot("tank/home@b").unwrap();
+        zfs.advance(Duration::from_secs(60));
+        zfs.set_changes(
+            "tank/home@a",
+            vec![Change {
+                kind: ChangeKind::Modified,
+                file_type: FileType::File,
+                path: PathBuf::from("/tank/home/report.odt"),
+            }],
+        );
+
+        let config = Config::default();
+        let rollback = |yes| rollback(&zfs, &config, &Locks::none(), "tank/home@a", yes, false);
+        rollback(false).unwrap();
+        assert_eq!(zfs.snapshots_of("tank/home"), ["tank/home@a", "tank/home@b"]);
+
+        rollback(true).unwrap();
+        assert_eq!(zfs.snapshots_of("tank/home"), ["tank/home@a"]);
+        assert!(zfs.diff("tank/home@a", "tank/home").unwrap().is_empty());
+        assert_eq!(zfs.get_property("tank/home", ZFS_PROPERTY).unwrap(), "1h3");
+        let kept = "tank/home-before-rollback-2025-01-01T00:02:00Z";
+        let safety = format!("{kept}@before-rollback-2025-01-01T00:02:00Z");
+        assert_eq!(zfs.snapshots_of(kept), [format!("{kept}@b"), safety.clone()]);
+        assert_eq!(zfs.get_property(&safety, ZFS_PROPERTY).unwrap(), "-");
+        assert_eq!(zfs.get_property(kept, ZFS_PROPERTY).unwrap(), "-");
+        let datasets = configured_datasets(&zfs, &config).unwrap();
+        assert_eq!(datasets.iter().map(|d| d.path.as_str()).collect_vec(), ["tank/home"]);
+
+        // a descendant would be renamed along with the kept dataset
+        zfs.add_dataset("tank/home/child");
+        zfs.advance(Duration::from_secs(60));
+        zfs.snapshot("tank/home@c").unwrap();
+        assert!(rollback(true).is_err());
+        assert_eq!(zfs.snapshots_of("tank/home"), ["tank/home@a", "tank/home@c"]);
+    }
+
+    #[test]
+    fn history_finds_each_version_once() {
+        let root = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(root.path()).unwrap();
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank/home", "1h3");
+        zfs.set_property("tank/home", "mountpoint", root.to_str().unwrap())
+            .unwrap();
+        for name in ["a", "b", "c", "d"] {
+            zfs.snapshot(&format!("tank/home@{name}")).unwrap();
+            zfs.advance(Duration::from_secs(60 * 60));
+            fs::create_dir_all(root.join(".zfs/snapshot").join(name)).unwrap();
+        }
+        // unchanged files keep their inode from snapshot to snapshot
+        let snapshot = |name: &str| root.join(".zfs/snapshot").join(name).join("report.odt");
+        fs::write(snapshot("a"), "old").unwrap();
+        fs::hard_link(snapshot("a"), snapshot("b")).unwrap();
+        fs::write(snapshot("d"), "new").unwrap();
+        fs::hard_link(snapshot("d"), root.join("report.odt")).unwrap();
+
+        let datasets = configured_datasets(&zfs, &Config::default()).unwrap();
+        let (dataset, mountpoint, relative) =
+            locate(&zfs, &datasets, &root.join("report.odt")).unwrap();
+        assert_eq!(dataset.path, "tank/home");
+        assert_eq!(relative, Path::new("report.odt"));
+        let snapshots = all_snapshots(&zfs, "tank/home").unwrap();
+        let versions = versions(&mountpoint, &relative, &snapshots).unwrap();
+        let held = versions
+            .iter()
+            .map(|(_, holding)| holding.iter().map(|s| s.short_name()).collect_vec())
+            .collect_vec();
+        assert_eq!(held, [vec!["a", "b"], vec!["d"]]);
+
+        let live = root.join("report.odt");
+        history(&zfs, &Config::default(), &live, Some("tank/home@b"), false).unwrap();
+        assert_eq!(fs::read_to_string(root.join("report@b.odt")).unwrap(), "old");
+        assert!(history(&zfs, &Config::default(), &live, Some("b"), false).is_err());
+        assert!(history(&zfs, &Config::default(), &live, Some("c"), false).is_err());
+        assert_eq!(restored_name(Path::new("/a/.bashrc"), "b"), Path::new("/a/.bashrc@b"));
+    }
+}
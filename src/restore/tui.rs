@@ -0,0 +1,569 @@
+//! A full-screen browser of the managed datasets and their snapshots. It
+//! shows why each snapshot is kept and offers the actions of the restore
+//! commands, asking before doing anything that is hard to undo.
+//!
+//! Pinning a snapshot opts it out of management, like setting
+//! `ZFS_PROPERTY` to `-` on it by hand, so it is kept until unpinned.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::SecondsFormat;
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+use itertools::Itertools;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Flex, Layout, Rect};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Clear, List, ListState, Paragraph, Row, Table, TableState, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+
+use super::{all_snapshots, clone_name, clone_to, newer, reason, roll_back};
+use crate::ZFS_PROPERTY;
+use crate::config::Config;
+use crate::lock::Locks;
+use crate::zfs::{
+    self, ConfiguredDataSet, Protection, SnapshotMetadata, ZfsBackend, configured_datasets,
+};
+
+const HELP: &str = "↑↓ move  ←→ switch pane  p pin  u unpin  x destroy  c clone  d diff  \
+                    r roll back  q quit";
+
+pub fn run(zfs: &dyn ZfsBackend, config: &Config, locks: &Locks, sandbox: bool) -> Result<()> {
+    let mut browser = Browser::new(zfs, config, locks, sandbox)?;
+    let mut terminal = ratatui::init();
+    let result = browser.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+struct Entry {
+    snapshot: SnapshotMetadata,
+    reason: String,
+    pinned: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    Datasets,
+    Snapshots,
+}
+
+enum Popup {
+    /// Does `action` if answered with y
+    Confirm { text: Vec<String>, action: Pending },
+    /// Output to read, closed by any key but the arrows
+    Text {
+        title: String,
+        text: Vec<String>,
+        scroll: u16,
+    },
+}
+
+/// Actions waiting for confirmation, each on a snapshot given by full name
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Pending {
+    Unpin(String),
+    Destroy(String),
+    Clone(String),
+    Rollback(String),
+}
+
+impl fmt::Display for Pending {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pending::Unpin(name) => write!(f, "unpin {name}"),
+            Pending::Destroy(name) => write!(f, "destroy {name}"),
+            Pending::Clone(name) => write!(f, "clone {name} to {}", clone_name(name)),
+            Pending::Rollback(name) => write!(f, "roll back to {name}"),
+        }
+    }
+}
+
+struct Browser<'a> {
+    zfs: &'a dyn ZfsBackend,
+    config: &'a Config,
+    locks: &'a Locks,
+    sandbox: bool,
+    /// Sorted by path so children follow their parent
+    datasets: Vec<ConfiguredDataSet>,
+    /// Of all datasets, read along with them
+    protected: HashMap<String, Protection>,
+    /// The snapshots of the selected dataset newest first
+    entries: Vec<Entry>,
+    dataset: ListState,
+    snapshot: TableState,
+    focus: Focus,
+    popup: Option<Popup>,
+    /// Outcome of the last action, and whether it failed
+    message: Option<(String, bool)>,
+    /// Commands such as hooks may have written over the screen
+    redraw: bool,
+    quit: bool,
+}
+
+impl<'a> Browser<'a> {
+    fn new(
+        zfs: &'a dyn ZfsBackend,
+        config: &'a Config,
+        locks: &'a Locks,
+        sandbox: bool,
+    ) -> Result<Self> {
+        let mut browser = Browser {
+            zfs,
+            config,
+            locks,
+            sandbox,
+            datasets: Vec::new(),
+            protected: HashMap::new(),
+            entries: Vec::new(),
+            dataset: ListState::default().with_selected(Some(0)),
+            snapshot: TableState::default().with_selected(Some(0)),
+            focus: Focus::Datasets,
+            popup: None,
+            message: None,
+            redraw: false,
+            quit: false,
+        };
+        browser.reload()?;
+        Ok(browser)
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        while !self.quit {
+            if self.redraw {
+                terminal.clear()?;
+                self.redraw = false;
+            }
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+            {
+                self.key(key.code);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the datasets and the snapshots of the selected one again
+    fn reload(&mut self) -> Result<()> {
+        self.datasets = configured_datasets(self.zfs, self.config)?;
+        self.datasets.sort_by(|a, b| a.path.cmp(&b.path));
+        if self.datasets.is_empty() {
+            self.dataset.select(None);
+        } else if self.dataset.selected().is_none_or(|i| i >= self.datasets.len()) {
+            self.dataset.select(Some(self.datasets.len() - 1));
+        }
+        self.protected = zfs::protected(self.zfs, &self.datasets)?;
+        self.load_entries()
+    }
+
+    /// Lists the snapshots of the selected dataset, judged by the datasets
+    /// as last read
+    fn load_entries(&mut self) -> Result<()> {
+        self.entries.clear();
+        if let Some(dataset) = self.selected_dataset() {
+            let judgement = dataset.retention_policy.judge(&dataset.sorted_snapshots);
+            let entries = all_snapshots(self.zfs, &dataset.path)?
+                .into_iter()
+                .map(|snapshot| {
+                    let pinned = !dataset.sorted_snapshots.contains(&snapshot);
+                    let reason = if pinned {
+                        String::from("pinned")
+                    } else {
+                        reason(&judgement, &self.protected, &snapshot)
+                    };
+                    Entry {
+                        snapshot,
+                        reason,
+                        pinned,
+                    }
+                })
+                .collect_vec();
+            self.entries = entries;
+        }
+        if self.entries.is_empty() {
+            self.snapshot.select(None);
+        } else if self.snapshot.selected().is_none_or(|i| i >= self.entries.len()) {
+            self.snapshot.select(Some(self.entries.len() - 1));
+        }
+        Ok(())
+    }
+
+    fn selected_dataset(&self) -> Option<&ConfiguredDataSet> {
+        self.dataset.selected().and_then(|i| self.datasets.get(i))
+    }
+
+    fn selected_snapshot(&self) -> Option<&Entry> {
+        self.snapshot.selected().and_then(|i| self.entries.get(i))
+    }
+
+    fn key(&mut self, key: KeyCode) {
+        if let Some(popup) = self.popup.take() {
+            self.popup_key(popup, key);
+            return;
+        }
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Up | KeyCode::Char('k') => self.step(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.step(1),
+            KeyCode::Left | KeyCode::Char('h') => self.focus = Focus::Datasets,
+            KeyCode::Right | KeyCode::Char('l') | KeyCode::Enter => {
+                self.focus = Focus::Snapshots;
+            }
+            KeyCode::Tab if self.focus == Focus::Datasets => self.focus = Focus::Snapshots,
+            KeyCode::Tab => self.focus = Focus::Datasets,
+            KeyCode::Char(action @ ('p' | 'u' | 'x' | 'c' | 'd' | 'r')) => {
+                let Some(entry) = self.selected_snapshot() else {
+                    self.message = Some((String::from("no snapshot selected"), true));
+                    return;
+                };
+                let name = entry.snapshot.name.clone();
+                let result = match action {
+                    'p' => self.pin(&name),
+                    'u' => self.ask(Pending::Unpin(name)),
+                    'x' => self.ask(Pending::Destroy(name)),
+                    'c' => self.ask(Pending::Clone(name)),
+                    'd' => self.diff(&name),
+                    'r' => self.ask(Pending::Rollback(name)),
+                    _ => unreachable!("matched above"),
+                };
+                self.outcome(result);
+            }
+            _ => (),
+        }
+    }
+
+    fn popup_key(&mut self, popup: Popup, key: KeyCode) {
+        match (popup, key) {
+            (Popup::Confirm { action, .. }, KeyCode::Char('y')) => {
+                let result = self.perform(&action);
+                self.redraw = true;
+                self.outcome(result);
+            }
+            (Popup::Confirm { .. }, _) => {
+                self.message = Some((String::from("cancelled"), false));
+            }
+            (Popup::Text { title, text, scroll }, KeyCode::Up | KeyCode::Char('k')) => {
+                let scroll = scroll.saturating_sub(1);
+                self.popup = Some(Popup::Text { title, text, scroll });
+            }
+            (Popup::Text { title, text, scroll }, KeyCode::Down | KeyCode::Char('j')) => {
+                let scroll = scroll.saturating_add(1);
+                self.popup = Some(Popup::Text { title, text, scroll });
+            }
+            (Popup::Text { .. }, _) => (),
+        }
+    }
+
+    /// Moves the selection in the focused pane
+    fn step(&mut self, by: isize) {
+        let next = |selected: Option<usize>, len: usize| {
+            selected.map(|i| i.saturating_add_signed(by).min(len.saturating_sub(1)))
+        };
+        match self.focus {
+            Focus::Datasets => {
+                self.dataset.select(next(self.dataset.selected(), self.datasets.len()));
+                self.snapshot.select(Some(0));
+                if let Err(e) = self.load_entries() {
+                    self.message = Some((format!("{e:#}"), true));
+                }
+            }
+            Focus::Snapshots => {
+                self.snapshot.select(next(self.snapshot.selected(), self.entries.len()));
+            }
+        }
+    }
+
+    /// Shows the result of an action and what the datasets look like after
+    fn outcome(&mut self, result: Result<Option<String>>) {
+        let reloaded = result.and_then(|message| self.reload().map(|()| message));
+        match reloaded {
+            Ok(Some(message)) => self.message = Some((message, false)),
+            Ok(None) => (),
+            Err(e) => self.message = Some((format!("{e:#}"), true)),
+        }
+    }
+
+    /// Finds the dataset and metadata of a snapshot in the loaded state
+    fn lookup(&self, name: &str) -> Result<(&ConfiguredDataSet, &SnapshotMetadata)> {
+        let dataset = self.selected_dataset().ok_or_else(|| eyre!("No dataset selected"))?;
+        let entry = self
+            .entries
+            .iter()
+            .find(|entry| entry.snapshot.name == name)
+            .ok_or_else(|| eyre!("Snapshot is gone: {name}"))?;
+        Ok((dataset, &entry.snapshot))
+    }
+
+    fn pin(&self, name: &str) -> Result<Option<String>> {
+        let (dataset, _) = self.lookup(name)?;
+        if self.selected_snapshot().is_some_and(|entry| entry.pinned) {
+            return Ok(Some(format!("{name} is already pinned")));
+        }
+        if self.sandbox {
+            return Ok(Some(format!("would pin {name}")));
+        }
+        let _locked = self.locks.datasets([dataset.path.as_str()])?;
+        self.zfs.set_property(name, ZFS_PROPERTY, "-")?;
+        Ok(Some(format!("pinned {name}, it is kept until unpinned")))
+    }
+
+    fn diff(&mut self, name: &str) -> Result<Option<String>> {
+        let (dataset, _) = self.lookup(name)?;
+        let changes = self.zfs.diff(name, &dataset.path)?;
+        let text = if changes.is_empty() {
+            vec![String::from("no changes")]
+        } else {
+            changes.iter().map(ToString::to_string).collect()
+        };
+        self.popup = Some(Popup::Text {
+            title: format!("Changed since {name}"),
+            text,
+            scroll: 0,
+        });
+        Ok(None)
+    }
+
+    /// Opens the confirmation for `action`
+    fn ask(&mut self, action: Pending) -> Result<Option<String>> {
+        let mut text = match &action {
+            Pending::Unpin(name) => {
+                if !self.selected_snapshot().is_some_and(|entry| entry.pinned) {
+                    return Ok(Some(format!("{name} is not pinned")));
+                }
+                vec![
+                    format!("Unpin {name}?"),
+                    String::from("The retention policy applies to it again, it is destroyed"),
+                    String::from("on the next pass if no rule keeps it."),
+                ]
+            }
+            Pending::Destroy(name) => vec![
+                format!("Destroy {name}?"),
+                String::from("This can not be undone."),
+            ],
+            Pending::Clone(name) => vec![
+                format!("Clone {name} to {}?", clone_name(name)),
+                String::from("The snapshot can not be destroyed while the clone exists."),
+            ],
+            Pending::Rollback(name) => {
+                let (dataset, _) = self.lookup(name)?;
+                let changes = self.zfs.diff(name, &dataset.path)?;
+                let newer = newer(self.zfs, name)?;
+                if changes.is_empty() && newer.is_empty() {
+                    return Ok(Some(format!("{} did not change since {name}", dataset.path)));
+                }
+                let mut text = vec![
+                    format!("Roll {} back to {name}?", dataset.path),
+                    String::from("This sets aside:"),
+                ];
+                text.extend(changes.iter().map(|change| format!("  {change}")));
+                if !newer.is_empty() {
+                    text.push(String::from("and the newer snapshots:"));
+                    text.extend(newer.iter().map(|name| format!("  {name}")));
+                }
+                text.push(format!("both are kept in {}-before-rollback-<time>", dataset.path));
+                text
+            }
+        };
+        text.push(String::new());
+        text.push(String::from("y to go ahead, any other key to cancel"));
+        self.popup = Some(Popup::Confirm { text, action });
+        Ok(None)
+    }
+
+    fn perform(&self, action: &Pending) -> Result<Option<String>> {
+        if self.sandbox {
+            return Ok(Some(format!("would {action}")));
+        }
+        let message = match action {
+            Pending::Unpin(name) => {
+                let (dataset, _) = self.lookup(name)?;
+                let _locked = self.locks.datasets([dataset.path.as_str()])?;
+                self.zfs.inherit_property(name, ZFS_PROPERTY)?;
+                format!("unpinned {name}")
+            }
+            Pending::Destroy(name) => {
+                let (dataset, snapshot) = self.lookup(name)?;
+                let _locked = self.locks.datasets([dataset.path.as_str()])?;
+                zfs::destroy_snapshot(self.zfs, &self.datasets, dataset, snapshot)?;
+                format!("destroyed {name}")
+            }
+            Pending::Clone(name) => {
+                let clone = clone_name(name);
+                let mountpoint = clone_to(self.zfs, name, &clone)?;
+                format!("cloned {name} to {clone}, mounted at {mountpoint}")
+            }
+            Pending::Rollback(name) => {
+                let (dataset, _) = self.lookup(name)?;
+                let safety = roll_back(self.zfs, self.locks, &dataset.path, name)?;
+                format!("rolled back to {name}, the state before is kept in {safety}")
+            }
+        };
+        Ok(Some(message))
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Fill(1), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(30), Constraint::Fill(1)]).areas(main);
+        let highlight = |pane| {
+            if self.focus == pane {
+                Style::new().reversed()
+            } else {
+                Style::new().bold()
+            }
+        };
+
+        let datasets = List::new(tree(&self.datasets))
+            .block(Block::bordered().title("Datasets"))
+            .highlight_style(highlight(Focus::Datasets));
+        frame.render_stateful_widget(datasets, left, &mut self.dataset);
+
+        let name_width = self
+            .entries
+            .iter()
+            .map(|entry| entry.snapshot.short_name().chars().count())
+            .max()
+            .unwrap_or(0);
+        let rows = self.entries.iter().map(|entry| {
+            let snapshot = &entry.snapshot;
+            let row = Row::new([
+                snapshot.short_name().to_string(),
+                snapshot.created.to_rfc3339_opts(SecondsFormat::Secs, true),
+                snapshot.used.get_appropriate_unit(true).to_string(),
+                entry.reason.clone(),
+            ]);
+            if entry.pinned { row.italic() } else { row }
+        });
+        let widths = [
+            Constraint::Length(u16::try_from(name_width).unwrap_or(u16::MAX).max(8)),
+            Constraint::Length(20),
+            Constraint::Length(10),
+            Constraint::Fill(1),
+        ];
+        let title = self
+            .selected_dataset()
+            .map_or(String::from("Snapshots"), |d| format!("Snapshots of {}", d.path));
+        let snapshots = Table::new(rows, widths)
+            .header(Row::new(["snapshot", "created", "used", "kept because"]).bold())
+            .block(Block::bordered().title(title))
+            .row_highlight_style(highlight(Focus::Snapshots));
+        frame.render_stateful_widget(snapshots, right, &mut self.snapshot);
+
+        let status_line = match &self.message {
+            Some((message, true)) => Line::from(message.as_str()).red(),
+            Some((message, false)) => Line::from(format!("{message}  |  {HELP}")),
+            None => Line::from(HELP),
+        };
+        frame.render_widget(status_line, status);
+
+        let (title, text, scroll) = match &self.popup {
+            None => return,
+            Some(Popup::Confirm { text, .. }) => ("Confirm", text, 0),
+            Some(Popup::Text {
+                title,
+                text,
+                scroll,
+            }) => (title.as_str(), text, *scroll),
+        };
+        let area = centered(frame.area());
+        let lines = text.iter().map(|line| Line::from(line.as_str())).collect_vec();
+        let popup = Paragraph::new(lines)
+            .wrap(Wrap { trim: false })
+            .scroll((scroll, 0))
+            .block(Block::bordered().title(title));
+        frame.render_widget(Clear, area);
+        frame.render_widget(popup, area);
+    }
+}
+
+/// Labels indented below the closest managed parent, `datasets` sorted by
+/// path
+fn tree(datasets: &[ConfiguredDataSet]) -> Vec<String> {
+    let mut depths: HashMap<&str, usize> = HashMap::new();
+    let mut labels = Vec::with_capacity(datasets.len());
+    for dataset in datasets {
+        let parent = dataset
+            .path
+            .match_indices('/')
+            .rev()
+            .map(|(i, _)| &dataset.path[..i])
+            .find(|parent| depths.contains_key(parent));
+        let (depth, label) = match parent {
+            Some(parent) => (depths[parent] + 1, &dataset.path[parent.len() + 1..]),
+            None => (0, dataset.path.as_str()),
+        };
+        depths.insert(&dataset.path, depth);
+        labels.push(format!("{}{label}", "  ".repeat(depth)));
+    }
+    labels
+}
+
+fn centered(area: Rect) -> Rect {
+    let [area] = Layout::vertical([Constraint::Percentage(80)])
+        .flex(Flex::Center)
+        .areas(area);
+    let [area] = Layout::horizontal([Constraint::Percentage(80)])
+        .flex(Flex::Center)
+        .areas(area);
+    area
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use ratatui::Terminal;
+    use ratatui::backend::TestBackend;
+
+    use super::*;
+    use crate::zfs::fake::FakeZfs;
+
+    fn start() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z")
+            .unwrap()
+            .to_utc()
+    }
+
+    #[test]
+    fn pin_and_destroy() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_configured_dataset("tank", "1h3");
+        zfs.add_configured_dataset("tank/home", "1h3");
+        zfs.add_configured_dataset("tank/home/alice", "1h3");
+        zfs.snapshot("tank/home@a").unwrap();
+        let config = Config::default();
+        let locks = Locks::none();
+        let mut browser = Browser::new(&zfs, &config, &locks, false).unwrap();
+        assert_eq!(tree(&browser.datasets), ["tank", "  home", "    alice"]);
+
+        browser.key(KeyCode::Down);
+        assert_eq!(browser.selected_dataset().unwrap().path, "tank/home");
+        browser.key(KeyCode::Right);
+        browser.key(KeyCode::Char('p'));
+        assert_eq!(zfs.get_property("tank/home@a", ZFS_PROPERTY).unwrap(), "-");
+        assert!(browser.selected_snapshot().unwrap().pinned);
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 10)).unwrap();
+        terminal.draw(|frame| browser.draw(frame)).unwrap();
+        let screen = format!("{:?}", terminal.backend().buffer());
+        assert!(screen.contains("Snapshots of tank/home"));
+        assert!(screen.contains("pinned"));
+
+        browser.key(KeyCode::Char('u'));
+        browser.key(KeyCode::Char('n'));
+        assert!(browser.selected_snapshot().unwrap().pinned);
+        browser.key(KeyCode::Char('u'));
+        browser.key(KeyCode::Char('y'));
+        assert!(!browser.selected_snapshot().unwrap().pinned);
+
+        browser.key(KeyCode::Char('x'));
+        assert!(browser.popup.is_some());
+        browser.key(KeyCode::Char('y'));
+        assert!(zfs.snapshots_of("tank/home").is_empty());
+        assert!(browser.selected_snapshot().is_none());
+        assert_eq!(browser.message, Some((String::from("destroyed tank/home@a"), false)));
+    }
+}
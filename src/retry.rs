@@ -0,0 +1,145 @@
+//! Failed snapshots and destroys are retried with exponential backoff so
+//! one busy dataset or held snapshot neither stops the daemon nor gets
+//! hammered every pass.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::DataSet;
+use crate::zfs::ConfiguredDataSet;
+
+const FIRST_RETRY: Duration = Duration::from_secs(60);
+const MAX_RETRY: Duration = Duration::from_secs(60 * 60);
+
+/// How long to wait after `attempts` failures in a row
+pub fn backoff(attempts: u32) -> Duration {
+    FIRST_RETRY
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_RETRY)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "action", content = "name")]
+pub enum Task {
+    /// Snapshotting a dataset, or the group it leads
+    Snapshot(DataSet),
+    /// Destroying a snapshot, by full name
+    Destroy(String),
+}
+
+impl Task {
+    pub fn dataset(&self) -> &str {
+        match self {
+            Task::Snapshot(dataset) => dataset,
+            Task::Destroy(snapshot) => snapshot.split_once('@').map_or(snapshot, |(d, _)| d),
+        }
+    }
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Task::Snapshot(dataset) => write!(f, "snapshot {dataset}"),
+            Task::Destroy(snapshot) => write!(f, "destroy {snapshot}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Failure {
+    pub task: Task,
+    pub error: String,
+    /// Failed attempts in a row
+    pub attempts: u32,
+    pub retry_at: DateTime<Utc>,
+}
+
+/// Tasks that failed and when to try them again, kept by the daemon for as
+/// long as it runs
+#[derive(Debug, Default)]
+pub struct Retries(BTreeMap<Task, Failure>);
+
+impl Retries {
+    pub fn failed(&mut self, task: Task, error: &color_eyre::Report, now: DateTime<Utc>) {
+        let attempts = self.0.get(&task).map_or(0, |failure| failure.attempts) + 1;
+        let retry_at = now + backoff(attempts);
+        let failure = Failure {
+            task: task.clone(),
+            error: format!("{error:#}"),
+            attempts,
+            retry_at,
+        };
+        self.0.insert(task, failure);
+    }
+
+    pub fn succeeded(&mut self, task: &Task) {
+        self.0.remove(task);
+    }
+
+    /// Zero if the task may be tried now
+    pub fn until_retry(&self, task: &Task, now: DateTime<Utc>) -> Duration {
+        self.0
+            .get(task)
+            .and_then(|failure| (failure.retry_at - now).to_std().ok())
+            .unwrap_or_default()
+    }
+
+    pub fn waiting(&self, task: &Task, now: DateTime<Utc>) -> bool {
+        !self.until_retry(task, now).is_zero()
+    }
+
+    /// Forgets the datasets that are no longer managed and the snapshots
+    /// that are gone, they will never succeed.
+    pub fn prune(&mut self, datasets: &[ConfiguredDataSet]) {
+        self.0.retain(|task, _| match task {
+            Task::Snapshot(path) => datasets.iter().any(|d| &d.path == path),
+            Task::Destroy(name) => datasets
+                .iter()
+                .flat_map(|d| d.sorted_snapshots.iter())
+                .any(|snapshot| &snapshot.name == name),
+        });
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &Failure> {
+        self.0.values()
+    }
+
+    /// Failures of the tasks on `dataset`
+    pub fn of<'a>(&'a self, dataset: &'a str) -> impl Iterator<Item = &'a Failure> {
+        self.failures()
+            .filter(move |failure| failure.task.dataset() == dataset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::eyre;
+
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially() {
+        assert_eq!(backoff(1), Duration::from_secs(60));
+        assert_eq!(backoff(3), Duration::from_secs(4 * 60));
+        assert_eq!(backoff(7), MAX_RETRY);
+        assert_eq!(backoff(100), MAX_RETRY);
+    }
+
+    #[test]
+    fn success_resets() {
+        let now = Utc::now();
+        let task = Task::Destroy(String::from("tank/home@a"));
+        let mut retries = Retries::default();
+        retries.failed(task.clone(), &eyre!("dataset is busy"), now);
+        retries.failed(task.clone(), &eyre!("dataset is busy"), now);
+        assert_eq!(retries.until_retry(&task, now), Duration::from_secs(120));
+        assert_eq!(retries.of("tank/home").count(), 1);
+
+        retries.succeeded(&task);
+        assert!(!retries.waiting(&task, now));
+    }
+}
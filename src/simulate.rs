@@ -0,0 +1,321 @@
+//! Previews what a retention policy does over time. The daemon is replayed
+//! with a virtual clock: a snapshot is made whenever `next_snapshot_in`
+//! says one is due and the policy judges the snapshots after each. While
+//! offline nothing happens, on coming back an overdue snapshot is made at
+//! once just like after a real reboot.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::Duration;
+
+use byte_unit::Byte;
+use chrono::{DateTime, SecondsFormat, Utc};
+use color_eyre::eyre::{WrapErr, eyre};
+use color_eyre::{Result, Section};
+use humantime::format_duration;
+use itertools::Itertools;
+
+use crate::config::Config;
+use crate::policy::RetentionPolicy;
+use crate::zfs::{SnapshotMetadata, ZfsBackend, configured_datasets};
+
+const SECOND: Duration = Duration::from_secs(1);
+const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+/// Times the population is shown at, evenly spread over the span
+const SAMPLES: u32 = 20;
+
+/// A time the daemon does not run, counted from the start of the
+/// simulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Downtime {
+    after: Duration,
+    length: Duration,
+}
+
+impl FromStr for Downtime {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // clap only shows the message, not notes
+        let invalid = || format!("Invalid downtime '{s}', expected <after>+<length> like 10d+3d");
+        let (after, length) = s.split_once('+').ok_or_else(|| eyre!(invalid()))?;
+        let parse = |duration: &str| humantime::parse_duration(duration).wrap_err_with(invalid);
+        Ok(Downtime {
+            after: parse(after)?,
+            length: parse(length)?,
+        })
+    }
+}
+
+impl fmt::Display for Downtime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "offline for {} after {}",
+            approximate(self.length),
+            approximate(self.after)
+        )
+    }
+}
+
+/// The snapshots at one moment of the simulation
+struct Sample {
+    after: Duration,
+    count: usize,
+    oldest: Option<Duration>,
+}
+
+struct Simulation {
+    samples: Vec<Sample>,
+    /// Ages of the snapshots left at the end, youngest first
+    kept: Vec<Duration>,
+    made: usize,
+    /// The largest gap between neighbouring snapshots ever seen, per age
+    /// range the older one was in. Ranges end where a rule stops keeping
+    /// snapshots, the last holds everything older.
+    worst_gaps: Vec<(String, Option<Duration>)>,
+}
+
+pub fn run(
+    zfs: &dyn ZfsBackend,
+    config: &Config,
+    policy: &str,
+    dataset: Option<&str>,
+    span: Duration,
+    downtime: &[Downtime],
+) -> Result<()> {
+    let policy = RetentionPolicy::from_str(policy)?;
+    let snapshots = match dataset {
+        None => Vec::new(),
+        Some(name) => configured_datasets(zfs, config)?
+            .into_iter()
+            .find(|d| d.path == name)
+            .ok_or_else(|| eyre!("Not a managed dataset: {name}"))
+            .suggestion("Only the snapshots of datasets with a retention policy can be used")?
+            .sorted_snapshots
+            .into_vec(),
+    };
+    let start = zfs.now();
+    let simulation = simulate(&policy, snapshots, start, span, downtime);
+
+    let mut stdout = io::stdout().lock();
+    writeln!(
+        stdout,
+        "{policy:?} over {} from {}, starting with {}",
+        approximate(span),
+        start.to_rfc3339_opts(SecondsFormat::Secs, true),
+        dataset.map_or(String::from("no snapshots"), |d| format!("the snapshots of {d}")),
+    )?;
+    for downtime in downtime {
+        writeln!(stdout, "  {downtime}")?;
+    }
+    write_simulation(&mut stdout, &simulation);
+    Ok(())
+}
+
+fn simulate(
+    policy: &RetentionPolicy,
+    // newest first
+    mut snapshots: Vec<SnapshotMetadata>,
+    start: DateTime<Utc>,
+    span: Duration,
+    downtime: &[Downtime],
+) -> Simulation {
+    let end = start + span;
+    let offline_until = |time: DateTime<Utc>| {
+        downtime
+            .iter()
+            .map(|d| (start + d.after, start + d.after + d.length))
+            .find(|(from, until)| (*from..*until).contains(&time))
+            .map(|(_, until)| until)
+    };
+    let mut ranges = policy
+        .0
+        .iter()
+        .map(|rule| {
+            let covers = rule.snapshot_period.approximate() * rule.retained_copies as u32;
+            (covers, format!("up to {} ({rule:?})", approximate(covers)))
+        })
+        .collect_vec();
+    ranges.sort_by_key(|(covers, _)| *covers);
+    let mut worst_gaps = vec![None; ranges.len() + 1];
+
+    let mut samples = Vec::new();
+    let mut made = 0;
+    let mut now = start;
+    loop {
+        let next_in = policy.next_snapshot_in(&snapshots, now).unwrap_or(span);
+        // a rule with a zero period would have the clock stand still
+        let mut at = (now + next_in).max(snapshots.first().map_or(now, |s| s.created + SECOND));
+        // windows can overlap, coming back from one may be inside another
+        while let Some(until) = offline_until(at) {
+            at = until;
+        }
+        // the samples up to the next snapshot all see the current ones
+        while samples.len() < SAMPLES as usize {
+            let sample_at = start + span / SAMPLES * (samples.len() as u32 + 1);
+            if sample_at > end || sample_at >= at {
+                break;
+            }
+            samples.push(sample(&snapshots, start, sample_at));
+        }
+        if at > end {
+            break;
+        }
+
+        now = at;
+        snapshots.insert(
+            0,
+            SnapshotMetadata {
+                name: format!("simulated@{}", now.to_rfc3339_opts(SecondsFormat::Secs, true)),
+                created: now,
+                used: Byte::from_bytes(0),
+            },
+        );
+        made += 1;
+        let judgement = policy.judge(&snapshots);
+        let rejected = judgement.rejected.into_iter().cloned().collect_vec();
+        snapshots.retain(|snapshot| !rejected.contains(snapshot));
+
+        for (newer, older) in snapshots.iter().tuple_windows() {
+            let age = (now - older.created).to_std().unwrap_or_default();
+            let range = ranges
+                .iter()
+                .position(|(covers, _)| age <= *covers)
+                .unwrap_or(ranges.len());
+            let gap = (newer.created - older.created).to_std().unwrap_or_default();
+            let worst: &mut Option<Duration> = &mut worst_gaps[range];
+            *worst = Some(worst.map_or(gap, |worst| worst.max(gap)));
+        }
+    }
+
+    let labels = ranges
+        .into_iter()
+        .map(|(_, label)| label)
+        .chain([String::from("older")]);
+    Simulation {
+        samples,
+        kept: snapshots
+            .iter()
+            .map(|s| (end - s.created).to_std().unwrap_or_default())
+            .collect(),
+        made,
+        worst_gaps: labels.zip(worst_gaps).collect(),
+    }
+}
+
+fn sample(snapshots: &[SnapshotMetadata], start: DateTime<Utc>, at: DateTime<Utc>) -> Sample {
+    Sample {
+        after: (at - start).to_std().unwrap_or_default(),
+        count: snapshots.len(),
+        oldest: snapshots
+            .last()
+            .map(|oldest| (at - oldest.created).to_std().unwrap_or_default()),
+    }
+}
+
+fn write_simulation(f: &mut impl Write, simulation: &Simulation) {
+    let after_width = simulation
+        .samples
+        .iter()
+        .map(|s| approximate(s.after).chars().count())
+        .max()
+        .unwrap_or(0)
+        .max("After".len());
+    writeln!(f, "  {:<after_width$} | Count | Oldest", "After").unwrap();
+    for sample in &simulation.samples {
+        writeln!(
+            f,
+            "  {:<after_width$} | {:>5} | {}",
+            approximate(sample.after),
+            sample.count,
+            sample.oldest.map_or(String::from("-"), approximate),
+        )
+        .unwrap();
+    }
+
+    writeln!(
+        f,
+        "made {} snapshots, {} kept at the end aged: {}",
+        simulation.made,
+        simulation.kept.len(),
+        simulation.kept.iter().copied().map(approximate).join(", ")
+    )
+    .unwrap();
+    writeln!(f, "worst gap between snapshots by age:").unwrap();
+    let label_width = simulation
+        .worst_gaps
+        .iter()
+        .map(|(label, _)| label.chars().count())
+        .max()
+        .unwrap_or(0);
+    for (label, gap) in &simulation.worst_gaps {
+        let gap = gap.map_or(String::from("-"), approximate);
+        writeln!(f, "  {label:<label_width$}  {gap}").unwrap();
+    }
+}
+
+/// Rounded to the minute, or to the hour in days beyond a day, for reading
+fn approximate(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if duration < DAY {
+        return format_duration(Duration::from_secs((secs + 30) / 60 * 60)).to_string();
+    }
+    let hours = (secs + 30 * 60) / (60 * 60);
+    let days = match hours / 24 {
+        1 => String::from("1day"),
+        days => format!("{days}days"),
+    };
+    match hours % 24 {
+        0 => days,
+        hours => format!("{days} {hours}h"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z")
+            .unwrap()
+            .to_utc()
+    }
+
+    fn hours(hours: u64) -> Duration {
+        Duration::from_secs(hours * 60 * 60)
+    }
+
+    #[test]
+    fn recovers_after_downtime() {
+        let policy = RetentionPolicy::from_str("1h3").unwrap();
+        let downtime = Downtime::from_str("2h+5h").unwrap();
+        let simulation = simulate(&policy, Vec::new(), start(), hours(12), &[downtime]);
+
+        // at 0, 1, 7 (on coming back), 8, 9, 10, 11 and 12 hours
+        assert_eq!(simulation.made, 8);
+        assert_eq!(simulation.kept, [hours(0), hours(1), hours(2)]);
+        assert_eq!(
+            simulation.worst_gaps,
+            [
+                (String::from("up to 3h (1h3)"), Some(hours(1))),
+                (String::from("older"), Some(hours(6))),
+            ]
+        );
+        // halfway, while offline
+        let sample = &simulation.samples[9];
+        assert_eq!((sample.after, sample.count, sample.oldest), (hours(6), 2, Some(hours(6))));
+    }
+
+    #[test]
+    fn overlapping_downtime() {
+        let policy = RetentionPolicy::from_str("1d30").unwrap();
+        let downtime = ["10d+3d", "12d+5d"].map(|d| Downtime::from_str(d).unwrap());
+        let simulation = simulate(&policy, Vec::new(), start(), DAY * 20, &downtime);
+
+        // daily up to 9 days, then not before coming back from both at 17
+        assert_eq!(simulation.made, 14);
+        assert_eq!(simulation.kept[3..5], [DAY * 3, DAY * 11]);
+    }
+}
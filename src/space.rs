@@ -0,0 +1,377 @@
+//! Destroys retained snapshots early when a pool runs low on free space or
+//! the snapshots of a dataset grow past a cap. The least valuable go first:
+//! those retained only by the shortest rules, and of those the largest.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+
+use byte_unit::Byte;
+use color_eyre::{Report, Result};
+use itertools::Itertools;
+
+use crate::lock::Locks;
+use crate::policy::Period;
+use crate::zfs::{self, Blockers, ConfiguredDataSet, Protection, SnapshotMetadata, ZfsBackend};
+
+/// Why a snapshot was destroyed before its retention policy let it go
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reason {
+    PoolFree { pool: String, free: f64, min: u8 },
+    SnapshotSpace { dataset: String, used: Byte, max: Byte },
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::PoolFree { pool, free, min } => {
+                write!(f, "pool {pool} has {free:.1}% free, below {min}%")
+            }
+            Reason::SnapshotSpace { dataset, used, max } => write!(
+                f,
+                "snapshots of {dataset} use {}, above {}",
+                used.get_appropriate_unit(true),
+                max.get_appropriate_unit(true)
+            ),
+        }
+    }
+}
+
+/// Whether any of `datasets` has a space limit
+pub fn limited<'a>(datasets: impl IntoIterator<Item = &'a ConfiguredDataSet>) -> bool {
+    datasets.into_iter().any(|d| {
+        d.settings.min_pool_free_percent.is_some() || d.settings.max_snapshot_space.is_some()
+    })
+}
+
+fn free_percent(zfs: &dyn ZfsBackend, pool: &str) -> Result<f64> {
+    let bytes = |property| -> Result<f64> {
+        let value = zfs::parse_used(&zfs.get_property(pool, property)?)?;
+        Ok(value.get_bytes() as f64)
+    };
+    let available = bytes("available")?;
+    let used = bytes("used")?;
+    if available + used == 0.0 {
+        return Ok(100.0);
+    }
+    Ok(100.0 * available / (available + used))
+}
+
+fn pressure(
+    zfs: &dyn ZfsBackend,
+    dataset: &ConfiguredDataSet,
+    pool_free: Option<f64>,
+) -> Result<Option<Reason>> {
+    if let (Some(min), Some(free)) = (dataset.settings.min_pool_free_percent, pool_free)
+        && free < f64::from(min)
+    {
+        return Ok(Some(Reason::PoolFree {
+            pool: dataset.pool().to_string(),
+            free,
+            min,
+        }));
+    }
+    if let Some(max) = dataset.settings.max_snapshot_space {
+        let used = zfs::parse_used(&zfs.get_property(&dataset.path, "usedbysnapshots")?)?;
+        if used > max {
+            return Ok(Some(Reason::SnapshotSpace {
+                dataset: dataset.path.clone(),
+                used,
+                max,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Lower is less valuable
+type Value = (Option<Period>, Reverse<Byte>);
+
+/// Retained snapshots that may go early, least valuable last. The newest
+/// snapshot, protected snapshots and held or cloned snapshots, like
+/// replication anchors, are never candidates. Members of a group are left to their
+/// leader which removes the copies together.
+fn candidates<'a>(
+    dataset: &'a ConfiguredDataSet,
+    protected: &HashMap<String, Protection>,
+    blockers: &HashMap<String, Blockers>,
+) -> Vec<(Value, &'a SnapshotMetadata)> {
+    if dataset.leader() != dataset.path {
+        return Vec::new();
+    }
+    let newest = dataset.sorted_snapshots.first();
+    let mut candidates = Vec::new();
+    for (snapshot, rules) in dataset
+        .retention_policy
+        .judge(&dataset.sorted_snapshots)
+        .retained
+    {
+        if Some(snapshot) == newest
+            || protected.contains_key(&snapshot.name)
+            || blockers.get(&snapshot.name).is_some_and(Blockers::blocking)
+        {
+            continue;
+        }
+        let longest = rules.iter().max().map(|rule| rule.snapshot_period);
+        candidates.push(((longest, Reverse(snapshot.used)), snapshot));
+    }
+    candidates.sort_by_key(|(value, _)| Reverse(*value));
+    candidates
+}
+
+/// Destroys `snapshot` and the copies in the group of `dataset`, a
+/// recursive destroy already takes those.
+fn destroy(
+    zfs: &dyn ZfsBackend,
+    datasets: &[ConfiguredDataSet],
+    dataset: &ConfiguredDataSet,
+    snapshot: &SnapshotMetadata,
+) -> Result<()> {
+    zfs::destroy_snapshot(zfs, datasets, dataset, snapshot)?;
+    if dataset.group.as_ref().is_some_and(|group| !group.recursive) {
+        for member in dataset.members(datasets).into_iter().skip(1) {
+            for copy in member
+                .sorted_snapshots
+                .iter()
+                .filter(|copy| copy.short_name() == snapshot.short_name())
+            {
+                zfs::destroy_snapshot(zfs, datasets, member, copy)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What freeing space did
+#[derive(Debug, Default)]
+pub struct Freed {
+    pub destroyed: Vec<SnapshotMetadata>,
+    /// The dataset or pool each failure is about
+    pub failed: Vec<(String, Report)>,
+}
+
+/// Destroys snapshots until every dataset is within its space limits again
+/// or has nothing left to give. A failure only stops the dataset, or pool,
+/// it is about. In sandbox mode only the first snapshot per pool is
+/// reported, what follows depends on the space it would free.
+pub fn free_space(
+    zfs: &dyn ZfsBackend,
+    datasets: &[ConfiguredDataSet],
+    protected: &HashMap<String, Protection>,
+    locks: &Locks,
+    sandbox: bool,
+) -> Freed {
+    let mut freed = Freed::default();
+    let pools = datasets.iter().map(ConfiguredDataSet::pool).unique().collect_vec();
+
+    for pool_name in pools {
+        let in_pool = datasets.iter().filter(|d| d.pool() == pool_name).collect_vec();
+        if !limited(in_pool.iter().copied()) {
+            continue;
+        }
+        let check_pool = in_pool
+            .iter()
+            .any(|d| d.settings.min_pool_free_percent.is_some());
+        let blockers = match zfs.blockers() {
+            Ok(blockers) => blockers,
+            Err(e) => {
+                freed.failed.push((pool_name.to_string(), e));
+                continue;
+            }
+        };
+        let mut candidates: HashMap<&str, Vec<_>> = in_pool
+            .iter()
+            .map(|d| (d.path.as_str(), candidates(d, protected, &blockers)))
+            .collect();
+        // only reported once, the space is checked again after each destroy
+        let mut unchecked = Vec::new();
+
+        loop {
+            let pool_free = match check_pool.then(|| free_percent(zfs, pool_name)).transpose() {
+                Ok(pool_free) => pool_free,
+                Err(e) => {
+                    freed.failed.push((pool_name.to_string(), e));
+                    break;
+                }
+            };
+            let mut under_pressure = None;
+            let mut best: Option<(&ConfiguredDataSet, Value, Reason)> = None;
+            for dataset in &in_pool {
+                if unchecked.contains(&dataset.path) {
+                    continue;
+                }
+                let reason = match pressure(zfs, dataset, pool_free) {
+                    Ok(Some(reason)) => reason,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        unchecked.push(dataset.path.clone());
+                        freed.failed.push((dataset.path.clone(), e));
+                        continue;
+                    }
+                };
+                // for pool pressure any dataset in the pool with the limit
+                // set can give
+                let giver = match reason {
+                    Reason::PoolFree { .. } => in_pool
+                        .iter()
+                        .filter(|d| d.settings.min_pool_free_percent.is_some())
+                        .filter(|d| !candidates[d.path.as_str()].is_empty())
+                        .min_by_key(|d| candidates[d.path.as_str()].last().map(|c| c.0)),
+                    Reason::SnapshotSpace { .. } => Some(dataset),
+                };
+                let Some(value) = giver.and_then(|g| candidates[g.path.as_str()].last()) else {
+                    under_pressure = Some(reason);
+                    continue;
+                };
+                if best.as_ref().is_none_or(|(_, best, _)| value.0 < *best) {
+                    best = Some((giver.expect("has a candidate"), value.0, reason));
+                }
+            }
+
+            let Some((dataset, _, reason)) = best else {
+                if let Some(reason) = under_pressure {
+                    eprintln!("{reason}, but no snapshots are left that may be removed");
+                }
+                break;
+            };
+            let (_, snapshot) = candidates
+                .get_mut(dataset.path.as_str())
+                .and_then(Vec::pop)
+                .expect("best has a candidate");
+            if sandbox {
+                println!("would remove snapshot {} to free space: {reason}", snapshot.name);
+                break;
+            }
+            let members = dataset.members(datasets);
+            let locked = match locks.datasets(members.iter().map(|member| member.path.as_str())) {
+                Ok(locked) => locked,
+                Err(e) => {
+                    freed.failed.push((dataset.path.clone(), e));
+                    break;
+                }
+            };
+            // the snapshot is no candidate anymore, a vetoing hook does not
+            // stop the others from going
+            let destroyed = destroy(zfs, datasets, dataset, snapshot);
+            drop(locked);
+            match destroyed {
+                Ok(()) => {
+                    println!("removed snapshot {} to free space: {reason}", snapshot.name);
+                    freed.destroyed.push(snapshot.clone());
+                }
+                Err(e) => freed.failed.push((dataset.path.clone(), e)),
+            }
+        }
+    }
+    freed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{DateTime, Utc};
+
+    use super::*;
+    use crate::config::Config;
+    use crate::zfs::configured_datasets;
+    use crate::zfs::fake::FakeZfs;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn start() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z")
+            .unwrap()
+            .to_utc()
+    }
+
+    /// One snapshot an hour, `used` gives the size of each, oldest first
+    fn hourly(zfs: &FakeZfs, dataset: &str, used: &[u64]) {
+        for (hour, used) in used.iter().enumerate() {
+            let name = format!("{dataset}@{hour}");
+            zfs.snapshot(&name).unwrap();
+            zfs.set_used(&name, *used);
+            zfs.advance(HOUR);
+        }
+    }
+
+    fn free(zfs: &FakeZfs, config: &str) -> Vec<String> {
+        let config: Config = toml::from_str(config).unwrap();
+        let datasets = configured_datasets(zfs, &config).unwrap();
+        let protected = zfs::protected(zfs, &datasets).unwrap();
+        let freed = free_space(zfs, &datasets, &protected, &Locks::none(), false);
+        assert!(freed.failed.is_empty(), "{:?}", freed.failed);
+        freed
+            .destroyed
+            .into_iter()
+            .map(|snapshot| snapshot.name)
+            .collect()
+    }
+
+    #[test]
+    fn largest_go_first_until_under_the_cap() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_dataset("tank/home");
+        hourly(&zfs, "tank/home", &[10, 30, 10, 20, 50]);
+
+        let destroyed = free(
+            &zfs,
+            r#"
+            [[dataset]]
+            name = "tank/home"
+            policy = "1h5"
+            max-snapshot-space = "70"
+            "#,
+        );
+        // the newest is never removed, even though it is the largest
+        assert_eq!(destroyed, ["tank/home@1", "tank/home@3"]);
+    }
+
+    #[test]
+    #[cfg(feature = "ssh")]
+    fn held_snapshots_stay() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_dataset("tank/home");
+        hourly(&zfs, "tank/home", &[10, 30, 10]);
+        zfs.hold("backup", "tank/home@1").unwrap();
+
+        let destroyed = free(
+            &zfs,
+            r#"
+            [[dataset]]
+            name = "tank/home"
+            policy = "1h5"
+            max-snapshot-space = "0"
+            "#,
+        );
+        assert_eq!(destroyed, ["tank/home@0"]);
+    }
+
+    #[test]
+    fn shortest_rule_goes_first_when_pool_is_full() {
+        let zfs = FakeZfs::new(start());
+        zfs.add_dataset("tank");
+        zfs.set_pool_size("tank", 80);
+        zfs.add_dataset("tank/home");
+        zfs.add_dataset("tank/media");
+        hourly(&zfs, "tank/home", &[20, 5, 6, 5]);
+        hourly(&zfs, "tank/media", &[10, 10]);
+
+        let destroyed = free(
+            &zfs,
+            r#"
+            [[dataset]]
+            name = "tank/home"
+            policy = "1h3@oldest:1d2@oldest"
+            min-pool-free-percent = 40
+
+            [[dataset]]
+            name = "tank/media"
+            policy = "1h9"
+            "#,
+        );
+        // only the snapshots of tank/home retained by the hourly rule may
+        // go, tank/media has no limit
+        assert_eq!(destroyed, ["tank/home@2", "tank/home@1"]);
+    }
+}
@@ -1,4 +1,4 @@
-use std::io::ErrorKind;
+use std::io::{ErrorKind, Read};
 
 use color_eyre::eyre::{OptionExt, WrapErr, eyre};
 use color_eyre::{Help, Result};
@@ -6,12 +6,14 @@ use color_eyre::{Help, Result};
 use openssh::{KnownHosts, Session};
 use tokio::io::AsyncWriteExt;
 
-struct Connection {
+use crate::zfs::{self, SnapshotMetadata};
+
+pub(crate) struct Connection {
     session: Session,
 }
 
 impl Connection {
-    async fn new(target: &str) -> Result<Self> {
+    pub(crate) async fn new(target: &str) -> Result<Self> {
         Ok(Self {
             session: Session::connect(target, KnownHosts::Strict)
                 .await
@@ -20,6 +22,120 @@ impl Connection {
         })
     }
 
+    /// Snapshots of `dataset` on the remote, newest first. None if the
+    /// dataset does not exist there.
+    pub(crate) async fn snapshots(&self, dataset: &str) -> Result<Option<Vec<SnapshotMetadata>>> {
+        let output = self
+            .session
+            .command("zfs")
+            .args(["list", "-H", "-p", "-t", "snapshot", "-S", "creation"])
+            .args(["-o", "name,creation,used", "-d", "1", dataset])
+            .output()
+            .await
+            .wrap_err("Could not start `zfs list` on remote machine")?;
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("does not exist") {
+            return Ok(None);
+        } else if !output.status.success() {
+            return Err(eyre!("zfs list on remote machine returned error"))
+                .with_note(|| format!("error: {stderr}"));
+        }
+
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(
+                |line| match line.split('\t').collect::<Vec<_>>().as_slice() {
+                    [name, created, used] => zfs::parse_snapshot(name, created, used),
+                    _ => Err(eyre!("remote list snapshots parse error"))
+                        .with_note(|| format!("line: {line}")),
+                },
+            )
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+
+    pub(crate) async fn destroy_snapshot(&self, snapshot: &SnapshotMetadata) -> Result<()> {
+        // Same guard as zfs::destroy_snapshot, never pass anything but a
+        // snapshot to zfs destroy.
+        if !snapshot.name.contains('@') {
+            return Err(eyre!("Tried to destroy something that is not a snapshot"));
+        }
+        let output = self
+            .session
+            .command("zfs")
+            .arg("destroy")
+            .arg(&snapshot.name)
+            .output()
+            .await
+            .wrap_err("Could not start `zfs destroy` on remote machine")?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            let err = String::from_utf8_lossy(&output.stderr);
+            Err(eyre!("zfs destroy on remote machine returned error"))
+                .with_note(|| format!("error: {err}"))
+        }
+    }
+
+    /// Feeds a `zfs send` stream into `zfs receive` on the remote. Incremental
+    /// streams are received with `-F` so that changes made on the remote since
+    /// the last replication (atime for example) do not block it.
+    pub(crate) async fn receive(
+        &self,
+        dataset: &str,
+        incremental: bool,
+        mut stream: Box<dyn Read + Send>,
+    ) -> Result<()> {
+        let mut command = self.session.command("zfs");
+        command.args(["receive", "-u"]);
+        if incremental {
+            command.arg("-F");
+        }
+        let mut receive = command
+            .arg(dataset)
+            .stdin(openssh::Stdio::piped())
+            .stdout(openssh::Stdio::null())
+            .stderr(openssh::Stdio::piped())
+            .spawn()
+            .await
+            .wrap_err("Failed to spawn zfs receive")?;
+        let mut stdin = receive
+            .stdin()
+            .take()
+            .expect("just configured stdin to piped");
+
+        let mut buf = vec![0u8; 128 * 1024];
+        loop {
+            let n = tokio::task::block_in_place(|| stream.read(&mut buf))
+                .wrap_err("Failed to read from zfs send")?;
+            if n == 0 {
+                break;
+            }
+            stdin
+                .write_all(&buf[..n])
+                .await
+                .wrap_err("Failed to write stream to remote")?;
+        }
+        stdin
+            .shutdown()
+            .await
+            .wrap_err("Failed to close stream to remote")?;
+        drop(stdin);
+
+        let res = receive
+            .wait_with_output()
+            .await
+            .wrap_err("zfs receive failed to complete")?;
+        if res.status.success() {
+            Ok(())
+        } else {
+            let err = String::from_utf8_lossy(&res.stderr);
+            Err(eyre!("zfs receive on remote machine returned error"))
+                .with_note(|| format!("error: {err}"))
+        }
+    }
+
     async fn copy_basic_build(&self, build: &[u8]) -> Result<()> {
         let mut copy_process = self
             .session
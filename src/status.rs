@@ -1,18 +1,61 @@
+use std::collections::HashMap;
 use std::io::Write;
 use std::time::Duration;
 
-use crate::zfs::{ConfiguredDataSet, SnapshotMetadata, configured_datasets};
+use crate::config::Config;
+use crate::health::Health;
+use crate::lock::{DaemonStatus, Locks};
+use crate::zfs::{
+    self, ConfiguredDataSet, Protection, SnapshotMetadata, ZfsBackend, configured_datasets,
+};
+use chrono::{DateTime, Utc};
 use color_eyre::Result;
 use humantime::format_duration;
 use itertools::Itertools;
 
-pub fn print_status(verbose: bool) -> Result<()> {
-    let datasets = configured_datasets()?;
-    write_status(&mut std::io::stdout(), &datasets, verbose);
+mod json;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Format {
+    /// Tables meant for humans
+    Text,
+    /// Everything status knows as a single json object
+    Json,
+}
+
+pub fn print_status(
+    zfs: &dyn ZfsBackend,
+    config: &Config,
+    locks: &Locks,
+    format: Format,
+    verbose: bool,
+) -> Result<()> {
+    let datasets = configured_datasets(zfs, config)?;
+    let protected = zfs::protected(zfs, &datasets)?;
+    let daemon = locks.daemon_status()?;
+    let mut stdout = std::io::stdout();
+    match format {
+        Format::Text => {
+            write_daemon(&mut stdout, daemon.as_ref());
+            write_status(&mut stdout, &datasets, &protected, zfs.now(), verbose);
+            write_pool_health(&mut stdout, &Health::check(zfs, &datasets));
+        }
+        Format::Json => {
+            let health = Health::check(zfs, &datasets);
+            let (daemon, now) = (daemon.as_ref(), zfs.now());
+            json::write_status(&mut stdout, &datasets, &protected, &health, daemon, now)?;
+        }
+    }
     Ok(())
 }
 
-pub fn write_status(f: &mut impl Write, datasets: &[ConfiguredDataSet], verbose: bool) {
+pub fn write_status(
+    f: &mut impl Write,
+    datasets: &[ConfiguredDataSet],
+    protected: &HashMap<String, Protection>,
+    now: DateTime<Utc>,
+    verbose: bool,
+) {
     if datasets.is_empty() {
         writeln!(
             f,
@@ -24,26 +67,119 @@ pub fn write_status(f: &mut impl Write, datasets: &[ConfiguredDataSet], verbose:
 
     if verbose {
         writeln!(f, "Configured datasets").unwrap();
-        write_configured_datasets_section_verbose(f, datasets);
+        write_configured_datasets_section_verbose(f, datasets, now);
         writeln!(f).unwrap();
         writeln!(f, "Snapshot to be removed").unwrap();
-        write_rejected_snapshot_state_verbose(f, datasets);
+        write_rejected_snapshot_state_verbose(f, datasets, protected);
     } else {
         writeln!(f, "Configured datasets").unwrap();
-        write_configured_datasets_section(f, datasets);
+        write_configured_datasets_section(f, datasets, now);
         writeln!(f, "Snapshot to be removed").unwrap();
-        write_rejected_snapshot_state(f, datasets);
+        write_rejected_snapshot_state(f, datasets, protected);
+    }
+    write_conflicts(f, datasets);
+}
+
+fn write_daemon(f: &mut impl Write, daemon: Option<&DaemonStatus>) {
+    match daemon {
+        Some(DaemonStatus {
+            pid,
+            doing,
+            failing,
+        }) => {
+            writeln!(f, "Daemon running (pid {pid}): {doing}").unwrap();
+            for failure in failing {
+                writeln!(
+                    f,
+                    "  could not {}, {} attempts, next at {}: {}",
+                    failure.task,
+                    failure.attempts,
+                    failure.retry_at.format("%Y-%m-%d %H:%M:%S"),
+                    failure.error
+                )
+                .unwrap();
+            }
+        }
+        None => writeln!(f, "Daemon not running").unwrap(),
+    }
+}
+
+fn write_pool_health(f: &mut impl Write, health: &Health) {
+    if health.is_empty() {
+        return;
+    }
+
+    writeln!(f, "Unhealthy pools, see on-unhealthy-pool for what is skipped").unwrap();
+    for (pool, problem) in health.iter() {
+        writeln!(f, "  {pool} {problem}").unwrap();
+    }
+}
+
+fn write_conflicts(f: &mut impl Write, datasets: &[ConfiguredDataSet]) {
+    if datasets.iter().all(|d| d.conflicts.is_empty()) {
+        return;
+    }
+
+    writeln!(f, "Config file and zfs properties disagree").unwrap();
+    for dataset in datasets.iter().filter(|d| !d.conflicts.is_empty()) {
+        writeln!(f, "  {}", dataset.path).unwrap();
+        for conflict in &dataset.conflicts {
+            writeln!(
+                f,
+                "    {}: using '{}' from the config file, property has '{}'",
+                conflict.setting, conflict.config, conflict.property
+            )
+            .unwrap();
+        }
     }
 }
 
-fn write_rejected_snapshot_state(f: &mut impl Write, datasets: &[ConfiguredDataSet]) {
+/// Splits the snapshots rejected by the policy into those that will be
+/// removed and those that are kept anyway.
+fn rejected_and_protected<'a>(
+    dataset: &'a ConfiguredDataSet,
+    protected: &'a HashMap<String, Protection>,
+) -> (
+    Vec<&'a SnapshotMetadata>,
+    Vec<(&'a SnapshotMetadata, &'a Protection)>,
+) {
+    let judgement = dataset.retention_policy.judge(&dataset.sorted_snapshots);
+    let mut rejected = judgement.rejected.into_iter().collect_vec();
+    rejected.sort();
+    let (kept, rejected): (Vec<_>, Vec<_>) = rejected
+        .into_iter()
+        .partition(|snapshot| protected.contains_key(&snapshot.name));
+    let kept = kept
+        .into_iter()
+        .map(|snapshot| (snapshot, &protected[&snapshot.name]))
+        .collect();
+    (rejected, kept)
+}
+
+fn write_protected(f: &mut impl Write, kept: &[(&SnapshotMetadata, &Protection)]) {
+    for (snapshot, protection) in kept {
+        match protection {
+            Protection::Blocked(_) => writeln!(f, "    {} ({protection})", snapshot.name),
+            _ => writeln!(f, "    {} (retained: {protection})", snapshot.name),
+        }
+        .unwrap();
+    }
+}
+
+fn write_rejected_snapshot_state(
+    f: &mut impl Write,
+    datasets: &[ConfiguredDataSet],
+    protected: &HashMap<String, Protection>,
+) {
     for dataset in datasets {
-        let judgement = dataset.retention_policy.judge(&dataset.sorted_snapshots);
-        let mut rejected = judgement.rejected.into_iter().collect_vec();
-        rejected.sort();
+        let (rejected, kept) = rejected_and_protected(dataset, protected);
         let mut rejected = rejected.into_iter();
 
         let Some(first) = rejected.next() else {
+            if !kept.is_empty() {
+                writeln!(f, "  {}:", dataset.path).unwrap();
+                write_protected(f, &kept);
+            }
             continue;
         };
         write!(f, "  {}: {}", dataset.path, first.name).unwrap();
@@ -57,16 +193,24 @@ fn write_rejected_snapshot_state(f: &mut impl Write, datasets: &[ConfiguredDataS
                 current_line_len += 4 + snapshot.name.chars().count();
             }
         }
+        writeln!(f).unwrap();
+        write_protected(f, &kept);
     }
 }
 
-fn write_rejected_snapshot_state_verbose(f: &mut impl Write, datasets: &[ConfiguredDataSet]) {
+fn write_rejected_snapshot_state_verbose(
+    f: &mut impl Write,
+    datasets: &[ConfiguredDataSet],
+    protected: &HashMap<String, Protection>,
+) {
     for dataset in datasets {
-        let judgement = dataset.retention_policy.judge(&dataset.sorted_snapshots);
-        let mut rejected = judgement.rejected.into_iter().collect_vec();
-        rejected.sort();
+        let (rejected, kept) = rejected_and_protected(dataset, protected);
 
         if rejected.is_empty() {
+            if !kept.is_empty() {
+                writeln!(f, "  {}", dataset.path).unwrap();
+                write_protected(f, &kept);
+            }
             continue;
         }
 
@@ -128,24 +272,39 @@ fn write_rejected_snapshot_state_verbose(f: &mut impl Write, datasets: &[Configu
             )
             .unwrap();
         }
+        write_protected(f, &kept);
     }
 }
 
-fn write_configured_datasets_section_verbose(f: &mut impl Write, datasets: &[ConfiguredDataSet]) {
+fn write_configured_datasets_section_verbose(
+    f: &mut impl Write,
+    datasets: &[ConfiguredDataSet],
+    now: DateTime<Utc>,
+) {
     for dataset in datasets {
         let next_snapshot_in = dataset
-            .until_next_snapshot()
+            .until_next_snapshot(now)
             .map(|d| d - Duration::from_nanos(u64::from(d.subsec_nanos())))
             .map_or("never".to_string(), |d| format_duration(d).to_string());
         let ConfiguredDataSet {
             path,
             retention_policy,
             sorted_snapshots,
+            ..
         } = dataset;
 
         writeln!(f, "  {path}").unwrap();
         writeln!(f, "    numbers of snapshots: {}", sorted_snapshots.len()).unwrap();
         writeln!(f, "    next snapshot in: {next_snapshot_in}").unwrap();
+        if let (Some(written), Some(min)) = (dataset.written, dataset.settings.min_written) {
+            writeln!(
+                f,
+                "    written since last snapshot: {} (postponed below {})",
+                written.get_appropriate_unit(true),
+                min.get_appropriate_unit(true)
+            )
+            .unwrap();
+        }
         writeln!(f, "    retention policy:").unwrap();
         for rule in &retention_policy.0 {
             writeln!(f, "    - {rule}",).unwrap();
@@ -153,7 +312,11 @@ fn write_configured_datasets_section_verbose(f: &mut impl Write, datasets: &[Con
     }
 }
 
-fn write_configured_datasets_section(f: &mut impl Write, datasets: &[ConfiguredDataSet]) {
+fn write_configured_datasets_section(
+    f: &mut impl Write,
+    datasets: &[ConfiguredDataSet],
+    now: DateTime<Utc>,
+) {
     let path_width = datasets
         .iter()
         .map(|d| d.path.chars().count())
@@ -182,13 +345,14 @@ fn write_configured_datasets_section(f: &mut impl Write, datasets: &[ConfiguredD
 
     for dataset in datasets {
         let next_snapshot_in = dataset
-            .until_next_snapshot()
+            .until_next_snapshot(now)
             .map(|d| d - Duration::from_nanos(u64::from(d.subsec_nanos())))
             .map_or("never".to_string(), |d| format_duration(d).to_string());
         let ConfiguredDataSet {
             path,
             retention_policy,
             sorted_snapshots,
+            ..
         } = dataset;
 
         let retention_policy = format!("{retention_policy:?}");
@@ -209,14 +373,17 @@ mod tests {
     use std::str::FromStr;
 
     use super::*;
+    use crate::config::{Conflict, Settings};
+    use crate::health::PoolStatus;
     use crate::policy::RetentionPolicy;
     use crate::policy::tests::aged;
+    use crate::zfs::fake::FakeZfs;
 
     fn test_datasets() -> [ConfiguredDataSet; 2] {
         [
             ConfiguredDataSet {
                 path: String::from("/home/david/Documents"),
-                retention_policy: RetentionPolicy::from_str("15m8:1h48:1d14:1w20").unwrap(),
+                retention_policy: RetentionPolicy::from_str("15min8:1h48:1d14:1w20").unwrap(),
                 sorted_snapshots: Box::new([
                     aged!(10 m),
                     aged!(36 m),
@@ -225,6 +392,10 @@ mod tests {
                     aged!(2 d),
                     aged!(3 d),
                 ]),
+                settings: Settings::default(),
+                conflicts: Vec::new(),
+                group: None,
+                written: None,
             },
             ConfiguredDataSet {
                 path: String::from("/home/david/Downloads"),
@@ -237,6 +408,10 @@ mod tests {
                     aged!(2 d),
                     aged!(3 d),
                 ]),
+                settings: Settings::default(),
+                conflicts: Vec::new(),
+                group: None,
+                written: None,
             },
         ]
     }
@@ -244,7 +419,13 @@ mod tests {
     #[test]
     fn verbose() {
         let mut output = Vec::new();
-        write_status(&mut output, &test_datasets(), true);
+        write_status(
+            &mut output,
+            &test_datasets(),
+            &HashMap::new(),
+            Utc::now(),
+            true,
+        );
         let output = String::from_utf8(output).unwrap();
         println!("{output}");
     }
@@ -252,8 +433,87 @@ mod tests {
     #[test]
     fn terse() {
         let mut output = Vec::new();
-        write_status(&mut output, &test_datasets(), false);
+        write_status(
+            &mut output,
+            &test_datasets(),
+            &HashMap::new(),
+            Utc::now(),
+            false,
+        );
         let output = String::from_utf8(output).unwrap();
         println!("{output}");
     }
+
+    #[test]
+    fn shows_protected() {
+        let datasets = test_datasets();
+        let rejected = datasets[1]
+            .retention_policy
+            .judge(&datasets[1].sorted_snapshots)
+            .rejected;
+        let anchor = rejected.iter().max().unwrap();
+        let protected = HashMap::from([(anchor.name.clone(), Protection::ReplicationAnchor)]);
+
+        for verbose in [true, false] {
+            let mut output = Vec::new();
+            write_status(&mut output, &datasets, &protected, Utc::now(), verbose);
+            let output = String::from_utf8(output).unwrap();
+            assert!(output.contains(&format!("{} (retained: replication anchor)", anchor.name)));
+        }
+    }
+
+    #[test]
+    fn shows_conflicts() {
+        let mut datasets = test_datasets();
+        datasets[0].conflicts.push(Conflict {
+            setting: "policy",
+            config: String::from("15min8:1h48:1d14:1w20"),
+            property: String::from("1h24"),
+        });
+
+        let mut output = Vec::new();
+        write_status(&mut output, &datasets, &HashMap::new(), Utc::now(), false);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("policy: using '15min8:1h48:1d14:1w20' from the config file"));
+    }
+
+    #[test]
+    fn json() {
+        let datasets = test_datasets();
+        let judgement = datasets[1]
+            .retention_policy
+            .judge(&datasets[1].sorted_snapshots);
+        let anchor = judgement.rejected.iter().max().unwrap();
+        let protected = HashMap::from([(anchor.name.clone(), Protection::ReplicationAnchor)]);
+
+        let zfs = FakeZfs::new(Utc::now());
+        zfs.add_configured_dataset("tank/home", "1h3");
+        zfs.add_configured_dataset("backup/home", "1h3");
+        zfs.set_pool_status(
+            "tank",
+            PoolStatus {
+                health: String::from("DEGRADED"),
+                ..PoolStatus::online()
+            },
+        );
+        let health = Health::check(&zfs, &configured_datasets(&zfs, &Config::default()).unwrap());
+
+        let mut output = Vec::new();
+        json::write_status(&mut output, &datasets, &protected, &health, None, Utc::now()).unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&output).unwrap();
+
+        let downloads = &status["datasets"][1];
+        assert_eq!(downloads["path"], "/home/david/Downloads");
+        assert_eq!(downloads["policy"], "1h2:2d2");
+        assert_eq!(downloads["rules"][0]["period_seconds"], 60 * 60);
+        assert_eq!(downloads["snapshots"].as_array().unwrap().len(), 6);
+        assert!(downloads["next_snapshot"].is_string());
+        assert_eq!(
+            downloads["judgement"]["rejected"].as_array().unwrap().len(),
+            judgement.rejected.len()
+        );
+        assert_eq!(downloads["judgement"]["retained"]["1h"][0], "1h2");
+        assert_eq!(downloads["protected"][&anchor.name], "replication anchor");
+        assert_eq!(status["pools"], serde_json::json!({"tank": "is DEGRADED"}));
+    }
 }
@@ -0,0 +1,149 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use color_eyre::eyre::WrapErr;
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::config::Conflict;
+use crate::health::Health;
+use crate::lock::DaemonStatus;
+use crate::policy::{Keep, RetentionRule, Spacing};
+use crate::zfs::{ConfiguredDataSet, Protection, SnapshotMetadata};
+
+#[derive(Serialize)]
+struct Status<'a> {
+    /// Null if no daemon is running
+    daemon: Option<&'a DaemonStatus>,
+    datasets: Vec<DataSet<'a>>,
+    /// What is wrong with each unhealthy pool, healthy pools are left out
+    pools: BTreeMap<&'a str, String>,
+}
+
+#[derive(Serialize)]
+struct DataSet<'a> {
+    path: &'a str,
+    /// In the syntax used by the property and config file
+    policy: String,
+    rules: Vec<Rule>,
+    /// Newest first
+    snapshots: Vec<Snapshot<'a>>,
+    next_snapshot: Option<DateTime<Utc>>,
+    judgement: Judgement<'a>,
+    /// Rejected snapshots that are kept anyway and why
+    protected: BTreeMap<&'a str, String>,
+    conflicts: &'a [Conflict],
+}
+
+#[derive(Serialize)]
+struct Rule {
+    rule: String,
+    /// For months and years this uses the average length of a month
+    period_seconds: u64,
+    retained_copies: usize,
+    /// Which snapshot of each calendar bucket is kept, null for rolling rules
+    calendar: Option<Keep>,
+}
+
+#[derive(Serialize)]
+struct Snapshot<'a> {
+    name: &'a str,
+    created: DateTime<Utc>,
+    used_bytes: u128,
+}
+
+#[derive(Serialize)]
+struct Judgement<'a> {
+    rejected: Vec<&'a str>,
+    /// The rules that retain each snapshot
+    retained: BTreeMap<&'a str, Vec<String>>,
+}
+
+impl Rule {
+    fn new(rule: &RetentionRule) -> Self {
+        Self {
+            rule: format!("{rule:?}"),
+            period_seconds: rule.snapshot_period.approximate().as_secs(),
+            retained_copies: rule.retained_copies,
+            calendar: match rule.spacing {
+                Spacing::Rolling => None,
+                Spacing::Calendar(keep) => Some(keep),
+            },
+        }
+    }
+}
+
+impl<'a> Snapshot<'a> {
+    fn new(snapshot: &'a SnapshotMetadata) -> Self {
+        Self {
+            name: &snapshot.name,
+            created: snapshot.created,
+            used_bytes: snapshot.used.get_bytes(),
+        }
+    }
+}
+
+impl<'a> DataSet<'a> {
+    fn new(
+        dataset: &'a ConfiguredDataSet,
+        protected: &HashMap<String, Protection>,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let judgement = dataset.retention_policy.judge(&dataset.sorted_snapshots);
+        let rejected = judgement
+            .rejected
+            .into_iter()
+            .sorted()
+            .map(|snapshot| snapshot.name.as_str())
+            .collect_vec();
+
+        Self {
+            path: &dataset.path,
+            policy: format!("{:?}", dataset.retention_policy),
+            rules: dataset.retention_policy.0.iter().map(Rule::new).collect(),
+            snapshots: dataset.sorted_snapshots.iter().map(Snapshot::new).collect(),
+            next_snapshot: dataset.until_next_snapshot(now).map(|until| now + until),
+            protected: rejected
+                .iter()
+                .filter_map(|name| Some((*name, protected.get(*name)?.to_string())))
+                .collect(),
+            judgement: Judgement {
+                rejected,
+                retained: judgement
+                    .retained
+                    .into_iter()
+                    .map(|(snapshot, rules)| {
+                        let rules = rules.into_iter().sorted().map(|r| format!("{r:?}"));
+                        (snapshot.name.as_str(), rules.collect())
+                    })
+                    .collect(),
+            },
+            conflicts: &dataset.conflicts,
+        }
+    }
+}
+
+pub(super) fn write_status(
+    f: &mut impl Write,
+    datasets: &[ConfiguredDataSet],
+    protected: &HashMap<String, Protection>,
+    health: &Health,
+    daemon: Option<&DaemonStatus>,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    let status = Status {
+        daemon,
+        datasets: datasets
+            .iter()
+            .map(|dataset| DataSet::new(dataset, protected, now))
+            .collect(),
+        pools: health
+            .iter()
+            .map(|(pool, problem)| (pool, problem.to_string()))
+            .collect(),
+    };
+    serde_json::to_writer_pretty(&mut *f, &status).wrap_err("Could not serialize status")?;
+    writeln!(f).wrap_err("Could not write status")
+}
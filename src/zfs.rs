@@ -1,4 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+#[cfg(feature = "ssh")]
+use std::io::Read;
 use std::process::Command;
 use std::str::FromStr;
 use std::time::Duration;
@@ -6,11 +9,19 @@ use std::time::Duration;
 use byte_unit::Byte;
 use chrono::prelude::*;
 use color_eyre::eyre::eyre;
+use color_eyre::eyre::WrapErr;
 use itertools::Itertools;
 use color_eyre::{Result, Section};
 
+use crate::config::{Config, Conflict, DataSetConfig, Settings, pick};
+use crate::health::{self, OnUnhealthyPool, PoolStatus};
+use crate::hooks::{self, Hooks, OnFailure};
+use crate::restore::{self, Change};
 use crate::{DataSet, ZFS_PROPERTY, RetentionPolicy};
 
+#[cfg(test)]
+pub mod fake;
+
 #[derive(PartialEq, Eq, Hash, Clone)]
 pub struct SnapshotMetadata {
     pub name: String,
@@ -43,40 +54,429 @@ impl SnapshotMetadata {
             .expect("Snapshot names always contain @")
             .0
     }
+
+    /// The part after the @
+    pub(crate) fn short_name(&self) -> &str {
+        self.name
+            .split_once('@')
+            .expect("Snapshot names always contain @")
+            .1
+    }
 }
 
-pub fn snapshot(dataset: &str) -> Result<SnapshotMetadata> {
-    // Take a snapshot of the given dataset, with an auto-generated name.
-    let now = Utc::now();
-    let name = format!(
-        "{}@{}-autosnap",
-        dataset,
-        now.to_rfc3339_opts(SecondsFormat::Secs, true)
-    );
-    call_do("snap", &[&name])?;
-    Ok(SnapshotMetadata {
-        name: name.clone(),
-        created: now,
-        used: parse_used(&get_property(&name, "used")?)?,
-    })
+/// Everything zcrab needs from zfs. The program only talks to zfs through
+/// this trait so that it can be tested without real pools.
+pub trait ZfsBackend {
+    /// Current time, used to timestamp new snapshots
+    fn now(&self) -> DateTime<Utc>;
+    /// Wait until `duration` has passed on the clock returned by `now`
+    fn sleep(&self, duration: Duration);
+    /// All filesystems and volumes together with the value of `property`
+    /// on them, `-` if it is not set.
+    fn list_datasets(&self, property: &str) -> Result<Vec<(DataSet, String)>>;
+    fn list_snapshots(&self) -> Result<Vec<SnapshotMetadata>>;
+    /// Datasets and snapshots that have `property` set locally (not
+    /// inherited) together with its value.
+    fn list_local(&self, property: &str) -> Result<Vec<(String, String)>>;
+    fn get_property(&self, name: &str, property: &str) -> Result<String>;
+    fn set_property(&self, name: &str, property: &str, value: &str) -> Result<()>;
+    /// Remove the local value of `property`, the inherited one applies again
+    fn inherit_property(&self, name: &str, property: &str) -> Result<()>;
+    /// Create a snapshot, `name` has the form `dataset@snapshot`
+    fn snapshot(&self, name: &str) -> Result<()>;
+    /// Create snapshots that all have the same part after the @ in a single
+    /// transaction group. With `recursive` the descendants of each dataset
+    /// are snapshotted too.
+    fn snapshot_atomic(&self, names: &[String], recursive: bool) -> Result<()>;
+    /// Destroy snapshots of a single dataset in one call, `names` have the
+    /// form `dataset@snapshot`. With `deferred` a snapshot that is held or
+    /// cloned is marked to be destroyed once that is no longer the case
+    /// instead of failing.
+    fn destroy(&self, names: &[&str], deferred: bool) -> Result<()>;
+    /// Destroy snapshots and the snapshots with the same names of all
+    /// descendants of their dataset
+    fn destroy_recursive(&self, names: &[&str], deferred: bool) -> Result<()>;
+    /// Space destroying the snapshots would free, without destroying them
+    fn reclaimable(&self, names: &[&str], recursive: bool) -> Result<Byte>;
+    /// Create the dataset `clone` from `snapshot` with `properties` set on it
+    fn clone_snapshot(
+        &self,
+        snapshot: &str,
+        clone: &str,
+        properties: &[(&str, &str)],
+    ) -> Result<()>;
+    /// Reverses the dependency of a clone on its origin: the clone takes
+    /// over the origin snapshot and the snapshots before it.
+    fn promote(&self, clone: &str) -> Result<()>;
+    /// Renames a dataset, its snapshots and descendants go along
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+    /// The properties set locally on a dataset together with their values
+    fn local_properties(&self, dataset: &str) -> Result<Vec<(String, String)>>;
+    /// Files changed between a snapshot and a later snapshot or the live
+    /// dataset
+    fn diff(&self, from: &str, to: &str) -> Result<Vec<Change>>;
+    /// What keeps snapshots from being destroyed, by snapshot name. Those
+    /// nothing blocks are left out.
+    fn blockers(&self) -> Result<HashMap<String, Blockers>>;
+    /// Health of a pool as reported by `zpool`
+    fn pool_status(&self, pool: &str) -> Result<PoolStatus>;
+    #[cfg(feature = "ssh")]
+    fn hold(&self, tag: &str, snapshot: &str) -> Result<()>;
+    #[cfg(feature = "ssh")]
+    fn release(&self, tag: &str, snapshot: &str) -> Result<()>;
+    /// Stream of `snapshot` as made by `zfs send`, incremental from `base`
+    /// if given.
+    #[cfg(feature = "ssh")]
+    fn send(&self, base: Option<&str>, snapshot: &str) -> Result<Box<dyn Read + Send>>;
 }
 
-pub fn add_snapshots() -> Result<HashMap<DataSet, Box<[SnapshotMetadata]>>> {
-    // List all snapshots under our control.
-    // zfs list -H -t snapshot -o name,creation,used,at.rollc.at:snapkeep
-    let lines = call_zfs_cli(
-        "list",
-        &[
-            "-t",
-            "snapshot",
-            "-o",
-            &format!("name,creation,used,{ZFS_PROPERTY}"),
-        ],
-    )?;
+/// The real thing, calls out to the `zfs` command line tool
+pub struct Cli;
+
+impl ZfsBackend for Cli {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+
+    fn list_datasets(&self, property: &str) -> Result<Vec<(DataSet, String)>> {
+        // zfs get -H -t filesystem,volume -o name,value $property
+        call_zfs_cli(
+            "get",
+            &["-t", "filesystem,volume", "-o", "name,value", property],
+        )?
+        .into_iter()
+        .map(|pairs| {
+            let [name, value]: [String; 2] = pairs
+                .try_into()
+                .map_err(|_| eyre!("zfs get returned a row that is not a pair"))?;
+            Ok((name, value))
+        })
+        .collect()
+    }
+
+    fn list_snapshots(&self) -> Result<Vec<SnapshotMetadata>> {
+        // zfs list -H -t snapshot -o name,creation,used
+        let lines = call_zfs_cli("list", &["-t", "snapshot", "-o", "name,creation,used"])?;
+        parse_snapshots(lines)
+    }
+
+    fn list_local(&self, property: &str) -> Result<Vec<(String, String)>> {
+        // zfs get -H -t filesystem,volume,snapshot -s local -o name,value $property
+        call_zfs_cli(
+            "get",
+            &["-t", "filesystem,volume,snapshot", "-s", "local", "-o", "name,value", property],
+        )?
+        .into_iter()
+        .map(|pairs| {
+            let [name, value]: [String; 2] = pairs
+                .try_into()
+                .map_err(|_| eyre!("zfs get returned a row that is not a pair"))?;
+            Ok((name, value))
+        })
+        .collect()
+    }
+
+    fn get_property(&self, name: &str, property: &str) -> Result<String> {
+        // Get a single named property on given dataset.
+        // zfs get -H -o value $property $dataset
+        call_zfs_cli("get", &["-o", "value", property, name])?
+            .first()
+            .and_then(|row| row.first())
+            .cloned()
+            .ok_or_else(|| eyre!("zfs get returned nothing"))
+            .with_note(|| format!("property: {property}, of: {name}"))
+    }
+
+    fn set_property(&self, name: &str, property: &str, value: &str) -> Result<()> {
+        let output = Command::new("zfs")
+            .args(["set", &format!("{property}={value}"), name])
+            .output()?;
+        if output.stderr.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre!("zfs set failed"))
+                .with_note(|| format!("stderr is: {}", String::from_utf8_lossy(&output.stderr)))
+        }
+    }
+
+    fn inherit_property(&self, name: &str, property: &str) -> Result<()> {
+        let output = Command::new("zfs")
+            .args(["inherit", property, name])
+            .output()?;
+        if output.stderr.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre!("zfs inherit failed"))
+                .with_note(|| format!("stderr is: {}", String::from_utf8_lossy(&output.stderr)))
+        }
+    }
+
+    fn snapshot(&self, name: &str) -> Result<()> {
+        call_do("snap", &[name])
+    }
+
+    fn snapshot_atomic(&self, names: &[String], recursive: bool) -> Result<()> {
+        // zfs snapshot [-r] a@... b@...
+        let mut args = Vec::with_capacity(names.len() + 1);
+        if recursive {
+            args.push("-r");
+        }
+        args.extend(names.iter().map(String::as_str));
+        call_do("snapshot", &args)
+    }
+
+    fn destroy(&self, names: &[&str], deferred: bool) -> Result<()> {
+        // zfs destroy [-d] ...@a,b,c
+        let batch = batch(names)?;
+        if deferred {
+            call_do("destroy", &["-d", &batch])
+        } else {
+            call_do("destroy", &[&batch])
+        }
+    }
+
+    fn destroy_recursive(&self, names: &[&str], deferred: bool) -> Result<()> {
+        // zfs destroy -r [-d] ...@a,b,c
+        let batch = batch(names)?;
+        if deferred {
+            call_do("destroy", &["-r", "-d", &batch])
+        } else {
+            call_do("destroy", &["-r", &batch])
+        }
+    }
+
+    fn reclaimable(&self, names: &[&str], recursive: bool) -> Result<Byte> {
+        // zfs destroy -nvp [-r] ...@a,b,c
+        let batch = batch(names)?;
+        let flags = if recursive { "-nvpr" } else { "-nvp" };
+        let capture = subprocess::Exec::cmd("zfs")
+            .args(&["destroy", flags, &batch])
+            .stdout(subprocess::Redirection::Pipe)
+            .capture()?;
+        if !capture.success() {
+            return Err(eyre!("zfs destroy dry run failed, {:?}", capture.exit_status))
+                .with_note(|| format!("snapshots: {batch}"));
+        }
+        let stdout = capture.stdout_str();
+        let reclaim = stdout
+            .lines()
+            .find_map(|line| line.strip_prefix("reclaim\t"))
+            .ok_or_else(|| eyre!("zfs destroy dry run did not report the space to reclaim"))?;
+        parse_used(reclaim)
+    }
+
+    fn clone_snapshot(
+        &self,
+        snapshot: &str,
+        clone: &str,
+        properties: &[(&str, &str)],
+    ) -> Result<()> {
+        // zfs clone [-o property=value]... ...@... $clone
+        let properties = properties
+            .iter()
+            .map(|(property, value)| format!("{property}={value}"))
+            .collect_vec();
+        let mut args = Vec::new();
+        for property in &properties {
+            args.extend(["-o", property.as_str()]);
+        }
+        args.extend([snapshot, clone]);
+        call_do("clone", &args)
+    }
+
+    fn promote(&self, clone: &str) -> Result<()> {
+        call_do("promote", &[clone])
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        call_do("rename", &[from, to])
+    }
+
+    fn local_properties(&self, dataset: &str) -> Result<Vec<(String, String)>> {
+        // zfs get -H -s local -o property,value all ...
+        call_zfs_cli("get", &["-s", "local", "-o", "property,value", "all", dataset])?
+            .into_iter()
+            .map(|row| match <[String; 2]>::try_from(row) {
+                Ok([property, value]) => Ok((property, value)),
+                Err(row) => Err(eyre!("zfs get parse error"))
+                    .with_note(|| format!("line: {}", row.join("\t"))),
+            })
+            .collect()
+    }
+
+    fn diff(&self, from: &str, to: &str) -> Result<Vec<Change>> {
+        // zfs diff -H -F ...@... ...[@...]
+        restore::parse_diff(call_zfs_cli("diff", &["-F", from, to])?)
+    }
+
+    fn blockers(&self) -> Result<HashMap<String, Blockers>> {
+        // zfs list -H -t snapshot -o name,clones,userrefs,defer_destroy
+        let (mut blockers, held) = parse_blockers(call_zfs_cli(
+            "list",
+            &["-t", "snapshot", "-o", "name,clones,userrefs,defer_destroy"],
+        )?)?;
+        if !held.is_empty() {
+            // zfs holds -H ...@... ...@...
+            let held = held.iter().map(String::as_str).collect_vec();
+            for row in call_zfs_cli("holds", &held)? {
+                if let [snapshot, tag, ..] = &row[..] {
+                    let blockers = blockers.entry(snapshot.clone()).or_default();
+                    blockers.holds.push(tag.clone());
+                }
+            }
+        }
+        Ok(blockers)
+    }
+
+    fn pool_status(&self, pool: &str) -> Result<PoolStatus> {
+        // zpool get -H -o value health,readonly $pool
+        let values = call_zpool("get", &["-H", "-o", "value", "health,readonly", pool])?;
+        let [health, readonly] = values.lines().collect_vec()[..] else {
+            return Err(eyre!("zpool get returned something else than two values"))
+                .with_note(|| format!("pool: {pool}"));
+        };
+        // zpool status $pool
+        let status = call_zpool("status", &[pool])?;
+        Ok(PoolStatus {
+            health: health.to_string(),
+            readonly: readonly == "on",
+            resilvering: status.contains("resilver in progress"),
+        })
+    }
+
+    #[cfg(feature = "ssh")]
+    fn hold(&self, tag: &str, snapshot: &str) -> Result<()> {
+        call_do("hold", &[tag, snapshot])
+    }
+
+    #[cfg(feature = "ssh")]
+    fn release(&self, tag: &str, snapshot: &str) -> Result<()> {
+        call_do("release", &[tag, snapshot])
+    }
+
+    #[cfg(feature = "ssh")]
+    fn send(&self, base: Option<&str>, snapshot: &str) -> Result<Box<dyn Read + Send>> {
+        // zfs send [-i base] ...@...
+        let mut command = Command::new("zfs");
+        command.arg("send");
+        if let Some(base) = base {
+            command.args(["-i", base]);
+        }
+        let child = command
+            .arg(snapshot)
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .wrap_err("Could not start zfs send")?;
+        Ok(Box::new(SendStream(child)))
+    }
+}
+
+/// Output of a running `zfs send`, reports an error at the end of the stream
+/// if `zfs send` failed.
+#[cfg(feature = "ssh")]
+struct SendStream(std::process::Child);
+
+#[cfg(feature = "ssh")]
+impl Read for SendStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self
+            .0
+            .stdout
+            .as_mut()
+            .expect("stdout is piped")
+            .read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let status = self.0.wait()?;
+            if !status.success() {
+                return Err(std::io::Error::other(format!("zfs send failed, {status}")));
+            }
+        }
+        Ok(n)
+    }
+}
+
+// Same as the rfc3339 timestamps zcrab used before naming was configurable
+const DEFAULT_NAMING: &str = "%Y-%m-%dT%H:%M:%SZ-autosnap";
+
+/// Snapshots `dataset` together with the rest of its group, if it leads
+/// one, in a single zfs call so they all share the same point in time.
+/// Returns the new snapshots of the configured datasets, the leader first.
+pub fn snapshot(
+    zfs: &dyn ZfsBackend,
+    datasets: &[ConfiguredDataSet],
+    dataset: &ConfiguredDataSet,
+) -> Result<Vec<SnapshotMetadata>> {
+    let now = zfs.now();
+    let naming = dataset.settings.naming.as_deref().unwrap_or(DEFAULT_NAMING);
+    let short_name = now.format(naming).to_string();
+    let members = dataset.members(datasets);
+    let names = members
+        .iter()
+        .map(|member| format!("{}@{short_name}", member.path))
+        .collect_vec();
+    let recursive = dataset.group.as_ref().is_some_and(|group| group.recursive);
+
+    // the pre-snapshot hooks run one after the other, if one vetoes the
+    // snapshot the ones that already ran still get their post-snapshot hook
+    let mut made = Ok(());
+    let mut frozen = Vec::new();
+    for (member, name) in members.iter().zip(&names) {
+        frozen.push((member, name));
+        made = member.settings.hooks.before_snapshot(name);
+        if made.is_err() {
+            break;
+        }
+    }
+    let made = made.and_then(|()| match names.as_slice() {
+        [name] if !recursive => zfs.snapshot(name),
+        _ if recursive => zfs.snapshot_atomic(&names[..1], true),
+        _ => zfs.snapshot_atomic(&names, false),
+    });
+    let mut thawed = Ok(());
+    for (member, name) in frozen {
+        let res = member.settings.hooks.after_snapshot(name).wrap_err_with(|| {
+            format!("Made snapshot {name} but the post-snapshot hook failed")
+        });
+        thawed = thawed.and(res);
+    }
+    made?;
+    thawed?;
+
+    names
+        .into_iter()
+        .map(|name| {
+            Ok(SnapshotMetadata {
+                used: parse_used(&zfs.get_property(&name, "used")?)?,
+                name,
+                created: now,
+            })
+        })
+        .collect()
+}
+
+/// Snapshots opted out of management by setting `ZFS_PROPERTY` to `-` on
+/// the snapshot itself. Unset user properties also read as `-` so only a
+/// local value counts.
+fn opted_out(zfs: &dyn ZfsBackend) -> Result<HashSet<String>> {
+    Ok(zfs
+        .list_local(ZFS_PROPERTY)?
+        .into_iter()
+        .filter(|(name, value)| name.contains('@') && value == "-")
+        .map(|(name, _)| name)
+        .collect())
+}
 
-    let snapshots = parse_snapshots(lines)?;
+pub fn add_snapshots(zfs: &dyn ZfsBackend) -> Result<HashMap<DataSet, Box<[SnapshotMetadata]>>> {
+    // List all snapshots that are not opted out.
+    let snapshots = zfs.list_snapshots()?;
+    let opted_out = opted_out(zfs)?;
     let mut snapshots: HashMap<_, _> = snapshots
         .into_iter()
+        .filter(|meta| !opted_out.contains(&meta.name))
         .map(|meta| (meta.dataset().to_string(), meta))
         .into_group_map();
 
@@ -96,31 +496,23 @@ pub fn add_snapshots() -> Result<HashMap<DataSet, Box<[SnapshotMetadata]>>> {
 fn parse_snapshots(lines: Vec<Vec<String>>) -> Result<Vec<SnapshotMetadata>> {
     let mut snapshots = Vec::with_capacity(lines.len());
     for line in lines {
-        // Skip snapshots that don't have the 'at.rollc.at:snapkeep' property.
-        //
-        // FIXME dvdsk: it does not. That filtering is done by zfs list in `list_snapshots`.
-        //
-        // This works both for datasets where a snapshot did not inherit the property
-        // (which means the dataset should not be managed), and for explicitly marking a
-        // snapshot to be retained / opted out.
-        //
         match line.as_slice() {
-            [_, _, _, snapkeep] if snapkeep == "-" => (),
-            [name, created, used, _] => {
-                let metadata = SnapshotMetadata {
-                    name: name.to_string(),
-                    created: parse_datetime(created)?,
-                    used: parse_used(used)?,
-                };
-                snapshots.push(metadata);
-            }
+            [name, created, used] => snapshots.push(parse_snapshot(name, created, used)?),
             _ => return Err(eyre!("list snapshots parse error")),
         }
     }
     Ok(snapshots)
 }
 
-fn parse_datetime(s: &String) -> Result<chrono::DateTime<chrono::Utc>> {
+pub(crate) fn parse_snapshot(name: &str, created: &str, used: &str) -> Result<SnapshotMetadata> {
+    Ok(SnapshotMetadata {
+        name: name.to_string(),
+        created: parse_datetime(created)?,
+        used: parse_used(used)?,
+    })
+}
+
+fn parse_datetime(s: &str) -> Result<chrono::DateTime<chrono::Utc>> {
     let r = {
         if let Ok(dt) = chrono::NaiveDateTime::parse_from_str(s, "%a %b %e %H:%M %Y") {
             dt
@@ -133,80 +525,508 @@ fn parse_datetime(s: &String) -> Result<chrono::DateTime<chrono::Utc>> {
     Ok(chrono::Utc.from_utc_datetime(&r))
 }
 
-pub fn get_property(dataset: &str, property: &str) -> Result<String> {
-    // Get a single named property on given dataset.
-    // zfs get -H -o value $property $dataset
-    Ok(call_zfs_cli("get", &["-o", "value", property, dataset])?
-        .first()
-        .unwrap()[0]
-        .clone())
+pub fn set_policy(zfs: &dyn ZfsBackend, dataset: &str, policy: &RetentionPolicy) -> Result<()> {
+    zfs.set_property(dataset, ZFS_PROPERTY, &format!("{policy:?}"))
 }
 
-pub fn set_policy(dataset: &str, policy: &RetentionPolicy) -> Result<()> {
-    let output = Command::new("zfs").args(["set", &format!("{ZFS_PROPERTY}={policy:?}"), dataset]).output()?;
-    if output.stderr.is_empty() {
-        Ok(())
-    } else {
-        Err(eyre!("zfs set failed"))
-            .with_note(|| format!("stderr is: {}", String::from_utf8_lossy(&output.stderr)))
+
+/// Hold tag placed on the newest snapshot in common with a replication
+/// target. It is needed as base for the next incremental send.
+pub const REPLICATION_ANCHOR: &str = concat!(env!("CARGO_PKG_NAME"), ":replication-anchor");
+
+/// Postpones snapshots while less than this was written to the dataset
+pub const MIN_WRITTEN_PROPERTY: &str = concat!(env!("CARGO_PKG_NAME"), ":min-written");
+
+/// Snapshots are destroyed early while the pool has less free space
+pub const MIN_POOL_FREE_PROPERTY: &str = concat!(env!("CARGO_PKG_NAME"), ":min-pool-free-percent");
+/// Snapshots are destroyed early while their `usedbysnapshots` is higher
+pub const MAX_SNAPSHOT_SPACE_PROPERTY: &str =
+    concat!(env!("CARGO_PKG_NAME"), ":max-snapshot-space");
+
+/// Set to `on` to snapshot a dataset and its descendants at once
+pub const RECURSIVE_PROPERTY: &str = concat!(env!("CARGO_PKG_NAME"), ":recursive");
+
+/// Set to `on` to destroy held and cloned snapshots with `zfs destroy -d`
+pub const DEFER_DESTROY_PROPERTY: &str = concat!(env!("CARGO_PKG_NAME"), ":defer-destroy");
+
+/// Blockers from rows of name, clones, userrefs and defer_destroy, without
+/// the tags of the holds. Those are to be asked for the held snapshots,
+/// returned as well.
+fn parse_blockers(rows: Vec<Vec<String>>) -> Result<(HashMap<String, Blockers>, Vec<String>)> {
+    let mut blockers = HashMap::new();
+    let mut held = Vec::new();
+    for row in rows {
+        let [name, clones, userrefs, deferred]: [String; 4] = row
+            .try_into()
+            .map_err(|_| eyre!("zfs list returned a row without four columns"))?;
+        if userrefs != "0" {
+            held.push(name.clone());
+        }
+        let clones = match clones.as_str() {
+            "" | "-" => Vec::new(),
+            clones => clones.split(',').map(str::to_string).collect(),
+        };
+        let deferred = deferred == "on";
+        if !clones.is_empty() || deferred {
+            let holds = Vec::new();
+            blockers.insert(name, Blockers { clones, holds, deferred });
+        }
+    }
+    Ok((blockers, held))
+}
+
+/// Clones and user holds make `zfs destroy` fail on a snapshot
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Blockers {
+    /// Datasets cloned from the snapshot
+    pub clones: Vec<DataSet>,
+    /// Tags of the user holds
+    pub holds: Vec<String>,
+    /// Marked by `zfs destroy -d`, zfs destroys it once nothing blocks it
+    pub deferred: bool,
+}
+
+impl Blockers {
+    pub fn blocking(&self) -> bool {
+        !self.clones.is_empty() || !self.holds.is_empty()
+    }
+}
+
+impl core::fmt::Display for Blockers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let blockers = self
+            .clones
+            .iter()
+            .map(|clone| format!("clone {clone}"))
+            .chain(self.holds.iter().map(|tag| format!("hold {tag}")))
+            .join(" / ");
+        write!(f, "blocked by {blockers}")
+    }
+}
+
+/// Why a snapshot rejected by its retention policy is kept anyway
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Protection {
+    ReplicationAnchor,
+    /// Another dataset in the group retains its copy of the snapshot
+    Group { leader: DataSet },
+    /// Would expire but can not be destroyed
+    Blocked(Blockers),
+}
+
+impl core::fmt::Display for Protection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protection::ReplicationAnchor => f.write_str("replication anchor"),
+            Protection::Group { leader } => write!(f, "kept with the group of {leader}"),
+            Protection::Blocked(blockers) if blockers.deferred => {
+                write!(f, "destroy deferred, {blockers}")
+            }
+            Protection::Blocked(blockers) => write!(f, "would expire but {blockers}"),
+        }
+    }
+}
+
+/// Finds the snapshots rejected by their retention policy that must be kept
+/// anyway, keyed by snapshot name. Held or cloned snapshots are left out on
+/// datasets with `defer_destroy` unless they were already marked, those
+/// are destroyed with `zfs destroy -d`.
+pub fn protected(
+    zfs: &dyn ZfsBackend,
+    datasets: &[ConfiguredDataSet],
+) -> Result<HashMap<String, Protection>> {
+    let all_blockers = zfs.blockers()?;
+    let rejected: HashMap<&str, HashSet<&SnapshotMetadata>> = datasets
+        .iter()
+        .map(|dataset| {
+            let judgement = dataset.retention_policy.judge(&dataset.sorted_snapshots);
+            (dataset.path.as_str(), judgement.rejected)
+        })
+        .collect();
+    let mut protected = HashMap::new();
+    for dataset in datasets {
+        let defer = dataset.settings.defer_destroy == Some(true);
+        for snapshot in &rejected[dataset.path.as_str()] {
+            let blockers = all_blockers.get(&snapshot.name).cloned().unwrap_or_default();
+            if blockers.holds.iter().any(|tag| tag == REPLICATION_ANCHOR) {
+                protected.insert(snapshot.name.clone(), Protection::ReplicationAnchor);
+            } else if blockers.deferred || (blockers.blocking() && !defer) {
+                protected.insert(snapshot.name.clone(), Protection::Blocked(blockers));
+            }
+        }
+    }
+
+    // a group keeps or removes the copies of a snapshot together so a
+    // restore never mixes points in time
+    let mut kept_with_group = HashMap::new();
+    for dataset in datasets {
+        let Some(group) = &dataset.group else {
+            continue;
+        };
+        let others = dataset
+            .members(datasets)
+            .into_iter()
+            .filter(|member| member.path != dataset.path)
+            .collect_vec();
+        for snapshot in &rejected[dataset.path.as_str()] {
+            if protected.contains_key(&snapshot.name) {
+                continue;
+            }
+            let kept_elsewhere = others.iter().any(|other| {
+                let rejected = &rejected[other.path.as_str()];
+                other
+                    .sorted_snapshots
+                    .iter()
+                    .filter(|copy| copy.short_name() == snapshot.short_name())
+                    .any(|copy| !rejected.contains(copy) || protected.contains_key(&copy.name))
+            });
+            if kept_elsewhere {
+                let leader = group.leader.clone();
+                kept_with_group.insert(snapshot.name.clone(), Protection::Group { leader });
+            }
+        }
     }
+    protected.extend(kept_with_group);
+    Ok(protected)
 }
 
+/// Datasets snapshotted in a single zfs call so they share a point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    /// Makes the zfs calls for the group, its naming is used
+    pub leader: DataSet,
+    /// Snapshotted with `zfs snapshot -r` of the leader, otherwise with one
+    /// call naming every member.
+    pub recursive: bool,
+}
 
 pub struct ConfiguredDataSet {
     pub path: String,
     pub retention_policy: RetentionPolicy,
     // newest to oldest
     pub sorted_snapshots: Box<[SnapshotMetadata]>,
+    pub settings: Settings,
+    pub conflicts: Vec<Conflict>,
+    pub group: Option<Group>,
+    /// Bytes written since the latest snapshot, only looked up if the
+    /// dataset has a `min_written` setting.
+    pub written: Option<Byte>,
 }
 
 impl ConfiguredDataSet {
-    pub fn until_next_snapshot(&self) -> Option<Duration> {
-        self
+    /// Name of the pool the dataset is on
+    pub fn pool(&self) -> &str {
+        self.path.split('/').next().unwrap_or(&self.path)
+    }
+
+    /// The dataset making the zfs calls for this one, itself if it is not
+    /// in a group.
+    pub fn leader(&self) -> &str {
+        self.group
+            .as_ref()
+            .map_or(self.path.as_str(), |group| group.leader.as_str())
+    }
+
+    /// All datasets in the same group, the leader first
+    pub fn members<'a>(&'a self, datasets: &'a [ConfiguredDataSet]) -> Vec<&'a ConfiguredDataSet> {
+        if self.group.is_none() {
+            return vec![self];
+        }
+        datasets
+            .iter()
+            .filter(|other| other.leader() == self.leader())
+            .sorted_by_key(|other| other.path != self.leader())
+            .collect()
+    }
+
+    pub fn until_next_snapshot(&self, now: DateTime<Utc>) -> Option<Duration> {
+        let until = self
             .retention_policy
-            .next_snapshot_in(&self.sorted_snapshots)
+            .next_snapshot_in(&self.sorted_snapshots, now)?;
+        if !until.is_zero() || !self.unchanged() {
+            return Some(until);
+        }
+
+        // Postpone as if a snapshot was made now. As this is redone on every
+        // check the snapshot follows as soon as enough has been written.
+        let mut snapshots = self.sorted_snapshots.to_vec();
+        snapshots.insert(
+            0,
+            SnapshotMetadata {
+                name: format!("{}@postponed", self.path),
+                created: now,
+                used: Byte::from_bytes(0),
+            },
+        );
+        self.retention_policy.next_snapshot_in(&snapshots, now)
+    }
+
+    /// Less than `min_written` was written since the latest snapshot
+    pub fn unchanged(&self) -> bool {
+        match (self.written, self.settings.min_written) {
+            (Some(written), Some(min)) => !self.sorted_snapshots.is_empty() && written < min,
+            _ => false,
+        }
+    }
+}
+
+pub fn configured_datasets(
+    zfs: &dyn ZfsBackend,
+    config: &Config,
+) -> Result<Vec<ConfiguredDataSet>> {
+    let mut snapshots = add_snapshots(zfs)?;
+    let mut hook_properties = hooks::from_properties(zfs)?;
+    let mut recursive_properties = parsed_properties(zfs, RECURSIVE_PROPERTY, parse_on_off)?;
+    let mut min_written_properties = parsed_properties(zfs, MIN_WRITTEN_PROPERTY, parse_used)?;
+    let mut min_free_properties = parsed_properties(zfs, MIN_POOL_FREE_PROPERTY, parse_percent)?;
+    let mut max_snapshot_space_properties =
+        parsed_properties(zfs, MAX_SNAPSHOT_SPACE_PROPERTY, parse_used)?;
+    let mut unhealthy_pool_properties = parsed_properties(
+        zfs,
+        health::ON_UNHEALTHY_POOL_PROPERTY,
+        OnUnhealthyPool::from_str,
+    )?;
+    let mut defer_destroy_properties =
+        parsed_properties(zfs, DEFER_DESTROY_PROPERTY, parse_on_off)?;
+    let mut hook_timeout_properties =
+        parsed_properties(zfs, hooks::TIMEOUT_PROPERTY, hooks::parse_timeout)?;
+    let mut on_failure_properties =
+        parsed_properties(zfs, hooks::ON_FAILURE_PROPERTY, OnFailure::from_str)?;
+    let mut datasets = Vec::new();
+    for (path, property) in zfs.list_datasets(ZFS_PROPERTY)? {
+        let entry = config.entry_for(&path);
+        let from_config = entry.and_then(|entry| entry.policy.clone());
+
+        let mut conflicts = Vec::new();
+        // a policy in an older syntax only keeps its own dataset out
+        let parsed = (property != "-")
+            .then(|| RetentionPolicy::from_str(&property))
+            .transpose();
+        let from_property = match (parsed, &from_config) {
+            (Ok(policy), _) => policy,
+            (Err(_), Some(config)) => {
+                conflicts.push(Conflict {
+                    setting: "policy",
+                    config: format!("{config:?}"),
+                    property: format!("{property} (invalid)"),
+                });
+                None
+            }
+            (Err(e), None) => {
+                eprintln!("skipped {path}, its policy is invalid: {e:?}");
+                continue;
+            }
+        };
+        let retention_policy = match (from_config, from_property) {
+            (Some(config), Some(property)) => {
+                if config != property {
+                    conflicts.push(Conflict {
+                        setting: "policy",
+                        config: format!("{config:?}"),
+                        property: format!("{property:?}"),
+                    });
+                }
+                config
+            }
+            (Some(policy), None) | (None, Some(policy)) => policy,
+            (None, None) => continue,
+        };
+
+        let mut settings: Settings = entry.map(DataSetConfig::settings).unwrap_or_default();
+        settings.hooks = Hooks::merge(
+            settings.hooks,
+            hook_properties.remove(&path).unwrap_or_default(),
+            &mut conflicts,
+        );
+        settings.hooks.timeout = pick_parsed(
+            &path,
+            "hook-timeout",
+            settings.hooks.timeout,
+            hook_timeout_properties.remove(&path),
+            &mut conflicts,
+        );
+        settings.hooks.on_pre_snapshot_failure = pick_parsed(
+            &path,
+            "on-pre-snapshot-failure",
+            settings.hooks.on_pre_snapshot_failure,
+            on_failure_properties.remove(&path),
+            &mut conflicts,
+        );
+        settings.recursive = pick_parsed(
+            &path,
+            "recursive",
+            settings.recursive,
+            recursive_properties.remove(&path),
+            &mut conflicts,
+        );
+        settings.min_written = pick_parsed(
+            &path,
+            "min-written",
+            settings.min_written,
+            min_written_properties.remove(&path),
+            &mut conflicts,
+        );
+        settings.min_pool_free_percent = pick_parsed(
+            &path,
+            "min-pool-free-percent",
+            settings.min_pool_free_percent,
+            min_free_properties.remove(&path),
+            &mut conflicts,
+        );
+        settings.max_snapshot_space = pick_parsed(
+            &path,
+            "max-snapshot-space",
+            settings.max_snapshot_space,
+            max_snapshot_space_properties.remove(&path),
+            &mut conflicts,
+        );
+        settings.on_unhealthy_pool = pick_parsed(
+            &path,
+            "on-unhealthy-pool",
+            settings.on_unhealthy_pool,
+            unhealthy_pool_properties.remove(&path),
+            &mut conflicts,
+        );
+        settings.defer_destroy = pick_parsed(
+            &path,
+            "defer-destroy",
+            settings.defer_destroy,
+            defer_destroy_properties.remove(&path),
+            &mut conflicts,
+        );
+        let written = settings
+            .min_written
+            .map(|_| parse_used(&zfs.get_property(&path, "written")?))
+            .transpose()?;
+
+        datasets.push(ConfiguredDataSet {
+            sorted_snapshots: snapshots.remove(&path).unwrap_or_default(),
+            settings,
+            path,
+            retention_policy,
+            conflicts,
+            group: None,
+            written,
+        });
     }
+    assign_groups(&mut datasets);
+    Ok(datasets)
 }
 
-pub fn configured_datasets() -> Result<Vec<ConfiguredDataSet>> {
-    let mut snapshots = add_snapshots()?;
-    let datasets = iter_configured_datasets()?;
-    datasets.map_ok(|(name, policy)| {
-        ConfiguredDataSet {
-            sorted_snapshots: snapshots.remove(&name).unwrap_or_default(),
-            path: name,retention_policy: policy
+/// A property value that did not parse, with the reason
+type Invalid = (String, color_eyre::Report);
+
+/// The values of `property` on the datasets where it is set. An invalid
+/// value is returned as such, it only affects the datasets that have it.
+fn parsed_properties<T>(
+    zfs: &dyn ZfsBackend,
+    property: &str,
+    parse: impl Fn(&str) -> Result<T>,
+) -> Result<HashMap<DataSet, Result<T, Invalid>>> {
+    Ok(zfs
+        .list_datasets(property)?
+        .into_iter()
+        .filter(|(_, value)| value != "-")
+        .map(|(dataset, value)| {
+            let parsed = parse(&value).map_err(|e| (value, e));
+            (dataset, parsed)
+        })
+        .collect())
+}
+
+/// [`pick`] for a parsed property. An invalid property is ignored, it is a
+/// conflict if the config file sets the value and printed otherwise.
+fn pick_parsed<T: PartialEq + fmt::Display>(
+    path: &str,
+    setting: &'static str,
+    config: Option<T>,
+    property: Option<Result<T, Invalid>>,
+    conflicts: &mut Vec<Conflict>,
+) -> Option<T> {
+    match (property.transpose(), config) {
+        (Ok(property), config) => pick(setting, config, property, conflicts),
+        (Err((value, _)), Some(config)) => {
+            conflicts.push(Conflict {
+                setting,
+                config: config.to_string(),
+                property: format!("{value} (invalid)"),
+            });
+            Some(config)
         }
-    }).collect()
+        (Err((value, e)), None) => {
+            eprintln!("ignored {setting} '{value}' of {path}, it is invalid: {e:?}");
+            None
+        }
+    }
+}
+
+pub(crate) fn parse_percent(value: &str) -> Result<u8> {
+    value
+        .trim_end_matches('%')
+        .parse()
+        .ok()
+        .filter(|percent| *percent <= 100)
+        .ok_or_else(|| eyre!("Not a percentage: '{value}'"))
+}
+
+fn parse_on_off(value: &str) -> Result<bool> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(eyre!("Invalid value: '{value}'")).with_note(|| "Valid values are: on|off"),
+    }
+}
+
+/// A recursive dataset leads a group with all configured datasets below it.
+/// Other datasets with the same `group` setting form a group led by the
+/// first of them.
+fn assign_groups(datasets: &mut [ConfiguredDataSet]) {
+    let recursive = datasets
+        .iter()
+        .filter(|d| d.settings.recursive == Some(true))
+        .map(|d| d.path.clone())
+        .collect_vec();
+    let mut leaders: HashMap<String, DataSet> = HashMap::new();
+
+    for dataset in datasets.iter_mut() {
+        let tree = recursive
+            .iter()
+            .filter(|root| {
+                dataset.path == **root || dataset.path.starts_with(&format!("{root}/"))
+            })
+            .min_by_key(|root| root.len());
+        dataset.group = if let Some(root) = tree {
+            Some(Group {
+                leader: root.clone(),
+                recursive: true,
+            })
+        } else if let Some(name) = &dataset.settings.group {
+            let leader = leaders
+                .entry(name.clone())
+                .or_insert_with(|| dataset.path.clone());
+            Some(Group {
+                leader: leader.clone(),
+                recursive: false,
+            })
+        } else {
+            None
+        };
+    }
 }
 
-pub fn iter_unconfigured_datasets() -> Result<impl Iterator<Item = String>> {
-    Ok(call_zfs_cli(
-        "list",
-        &[],
-    )?.into_iter().map(|mut row| row.remove(0) )) // TODO error handling
+pub fn iter_unconfigured_datasets(zfs: &dyn ZfsBackend) -> Result<impl Iterator<Item = String>> {
+    Ok(zfs
+        .list_datasets(ZFS_PROPERTY)?
+        .into_iter()
+        .map(|(name, _)| name))
 }
 
-pub fn iter_configured_datasets() -> Result<impl Iterator<Item = Result<(String, RetentionPolicy)>>> {
+pub fn iter_configured_datasets(zfs: &dyn ZfsBackend) -> Result<impl Iterator<Item = Result<(String, RetentionPolicy)>>> {
     // Which datasets should get a snapshot?
-    // zfs get -H -t filesystem,volume -o name,value at.rollc.at:snapkeep
-    Ok(call_zfs_cli(
-        "get",
-        &[
-            "-t",
-            "filesystem,volume",
-            "-o",
-            "name,value",
-            ZFS_PROPERTY,
-        ],
-    )?
+    Ok(zfs.list_datasets(ZFS_PROPERTY)?
     .into_iter()
-    .map(|pairs| {
-        pairs
-            .try_into()
-            .expect("get with two -o values returns pairs")
-    })
-    .filter(|[_, retention]: &[String; 2]| retention != "-")
-    .map(|[path, retention]| 
+    .filter(|(_, retention)| retention != "-")
+    .map(|(path, retention)| 
         // not proper err handling place
         {
         let res = (path, RetentionPolicy::from_str(&retention)?);
@@ -215,15 +1035,113 @@ pub fn iter_configured_datasets() -> Result<impl Iterator<Item = Result<(String,
     ))
 }
 
-pub fn destroy_snapshot(snapshot: &SnapshotMetadata) -> Result<()> {
-    // This will destroy the named snapshot. Since ZFS has a single verb for destroying
-    // anything, which could cause irreparable harm, we double check that the name we
-    // got passed looks like a snapshot name, and return an error otherwise.
-    if !snapshot.name.contains('@') {
-        return Err(eyre!("Tried to destroy something that is not a snapshot"));
+/// Asks the pre-destroy hook of `dataset`, and if it leads a recursive
+/// group those of the members whose copies go with the snapshot
+pub fn before_destroy(
+    datasets: &[ConfiguredDataSet],
+    dataset: &ConfiguredDataSet,
+    snapshot: &SnapshotMetadata,
+) -> Result<()> {
+    dataset.settings.hooks.before_destroy(&snapshot.name)?;
+    if leads_recursive_group(dataset) {
+        for member in dataset.members(datasets).into_iter().skip(1) {
+            let copy = format!("{}@{}", member.path, snapshot.short_name());
+            member.settings.hooks.before_destroy(&copy)?;
+        }
+    }
+    Ok(())
+}
+
+/// Destroys a snapshot of `dataset` after asking the pre-destroy hooks, if
+/// it leads a recursive group the copies in all its descendants go too.
+pub fn destroy_snapshot(
+    zfs: &dyn ZfsBackend,
+    datasets: &[ConfiguredDataSet],
+    dataset: &ConfiguredDataSet,
+    snapshot: &SnapshotMetadata,
+) -> Result<()> {
+    before_destroy(datasets, dataset, snapshot)?;
+    destroy_snapshots(zfs, dataset, &[snapshot])
+}
+
+/// Destroys snapshots of `dataset` in a single zfs call, without running
+/// the pre-destroy hook. With `defer_destroy` a held or cloned snapshot is
+/// only marked, zfs destroys it once it is released.
+pub fn destroy_snapshots(
+    zfs: &dyn ZfsBackend,
+    dataset: &ConfiguredDataSet,
+    snapshots: &[&SnapshotMetadata],
+) -> Result<()> {
+    // This will destroy the named snapshots. Since ZFS has a single verb for destroying
+    // anything, which could cause irreparable harm, we double check that the names we
+    // got passed look like snapshot names of the dataset, and return an error otherwise.
+    let names = snapshot_names(dataset, snapshots)?;
+    let deferred = dataset.settings.defer_destroy == Some(true);
+    if leads_recursive_group(dataset) {
+        zfs.destroy_recursive(&names, deferred)
+    } else {
+        zfs.destroy(&names, deferred)
+    }
+}
+
+/// Space destroying `snapshots` of `dataset` would free, from a dry run
+pub fn reclaimable(
+    zfs: &dyn ZfsBackend,
+    dataset: &ConfiguredDataSet,
+    snapshots: &[&SnapshotMetadata],
+) -> Result<Byte> {
+    let names = snapshot_names(dataset, snapshots)?;
+    zfs.reclaimable(&names, leads_recursive_group(dataset))
+}
+
+fn snapshot_names<'a>(
+    dataset: &ConfiguredDataSet,
+    snapshots: &[&'a SnapshotMetadata],
+) -> Result<Vec<&'a str>> {
+    snapshots
+        .iter()
+        .map(|snapshot| match snapshot.name.split_once('@') {
+            Some((of, _)) if of == dataset.path => Ok(snapshot.name.as_str()),
+            _ => Err(eyre!("Tried to destroy something that is not a snapshot"))
+                .with_note(|| format!("name: {}, dataset: {}", snapshot.name, dataset.path)),
+        })
+        .collect()
+}
+
+fn leads_recursive_group(dataset: &ConfiguredDataSet) -> bool {
+    dataset
+        .group
+        .as_ref()
+        .is_some_and(|group| group.recursive && group.leader == dataset.path)
+}
+
+/// `dataset@a,b,c`, the form in which `zfs destroy` takes several snapshots
+/// of one dataset
+fn batch(names: &[&str]) -> Result<String> {
+    let mut batch = String::new();
+    let mut dataset = None;
+    for name in names {
+        let (of, short_name) = name
+            .split_once('@')
+            .ok_or_else(|| eyre!("not a snapshot name: {name}"))?;
+        match dataset {
+            None => {
+                batch.push_str(name);
+                dataset = Some(of);
+            }
+            Some(dataset) if dataset == of => {
+                batch.push(',');
+                batch.push_str(short_name);
+            }
+            Some(dataset) => {
+                return Err(eyre!("snapshots of {dataset} and {of} can not be destroyed at once"));
+            }
+        }
+    }
+    if dataset.is_none() {
+        return Err(eyre!("no snapshots to destroy"));
     }
-    // zfs destroy -H ...@...
-    call_do("destroy", &[&snapshot.name])
+    Ok(batch)
 }
 
 fn call_zfs_cli(action: &str, args: &[&str]) -> Result<Vec<Vec<String>>> {
@@ -241,6 +1159,19 @@ fn call_zfs_cli(action: &str, args: &[&str]) -> Result<Vec<Vec<String>>> {
         .collect())
 }
 
+fn call_zpool(action: &str, args: &[&str]) -> Result<String> {
+    let capture = subprocess::Exec::cmd("zpool")
+        .arg(action)
+        .args(args)
+        .stdout(subprocess::Redirection::Pipe)
+        .capture()?;
+    if capture.success() {
+        Ok(capture.stdout_str())
+    } else {
+        Err(eyre!("zpool {action} failed, {:?}", capture.exit_status))
+    }
+}
+
 fn call_do(action: &str, args: &[&str]) -> Result<()> {
     // Perform a side effect, like snapshot or destroy.
     if subprocess::Exec::cmd("zfs")
@@ -255,7 +1186,7 @@ fn call_do(action: &str, args: &[&str]) -> Result<()> {
     }
 }
 
-fn parse_used(x: &str) -> Result<Byte> {
+pub(crate) fn parse_used(x: &str) -> Result<Byte> {
     // The zfs(1) commandline tool says e.g. 1.2M but means 1.2MiB,
     // so we mash it to make byte_unit parsing happy.
     match x.chars().last() {
@@ -271,34 +1202,97 @@ mod tests {
     #[test]
     fn test_parse_snapshots() {
         let lines = vec![
-            // name, created, used, snapkeep
+            // name, created, used
             vec![
                 String::from("first"),
                 String::from("Sat Oct 2 09:59 2021"),
                 String::from("13G"),
-                String::from("at.rollc.at:snapkeep=h24d30w8m6y1"),
             ],
             vec![
-                String::from("skip"),
-                String::from("Sat Oct 1 19:59 2021"),
-                String::from("2G"),
-                String::from("-"),
+                String::from("second"),
+                String::from("1633081140"),
+                String::from("2048"),
             ],
         ];
         let snapshots = parse_snapshots(lines).unwrap();
         assert_eq!(
             snapshots,
-            vec![SnapshotMetadata {
-                name: String::from("first"),
-                created: Utc.from_utc_datetime(
-                    &chrono::NaiveDateTime::parse_from_str(
-                        "Sat Oct 2 09:59 2021",
-                        "%a %b %e %H:%M %Y",
-                    )
-                    .unwrap(),
-                ),
-                used: Byte::from(13u64 * 1024 * 1024 * 1024),
-            }]
+            vec![
+                SnapshotMetadata {
+                    name: String::from("first"),
+                    created: Utc.from_utc_datetime(
+                        &chrono::NaiveDateTime::parse_from_str(
+                            "Sat Oct 2 09:59 2021",
+                            "%a %b %e %H:%M %Y",
+                        )
+                        .unwrap(),
+                    ),
+                    used: Byte::from(13u64 * 1024 * 1024 * 1024),
+                },
+                SnapshotMetadata {
+                    name: String::from("second"),
+                    created: DateTime::from_timestamp(1633081140, 0).unwrap(),
+                    used: Byte::from(2048u64),
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_opted_out_snapshots_are_skipped() {
+        let zfs = fake::FakeZfs::new(Utc::now());
+        zfs.add_configured_dataset("tank/home", "1h2");
+        zfs.snapshot("tank/home@keep").unwrap();
+        zfs.snapshot("tank/home@skip").unwrap();
+        zfs.set_property("tank/home@skip", ZFS_PROPERTY, "-").unwrap();
+
+        let snapshots = add_snapshots(&zfs).unwrap();
+        let names: Vec<_> = snapshots["tank/home"].iter().map(|s| &s.name).collect();
+        assert_eq!(names, vec!["tank/home@keep"]);
+    }
+
+    #[test]
+    fn invalid_policy_only_skips_its_dataset() {
+        let zfs = fake::FakeZfs::new(Utc::now());
+        zfs.add_configured_dataset("tank/home", "1h2");
+        zfs.add_configured_dataset("tank/old", "h24d30");
+        zfs.add_configured_dataset("tank/both", "m6");
+
+        let config: Config = toml::from_str("[[dataset]]\nname = \"tank/both\"\npolicy = \"1d7\"")
+            .unwrap();
+        let datasets = configured_datasets(&zfs, &config).unwrap();
+        let paths: Vec<_> = datasets.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, ["tank/both", "tank/home"]);
+        assert_eq!(datasets[0].conflicts[0].property, "m6 (invalid)");
+    }
+
+    #[test]
+    fn invalid_setting_is_ignored_for_its_dataset() {
+        let zfs = fake::FakeZfs::new(Utc::now());
+        zfs.add_configured_dataset("tank/home", "1h2");
+        zfs.add_configured_dataset("tank/media", "1h2");
+        zfs.add_dataset("tank/unmanaged");
+        zfs.set_property("tank/home", MIN_WRITTEN_PROPERTY, "lots")
+            .unwrap();
+        zfs.set_property("tank/media", DEFER_DESTROY_PROPERTY, "on")
+            .unwrap();
+        zfs.set_property("tank/unmanaged", DEFER_DESTROY_PROPERTY, "maybe")
+            .unwrap();
+        zfs.set_property("tank/home", hooks::TIMEOUT_PROPERTY, "soon")
+            .unwrap();
+        zfs.set_property("tank/media", hooks::ON_FAILURE_PROPERTY, "skip")
+            .unwrap();
+        zfs.set_property("tank/unmanaged", hooks::ON_FAILURE_PROPERTY, "retry")
+            .unwrap();
+
+        let datasets = configured_datasets(&zfs, &Config::default()).unwrap();
+        assert_eq!(datasets.len(), 2);
+        assert_eq!(datasets[0].settings.min_written, None);
+        assert_eq!(datasets[0].settings.hooks.timeout, None);
+        assert_eq!(datasets[1].settings.defer_destroy, Some(true));
+        assert_eq!(
+            datasets[1].settings.hooks.on_pre_snapshot_failure,
+            Some(OnFailure::Skip)
         );
     }
 
@@ -322,9 +1316,33 @@ mod tests {
             String::from("first"),
             String::from("2 Oct 2021 9:52AM"),
             String::from("3G"),
-            String::from("at.rollc.at:snapkeep=h24d30w8m6y1"),
         ]];
         let err = parse_snapshots(lines).unwrap_err();
         assert!(err.to_string().starts_with("can't parse datetime:"));
     }
+
+    #[test]
+    fn test_parse_blockers() {
+        let row = |columns: [&str; 4]| columns.map(str::to_string).to_vec();
+        let (blockers, held) = parse_blockers(vec![
+            row(["tank/home@a", "-", "0", "off"]),
+            row(["tank/home@b", "tank/restore,tank/other", "0", "off"]),
+            row(["tank/home@c", "-", "2", "on"]),
+        ])
+        .unwrap();
+        assert_eq!(blockers.len(), 2);
+        assert_eq!(blockers["tank/home@b"].clones, ["tank/restore", "tank/other"]);
+        assert!(blockers["tank/home@c"].deferred);
+        assert_eq!(held, ["tank/home@c"]);
+        assert!(parse_blockers(vec![vec![String::from("tank/home@a")]]).is_err());
+    }
+
+    #[test]
+    fn test_batch() {
+        let names = ["tank/home@a", "tank/home@b", "tank/home@c"];
+        assert_eq!(batch(&names).unwrap(), "tank/home@a,b,c");
+        assert!(batch(&["tank/home@a", "tank/media@b"]).is_err());
+        assert!(batch(&["tank/home"]).is_err());
+        assert!(batch(&[]).is_err());
+    }
 }
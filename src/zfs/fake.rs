@@ -0,0 +1,624 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use byte_unit::Byte;
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+
+use super::{Blockers, SnapshotMetadata, ZfsBackend};
+use crate::health::PoolStatus;
+use crate::restore::Change;
+use crate::{DataSet, ZFS_PROPERTY};
+
+type Properties = HashMap<String, String>;
+
+/// In memory stand in for zfs, its clock only moves when told to (or when
+/// the program under test sleeps).
+pub struct FakeZfs {
+    state: Mutex<State>,
+}
+
+struct State {
+    now: DateTime<Utc>,
+    datasets: BTreeMap<DataSet, Properties>,
+    // key is the full name: dataset@snapshot
+    snapshots: BTreeMap<String, FakeSnapshot>,
+    /// Bytes written since the latest snapshot
+    written: BTreeMap<DataSet, u64>,
+    /// Pools only have a size when set, their space is used by snapshots
+    pool_sizes: BTreeMap<String, u64>,
+    /// Pools are online unless set otherwise
+    pool_statuses: BTreeMap<String, PoolStatus>,
+    /// zfs destroy calls made, successful or not
+    destroy_calls: usize,
+    /// What `diff` reports since a snapshot, nothing unless set
+    changes: BTreeMap<String, Vec<Change>>,
+}
+
+struct FakeSnapshot {
+    created: DateTime<Utc>,
+    properties: Properties,
+    holds: Vec<String>,
+    clones: Vec<DataSet>,
+    /// Marked by a deferred destroy while held or cloned
+    deferred: bool,
+    used: u64,
+}
+
+impl FakeSnapshot {
+    fn new(created: DateTime<Utc>) -> Self {
+        FakeSnapshot {
+            created,
+            properties: Properties::new(),
+            holds: Vec::new(),
+            clones: Vec::new(),
+            deferred: false,
+            used: 0,
+        }
+    }
+
+    fn blocked(&self) -> bool {
+        !self.holds.is_empty() || !self.clones.is_empty()
+    }
+}
+
+impl FakeZfs {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            state: Mutex::new(State {
+                now,
+                datasets: BTreeMap::new(),
+                snapshots: BTreeMap::new(),
+                written: BTreeMap::new(),
+                pool_sizes: BTreeMap::new(),
+                pool_statuses: BTreeMap::new(),
+                destroy_calls: 0,
+                changes: BTreeMap::new(),
+            }),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .expect("tests do not panic while holding the lock")
+    }
+
+    pub fn add_dataset(&self, path: &str) {
+        self.state()
+            .datasets
+            .insert(path.to_string(), Properties::new());
+    }
+
+    pub fn add_configured_dataset(&self, path: &str, policy: &str) {
+        self.add_dataset(path);
+        self.set_property(path, ZFS_PROPERTY, policy)
+            .expect("dataset was just added");
+    }
+
+    pub fn write(&self, dataset: &str, bytes: u64) {
+        *self.state().written.entry(dataset.to_string()).or_default() += bytes;
+    }
+
+    pub fn set_pool_size(&self, pool: &str, bytes: u64) {
+        self.state().pool_sizes.insert(pool.to_string(), bytes);
+    }
+
+    pub fn set_pool_status(&self, pool: &str, status: PoolStatus) {
+        self.state().pool_statuses.insert(pool.to_string(), status);
+    }
+
+    /// Space only this snapshot uses
+    pub fn set_used(&self, snapshot: &str, bytes: u64) {
+        self.state()
+            .snapshots
+            .get_mut(snapshot)
+            .expect("snapshot exists")
+            .used = bytes;
+    }
+
+    /// Sets the changes `diff` reports since `snapshot`, whatever it is
+    /// compared to
+    pub fn set_changes(&self, snapshot: &str, changes: Vec<Change>) {
+        self.state().changes.insert(snapshot.to_string(), changes);
+    }
+
+    pub fn destroy_calls(&self) -> usize {
+        self.state().destroy_calls
+    }
+
+    /// Destroys all or none of the snapshots, like a single `zfs destroy`
+    fn destroy_all(&self, names: &[String], deferred: bool) -> Result<()> {
+        let mut state = self.state();
+        state.destroy_calls += 1;
+        if names.is_empty() {
+            return Err(eyre!("no snapshots to destroy"));
+        }
+        for name in names {
+            let snapshot = state
+                .snapshots
+                .get(name)
+                .ok_or_else(|| eyre!("could not find snapshot: {name}"))?;
+            if snapshot.blocked() && !deferred {
+                return Err(eyre!("cannot destroy snapshot {name}: dataset is busy"));
+            }
+        }
+        for name in names {
+            if let Some(snapshot) = state.snapshots.get_mut(name)
+                && snapshot.blocked()
+            {
+                snapshot.deferred = true;
+            } else {
+                state.snapshots.remove(name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.state().now += duration;
+    }
+
+    /// Full names of the snapshots of `dataset`, oldest first
+    pub fn snapshots_of(&self, dataset: &str) -> Vec<String> {
+        let state = self.state();
+        let mut snapshots: Vec<_> = state
+            .snapshots
+            .iter()
+            .filter(|(name, _)| name.split_once('@').is_some_and(|(ds, _)| ds == dataset))
+            .collect();
+        snapshots.sort_by_key(|(_, snapshot)| snapshot.created);
+        snapshots
+            .into_iter()
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+impl State {
+    /// `name` and the snapshots with the same name of the descendants of
+    /// its dataset
+    fn with_descendants(&self, name: &str) -> Result<Vec<String>> {
+        let (dataset, short_name) = name
+            .split_once('@')
+            .ok_or_else(|| eyre!("not a snapshot name: {name}"))?;
+        if !self.snapshots.contains_key(name) {
+            return Err(eyre!("could not find snapshot: {name}"));
+        }
+        let below = format!("{dataset}/");
+        Ok(self
+            .snapshots
+            .keys()
+            .filter(|other| {
+                other.split_once('@').is_some_and(|(ds, short)| {
+                    short == short_name && (ds == dataset || ds.starts_with(&below))
+                })
+            })
+            .cloned()
+            .collect())
+    }
+
+    // Mimics zfs property inheritance: snapshots inherit from their
+    // dataset and datasets from their parents.
+    fn lookup(&self, name: &str, property: &str) -> Option<String> {
+        let mut dataset = name;
+        if let Some((parent, _)) = name.split_once('@') {
+            let local = self
+                .snapshots
+                .get(name)
+                .and_then(|s| s.properties.get(property));
+            if let Some(value) = local {
+                return Some(value.clone());
+            }
+            dataset = parent;
+        }
+
+        loop {
+            let local = self
+                .datasets
+                .get(dataset)
+                .and_then(|props| props.get(property));
+            if let Some(value) = local {
+                return Some(value.clone());
+            }
+            dataset = dataset.rsplit_once('/')?.0;
+        }
+    }
+
+    /// Space used by the snapshots of `dataset`, with `descendants` also
+    /// by those of the datasets below it
+    fn used_by_snapshots(&self, dataset: &str, descendants: bool) -> u64 {
+        let below = format!("{dataset}/");
+        self.snapshots
+            .iter()
+            .filter(|(name, _)| {
+                name.split_once('@').is_some_and(|(ds, _)| {
+                    ds == dataset || (descendants && ds.starts_with(&below))
+                })
+            })
+            .map(|(_, snapshot)| snapshot.used)
+            .sum()
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.datasets.contains_key(name) || self.snapshots.contains_key(name)
+    }
+}
+
+impl ZfsBackend for FakeZfs {
+    fn now(&self) -> DateTime<Utc> {
+        self.state().now
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+
+    fn list_datasets(&self, property: &str) -> Result<Vec<(DataSet, String)>> {
+        let state = self.state();
+        Ok(state
+            .datasets
+            .keys()
+            .map(|name| {
+                let value = state.lookup(name, property);
+                (name.clone(), value.unwrap_or_else(|| "-".to_string()))
+            })
+            .collect())
+    }
+
+    fn list_snapshots(&self) -> Result<Vec<SnapshotMetadata>> {
+        let state = self.state();
+        Ok(state
+            .snapshots
+            .iter()
+            .map(|(name, snapshot)| SnapshotMetadata {
+                name: name.clone(),
+                created: snapshot.created,
+                used: Byte::from_bytes(u128::from(snapshot.used)),
+            })
+            .collect())
+    }
+
+    fn list_local(&self, property: &str) -> Result<Vec<(String, String)>> {
+        let state = self.state();
+        let datasets = state.datasets.iter();
+        let snapshots = state
+            .snapshots
+            .iter()
+            .map(|(name, snapshot)| (name, &snapshot.properties));
+        Ok(datasets
+            .chain(snapshots)
+            .filter_map(|(name, properties)| {
+                let value = properties.get(property)?;
+                Some((name.clone(), value.clone()))
+            })
+            .collect())
+    }
+
+    fn get_property(&self, name: &str, property: &str) -> Result<String> {
+        let state = self.state();
+        if !state.exists(name) {
+            return Err(eyre!("dataset does not exist: {name}"));
+        }
+        if let Some(snapshot) = state.snapshots.get(name)
+            && property == "used"
+        {
+            return Ok(snapshot.used.to_string());
+        }
+        match property {
+            "used" => return Ok(state.used_by_snapshots(name, true).to_string()),
+            "usedbysnapshots" => return Ok(state.used_by_snapshots(name, false).to_string()),
+            "available" => {
+                let pool = name.split('/').next().unwrap_or(name);
+                if let Some(size) = state.pool_sizes.get(pool) {
+                    let used = state.used_by_snapshots(pool, true);
+                    return Ok(size.saturating_sub(used).to_string());
+                }
+            }
+            _ => (),
+        }
+        if property == "written" {
+            return Ok(state.written.get(name).copied().unwrap_or(0).to_string());
+        }
+        Ok(state
+            .lookup(name, property)
+            .unwrap_or_else(|| "-".to_string()))
+    }
+
+    fn set_property(&self, name: &str, property: &str, value: &str) -> Result<()> {
+        let mut state = self.state();
+        let properties = if let Some(dataset) = state.datasets.get_mut(name) {
+            dataset
+        } else if let Some(snapshot) = state.snapshots.get_mut(name) {
+            &mut snapshot.properties
+        } else {
+            return Err(eyre!("dataset does not exist: {name}"));
+        };
+        properties.insert(property.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn inherit_property(&self, name: &str, property: &str) -> Result<()> {
+        let mut state = self.state();
+        let properties = if let Some(dataset) = state.datasets.get_mut(name) {
+            dataset
+        } else if let Some(snapshot) = state.snapshots.get_mut(name) {
+            &mut snapshot.properties
+        } else {
+            return Err(eyre!("dataset does not exist: {name}"));
+        };
+        properties.remove(property);
+        Ok(())
+    }
+
+    fn snapshot(&self, name: &str) -> Result<()> {
+        let mut state = self.state();
+        let (dataset, _) = name
+            .split_once('@')
+            .ok_or_else(|| eyre!("not a snapshot name: {name}"))?;
+        if !state.datasets.contains_key(dataset) {
+            return Err(eyre!("dataset does not exist: {dataset}"));
+        }
+        if state.snapshots.contains_key(name) {
+            return Err(eyre!("snapshot already exists: {name}"));
+        }
+        let created = state.now;
+        state.written.remove(dataset);
+        state
+            .snapshots
+            .insert(name.to_string(), FakeSnapshot::new(created));
+        Ok(())
+    }
+
+    fn snapshot_atomic(&self, names: &[String], recursive: bool) -> Result<()> {
+        let mut state = self.state();
+        let mut all = Vec::new();
+        for name in names {
+            let (dataset, short_name) = name
+                .split_once('@')
+                .ok_or_else(|| eyre!("not a snapshot name: {name}"))?;
+            if !state.datasets.contains_key(dataset) {
+                return Err(eyre!("dataset does not exist: {dataset}"));
+            }
+            all.push(name.clone());
+            if recursive {
+                let below = format!("{dataset}/");
+                all.extend(
+                    state
+                        .datasets
+                        .keys()
+                        .filter(|d| d.starts_with(&below))
+                        .map(|d| format!("{d}@{short_name}")),
+                );
+            }
+        }
+        // all or nothing, like a single transaction group
+        if let Some(existing) = all.iter().find(|name| state.snapshots.contains_key(*name)) {
+            return Err(eyre!("snapshot already exists: {existing}"));
+        }
+        let created = state.now;
+        for name in all {
+            if let Some((dataset, _)) = name.split_once('@') {
+                state.written.remove(dataset);
+            }
+            state.snapshots.insert(name, FakeSnapshot::new(created));
+        }
+        Ok(())
+    }
+
+    fn destroy_recursive(&self, names: &[&str], deferred: bool) -> Result<()> {
+        let mut all = Vec::new();
+        for name in names {
+            all.extend(self.state().with_descendants(name)?);
+        }
+        self.destroy_all(&all, deferred)
+    }
+
+    fn destroy(&self, names: &[&str], deferred: bool) -> Result<()> {
+        let names: Vec<_> = names.iter().map(|name| name.to_string()).collect();
+        self.destroy_all(&names, deferred)
+    }
+
+    fn reclaimable(&self, names: &[&str], recursive: bool) -> Result<Byte> {
+        let state = self.state();
+        let mut bytes = 0;
+        for name in names {
+            let all = if recursive {
+                state.with_descendants(name)?
+            } else {
+                vec![name.to_string()]
+            };
+            for name in all {
+                let snapshot = state
+                    .snapshots
+                    .get(&name)
+                    .ok_or_else(|| eyre!("could not find snapshot: {name}"))?;
+                bytes += snapshot.used;
+            }
+        }
+        Ok(Byte::from_bytes(u128::from(bytes)))
+    }
+
+    fn clone_snapshot(
+        &self,
+        snapshot: &str,
+        clone: &str,
+        properties: &[(&str, &str)],
+    ) -> Result<()> {
+        let mut state = self.state();
+        if state.exists(clone) {
+            return Err(eyre!("dataset already exists: {clone}"));
+        }
+        state
+            .snapshots
+            .get_mut(snapshot)
+            .ok_or_else(|| eyre!("could not find snapshot: {snapshot}"))?
+            .clones
+            .push(clone.to_string());
+        let properties = properties
+            .iter()
+            .map(|(property, value)| (property.to_string(), value.to_string()))
+            .collect();
+        state.datasets.insert(clone.to_string(), properties);
+        Ok(())
+    }
+
+    fn promote(&self, clone: &str) -> Result<()> {
+        let mut state = self.state();
+        let (origin, created) = state
+            .snapshots
+            .iter()
+            .find(|(_, s)| s.clones.iter().any(|c| c == clone))
+            .map(|(name, s)| (name.clone(), s.created))
+            .ok_or_else(|| eyre!("not a cloned filesystem: {clone}"))?;
+        let (dataset, _) = origin.split_once('@').expect("a snapshot name");
+        let moved: Vec<_> = state
+            .snapshots
+            .iter()
+            .filter(|(name, s)| {
+                name.split_once('@').is_some_and(|(ds, _)| ds == dataset) && s.created <= created
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in moved {
+            let mut snapshot = state.snapshots.remove(&name).expect("just listed");
+            if name == origin {
+                snapshot.clones.retain(|c| c != clone);
+                snapshot.clones.push(dataset.to_string());
+            }
+            let (_, short_name) = name.split_once('@').expect("a snapshot name");
+            state.snapshots.insert(format!("{clone}@{short_name}"), snapshot);
+        }
+        // nothing changed in the clone since it was made
+        state.changes.remove(&origin);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut state = self.state();
+        if !state.datasets.contains_key(from) {
+            return Err(eyre!("dataset does not exist: {from}"));
+        }
+        if state.exists(to) {
+            return Err(eyre!("dataset already exists: {to}"));
+        }
+        let renamed = |name: &str| -> Option<String> {
+            let rest = name.strip_prefix(from)?;
+            (rest.is_empty() || rest.starts_with(['/', '@'])).then(|| format!("{to}{rest}"))
+        };
+        state.datasets = std::mem::take(&mut state.datasets)
+            .into_iter()
+            .map(|(name, properties)| (renamed(&name).unwrap_or(name), properties))
+            .collect();
+        state.snapshots = std::mem::take(&mut state.snapshots)
+            .into_iter()
+            .map(|(name, mut snapshot)| {
+                for clone in &mut snapshot.clones {
+                    if let Some(to) = renamed(clone) {
+                        *clone = to;
+                    }
+                }
+                (renamed(&name).unwrap_or(name), snapshot)
+            })
+            .collect();
+        state.changes = std::mem::take(&mut state.changes)
+            .into_iter()
+            .map(|(name, changes)| (renamed(&name).unwrap_or(name), changes))
+            .collect();
+        Ok(())
+    }
+
+    fn local_properties(&self, dataset: &str) -> Result<Vec<(String, String)>> {
+        let state = self.state();
+        let properties = state
+            .datasets
+            .get(dataset)
+            .ok_or_else(|| eyre!("dataset does not exist: {dataset}"))?;
+        let mut properties: Vec<_> = properties
+            .iter()
+            .map(|(property, value)| (property.clone(), value.clone()))
+            .collect();
+        properties.sort();
+        Ok(properties)
+    }
+
+    fn diff(&self, from: &str, to: &str) -> Result<Vec<Change>> {
+        let state = self.state();
+        if !state.snapshots.contains_key(from) {
+            return Err(eyre!("could not find snapshot: {from}"));
+        }
+        if !state.exists(to) {
+            return Err(eyre!("dataset does not exist: {to}"));
+        }
+        Ok(state.changes.get(from).cloned().unwrap_or_default())
+    }
+
+    fn blockers(&self) -> Result<HashMap<String, Blockers>> {
+        Ok(self
+            .state()
+            .snapshots
+            .iter()
+            .filter(|(_, s)| s.blocked() || s.deferred)
+            .map(|(name, s)| {
+                let blockers = Blockers {
+                    clones: s.clones.clone(),
+                    holds: s.holds.clone(),
+                    deferred: s.deferred,
+                };
+                (name.clone(), blockers)
+            })
+            .collect())
+    }
+
+    fn pool_status(&self, pool: &str) -> Result<PoolStatus> {
+        Ok(self
+            .state()
+            .pool_statuses
+            .get(pool)
+            .cloned()
+            .unwrap_or_else(PoolStatus::online))
+    }
+
+    #[cfg(feature = "ssh")]
+    fn hold(&self, tag: &str, snapshot: &str) -> Result<()> {
+        let mut state = self.state();
+        let snapshot = state
+            .snapshots
+            .get_mut(snapshot)
+            .ok_or_else(|| eyre!("could not find snapshot: {snapshot}"))?;
+        if snapshot.holds.iter().any(|t| t == tag) {
+            return Err(eyre!("tag already exists on this dataset"));
+        }
+        snapshot.holds.push(tag.to_string());
+        Ok(())
+    }
+
+    #[cfg(feature = "ssh")]
+    fn release(&self, tag: &str, name: &str) -> Result<()> {
+        let mut state = self.state();
+        let snapshot = state
+            .snapshots
+            .get_mut(name)
+            .ok_or_else(|| eyre!("could not find snapshot: {name}"))?;
+        let before = snapshot.holds.len();
+        snapshot.holds.retain(|t| t != tag);
+        if snapshot.holds.len() == before {
+            return Err(eyre!("no such tag on this dataset"));
+        }
+        if snapshot.deferred && !snapshot.blocked() {
+            state.snapshots.remove(name);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "ssh")]
+    fn send(&self, base: Option<&str>, snapshot: &str) -> Result<Box<dyn std::io::Read + Send>> {
+        if !self.state().snapshots.contains_key(snapshot) {
+            return Err(eyre!("could not find snapshot: {snapshot}"));
+        }
+        let stream = format!("{}..{snapshot}", base.unwrap_or_default());
+        Ok(Box::new(std::io::Cursor::new(stream.into_bytes())))
+    }
+}